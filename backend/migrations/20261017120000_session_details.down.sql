@@ -0,0 +1,2 @@
+drop index if exists index_revocations_on_username;
+alter table public.revocations drop column client_ip;
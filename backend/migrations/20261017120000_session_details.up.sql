@@ -0,0 +1,2 @@
+alter table public.revocations add column client_ip text;
+create index if not exists index_revocations_on_username on public.revocations using btree (username);
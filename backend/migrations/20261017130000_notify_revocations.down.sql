@@ -0,0 +1,3 @@
+drop trigger if exists notify_revocation on revocations;
+
+drop function notify_revocation;
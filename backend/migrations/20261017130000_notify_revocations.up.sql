@@ -0,0 +1,11 @@
+create or replace function notify_revocation()
+returns trigger as $$
+begin
+    if new.revoked is not null then
+        perform pg_notify('revocations', json_build_object('data', new.data, 'expires', new.expires)::text);
+    end if;
+    return new;
+end;
+$$ language 'plpgsql';
+
+create trigger notify_revocation after insert or update of revoked on revocations for each row execute procedure notify_revocation();
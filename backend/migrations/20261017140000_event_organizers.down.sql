@@ -0,0 +1,3 @@
+drop table public.event_organizers;
+
+alter table public.events drop column creator_id;
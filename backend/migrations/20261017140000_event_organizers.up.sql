@@ -0,0 +1,22 @@
+alter table public.events add column creator_id bigint references public.users(id);
+
+create table public.event_organizers (
+    event_id bigint not null references public.events(id) on delete cascade,
+    user_id bigint not null references public.users(id) on delete cascade,
+    created_at timestamp without time zone not null default now(),
+    constraint event_organizers_pkey primary key (event_id, user_id)
+);
+alter table public.event_organizers owner to wagthepig;
+
+create index index_event_organizers_on_user_id on public.event_organizers using btree (user_id);
+
+-- Existing events are assigned to the site admin, named by the wagthepig.admin_email setting
+-- e.g. PGOPTIONS="-c wagthepig.admin_email=admin@example.com" sqlx migrate run
+-- If it isn't set, events are left without organizers.
+update public.events set creator_id = admins.id
+from (select id from public.users where email = current_setting('wagthepig.admin_email', true)) as admins
+where events.creator_id is null;
+
+insert into public.event_organizers (event_id, user_id)
+select id, creator_id from public.events where creator_id is not null
+on conflict do nothing;
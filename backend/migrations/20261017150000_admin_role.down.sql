@@ -0,0 +1,2 @@
+alter table public.users drop column locked_at;
+alter table public.users drop column is_admin;
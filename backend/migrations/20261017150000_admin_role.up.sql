@@ -0,0 +1,6 @@
+alter table public.users add column is_admin boolean not null default false;
+alter table public.users add column locked_at timestamp without time zone;
+
+-- As with event organizers, the site admin is named by the wagthepig.admin_email setting
+update public.users set is_admin = true
+where email = current_setting('wagthepig.admin_email', true);
@@ -0,0 +1,5 @@
+drop table public.recovery_codes;
+
+alter table public.users drop column totp_last_step;
+alter table public.users drop column totp_confirmed_at;
+alter table public.users drop column totp_secret;
@@ -0,0 +1,15 @@
+-- The secret is encrypted by the backend; see src/totp.rs
+alter table public.users add column totp_secret bytea;
+alter table public.users add column totp_confirmed_at timestamp without time zone;
+alter table public.users add column totp_last_step bigint;
+
+create table public.recovery_codes (
+    id bigserial primary key,
+    user_id bigint not null references public.users(id) on delete cascade,
+    code_hash text not null,
+    used_at timestamp without time zone,
+    created_at timestamp without time zone not null default now()
+);
+alter table public.recovery_codes owner to wagthepig;
+
+create index index_recovery_codes_on_user_id on public.recovery_codes using btree (user_id);
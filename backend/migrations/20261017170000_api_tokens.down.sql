@@ -0,0 +1,2 @@
+alter table public.revocations drop column created_at;
+alter table public.revocations drop column label;
@@ -0,0 +1,3 @@
+-- Personal API tokens are recorded alongside sessions, distinguished by their label
+alter table public.revocations add column label text;
+alter table public.revocations add column created_at timestamp without time zone not null default now();
@@ -0,0 +1,2 @@
+drop index public.index_revocations_on_event_id;
+alter table public.revocations drop column event_id;
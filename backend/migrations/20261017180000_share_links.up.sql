@@ -0,0 +1,4 @@
+-- Read-only links to an event are recorded alongside sessions, marked with the event they share
+alter table public.revocations add column event_id bigint references public.events(id) on delete cascade;
+
+create index index_revocations_on_event_id on public.revocations using btree (event_id);
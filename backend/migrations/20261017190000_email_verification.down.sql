@@ -0,0 +1,2 @@
+alter table public.users drop column account_email_sent_at;
+alter table public.users drop column email_verified_at;
@@ -0,0 +1,7 @@
+alter table public.users add column email_verified_at timestamp without time zone;
+-- When we last sent a registration or "you already have an account" email, to limit how often we do
+alter table public.users add column account_email_sent_at timestamp without time zone;
+
+-- Anyone who has set a password did so by following an emailed link
+update public.users set email_verified_at = updated_at
+where encrypted_password <> 'empty password cannot log in';
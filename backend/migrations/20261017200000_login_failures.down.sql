@@ -0,0 +1 @@
+drop table public.login_failures;
@@ -0,0 +1,10 @@
+-- Keyed by the email that was tried, rather than by user,
+-- so that unknown emails are throttled just like real accounts
+create table public.login_failures (
+    email text not null,
+    failures integer not null default 0,
+    last_failed_at timestamp without time zone not null,
+    locked_until timestamp without time zone,
+    constraint login_failures_pkey primary key (email)
+);
+alter table public.login_failures owner to wagthepig;
@@ -0,0 +1,2 @@
+drop table public.oidc_identities;
+drop table public.oidc_logins;
@@ -0,0 +1,24 @@
+-- Logins that have been sent to the identity provider and not come back yet.
+-- Each is used once, by the state it was sent with.
+create table public.oidc_logins (
+    state text not null,
+    nonce text not null,
+    pkce_verifier text not null,
+    created_at timestamp without time zone not null default now(),
+    constraint oidc_logins_pkey primary key (state)
+);
+alter table public.oidc_logins owner to wagthepig;
+
+-- A provider's account, by its issuer and subject, linked to one of ours
+create table public.oidc_identities (
+    issuer text not null,
+    subject text not null,
+    user_id bigint not null references public.users(id) on delete cascade,
+    email text not null,
+    created_at timestamp without time zone not null default now(),
+    last_login_at timestamp without time zone,
+    constraint oidc_identities_pkey primary key (issuer, subject)
+);
+alter table public.oidc_identities owner to wagthepig;
+
+create index index_oidc_identities_on_user_id on public.oidc_identities using btree (user_id);
@@ -0,0 +1,3 @@
+drop table public.passkey_challenges;
+drop table public.passkeys;
+alter table public.users drop column passkey_handle;
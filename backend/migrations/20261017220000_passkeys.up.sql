@@ -0,0 +1,28 @@
+-- The WebAuthn user handle: random, so that it doesn't give away who the user is
+alter table public.users add column passkey_handle uuid unique;
+
+-- The serialized webauthn-rs Passkey, which carries its own signature counter
+create table public.passkeys (
+    id bigserial primary key,
+    user_id bigint not null references public.users(id) on delete cascade,
+    credential_id bytea not null unique,
+    label text not null,
+    passkey jsonb not null,
+    created_at timestamp without time zone not null default now(),
+    last_used_at timestamp without time zone
+);
+alter table public.passkeys owner to wagthepig;
+
+create index index_passkeys_on_user_id on public.passkeys using btree (user_id);
+
+-- Registrations and logins waiting for the authenticator to answer.
+-- Each is used once, by the id it was sent with.
+create table public.passkey_challenges (
+    id text not null,
+    user_id bigint not null references public.users(id) on delete cascade,
+    registration boolean not null,
+    state jsonb not null,
+    created_at timestamp without time zone not null default now(),
+    constraint passkey_challenges_pkey primary key (id)
+);
+alter table public.passkey_challenges owner to wagthepig;
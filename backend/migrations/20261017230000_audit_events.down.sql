@@ -0,0 +1,2 @@
+drop table public.audit_events;
+drop function public.audit_events_append_only();
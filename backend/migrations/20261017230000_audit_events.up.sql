@@ -0,0 +1,29 @@
+-- The security log. `email` is the account an event concerns (for logins, whatever was tried);
+-- `actor` is whoever proved who they were, if anyone: the account itself, or an administrator.
+create table public.audit_events (
+    id bigserial primary key,
+    email text not null,
+    actor text,
+    action text not null,
+    outcome text not null,
+    client_ip text,
+    detail text,
+    created_at timestamp without time zone not null default now()
+);
+alter table public.audit_events owner to wagthepig;
+
+create index index_audit_events_on_email_and_created_at on public.audit_events using btree (email, created_at);
+create index index_audit_events_on_created_at on public.audit_events using btree (created_at);
+
+-- Entries are only ever added, or pruned once they're past keeping
+create function public.audit_events_append_only() returns trigger
+    language plpgsql
+    as $$
+begin
+    raise exception 'audit events can''t be changed';
+end;
+$$;
+alter function public.audit_events_append_only() owner to wagthepig;
+
+create trigger audit_events_append_only before update on public.audit_events
+    for each row execute function public.audit_events_append_only();
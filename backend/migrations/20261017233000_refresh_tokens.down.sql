@@ -0,0 +1,13 @@
+create or replace function notify_revocation()
+returns trigger as $$
+begin
+    if new.revoked is not null then
+        perform pg_notify('revocations', json_build_object('data', new.data, 'expires', new.expires)::text);
+    end if;
+    return new;
+end;
+$$ language 'plpgsql';
+
+drop index public.index_revocations_on_session_chain;
+alter table public.revocations drop column refresh;
+alter table public.revocations drop column session_chain;
@@ -0,0 +1,17 @@
+-- A session is an access token and a refresh token, replaced as a pair each time the refresh token is used.
+-- Every pair in a session shares the chain id, which is the revocation id of its first refresh token.
+alter table public.revocations add column session_chain text;
+alter table public.revocations add column refresh boolean not null default false;
+
+create index index_revocations_on_session_chain on public.revocations using btree (session_chain);
+
+-- Refresh tokens are checked against the table directly, so there's no need to broadcast them
+create or replace function notify_revocation()
+returns trigger as $$
+begin
+    if new.revoked is not null and not new.refresh then
+        perform pg_notify('revocations', json_build_object('data', new.data, 'expires', new.expires)::text);
+    end if;
+    return new;
+end;
+$$ language 'plpgsql';
@@ -0,0 +1,2 @@
+-- Fails if any anonymized games remain
+alter table public.games alter column suggestor_id set not null;
@@ -0,0 +1,2 @@
+-- Games suggested by someone who has since deleted their account stay with the event, unattributed
+alter table public.games alter column suggestor_id drop not null;
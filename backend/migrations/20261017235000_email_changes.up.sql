@@ -0,0 +1,10 @@
+-- A pending change of login email, waiting for both addresses to confirm it.
+-- Asking again replaces it, confirmations and all.
+create table public.email_changes (
+    user_id bigint primary key references public.users(id) on delete cascade,
+    new_email text not null,
+    old_confirmed_at timestamp without time zone,
+    new_confirmed_at timestamp without time zone,
+    created_at timestamp without time zone not null default now()
+);
+alter table public.email_changes owner to wagthepig;
@@ -0,0 +1 @@
+alter table public.events drop column archived_at;
@@ -0,0 +1,2 @@
+-- Archived events drop out of the default list, but stay readable
+alter table public.events add column archived_at timestamp without time zone;
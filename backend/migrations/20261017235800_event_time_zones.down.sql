@@ -0,0 +1,3 @@
+alter table public.events drop constraint events_end_after_start;
+alter table public.events drop column time_zone;
+alter table public.events drop column ends_at;
@@ -0,0 +1,4 @@
+-- Times stay in UTC; the zone is where the event happens, for showing them
+alter table public.events add column ends_at timestamp without time zone;
+alter table public.events add column time_zone text not null default 'UTC';
+alter table public.events add constraint events_end_after_start check (ends_at is null or date is null or ends_at >= date);
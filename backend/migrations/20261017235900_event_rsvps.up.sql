@@ -0,0 +1,23 @@
+create table public.rsvps (
+    event_id bigint not null references public.events(id) on delete cascade,
+    user_id bigint not null references public.users(id) on delete cascade,
+    status text not null,
+    arrives_at timestamp without time zone,
+    departs_at timestamp without time zone,
+    created_at timestamp without time zone not null default now(),
+    updated_at timestamp without time zone not null default now(),
+    constraint rsvps_pkey primary key (event_id, user_id),
+    constraint rsvps_status check (status in ('going', 'maybe', 'not_going')),
+    constraint rsvps_depart_after_arrive check (departs_at is null or arrives_at is null or departs_at >= arrives_at)
+);
+alter table public.rsvps owner to wagthepig;
+
+create index index_rsvps_on_user_id on public.rsvps using btree (user_id);
+
+-- Until now, marking interest in a game was how people said they were coming
+insert into public.rsvps (event_id, user_id, status)
+select distinct games.event_id, interests.user_id, 'going'
+from public.interests
+join public.games on interests.game_id = games.id
+where games.event_id is not null
+on conflict do nothing;
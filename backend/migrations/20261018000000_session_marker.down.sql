@@ -0,0 +1 @@
+alter table public.revocations drop column session;
@@ -0,0 +1,8 @@
+-- Marks the token that stands for a session in the sessions list: a session's refresh token.
+-- Login links and other single-use tokens are stored the same way as sessions used to be, so they can't be told apart otherwise.
+alter table public.revocations add column session boolean not null default false;
+
+update public.revocations set session = true where refresh;
+-- Sessions from before refresh tokens were recorded with the client they were issued to
+update public.revocations set session = true
+    where session_chain is null and label is null and event_id is null and clienthint is not null;
@@ -0,0 +1,7 @@
+create or replace function public.audit_events_append_only() returns trigger
+    language plpgsql
+    as $$
+begin
+    raise exception 'audit events can''t be changed';
+end;
+$$;
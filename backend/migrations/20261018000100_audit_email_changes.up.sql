@@ -0,0 +1,12 @@
+-- Entries follow their account to a new email; nothing else about them can change
+create or replace function public.audit_events_append_only() returns trigger
+    language plpgsql
+    as $$
+begin
+    if (new.id, new.action, new.outcome, new.client_ip, new.detail, new.created_at)
+        is not distinct from (old.id, old.action, old.outcome, old.client_ip, old.detail, old.created_at) then
+        return new;
+    end if;
+    raise exception 'audit events can''t be changed';
+end;
+$$;
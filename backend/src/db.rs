@@ -1,11 +1,11 @@
 use core::fmt;
-use std::{convert::Infallible, future::Future, time::SystemTime};
+use std::{convert::Infallible, future::Future, net::SocketAddr, time::SystemTime};
 use axum::response::IntoResponse;
 use chrono::{NaiveDateTime, TimeZone as _, Utc};
 use futures::TryFutureExt as _;
-use sqlx::{Executor, Postgres};
+use sqlx::{types::Uuid, Executor, Postgres};
 use serde::{Serialize, Deserialize};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
@@ -146,6 +146,14 @@ pub(crate) struct User<T> {
     pub encrypted_password: Password,
     pub updated_at: NaiveDateTime,
     pub created_at: NaiveDateTime,
+    pub is_admin: bool,
+    pub locked_at: Option<NaiveDateTime>,
+    pub totp_secret: Option<Vec<u8>>,
+    pub totp_confirmed_at: Option<NaiveDateTime>,
+    pub totp_last_step: Option<i64>,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub account_email_sent_at: Option<NaiveDateTime>,
+    pub passkey_handle: Option<Uuid>,
 
     // XXX these fields are slated for removal
     pub remember_created_at: Option<NaiveDateTime>,
@@ -154,15 +162,18 @@ pub(crate) struct User<T> {
 }
 
 impl User<UserId> {
+    /// Leaves an existing account alone, returning None
     pub fn create<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: &str, name: &str, bgg: &str)
-    -> impl Future<Output = Result<Self, Error>> + 'a {
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
         sqlx::query_as!(
             Self,
             r#"insert into users
             ("email", "name", "bgg_username", "encrypted_password")
-            values ($1, $2, $3, 'empty password cannot log in') returning *"#,
+            values ($1, $2, $3, 'empty password cannot log in')
+            on conflict (email) do nothing
+            returning *"#,
             email, name, bgg)
-            .fetch_one(db)
+            .fetch_optional(db)
             .map_err(Error::from)
     }
 
@@ -176,15 +187,49 @@ impl User<UserId> {
             .map_err(Error::from)
     }
 
+    pub fn by_id<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: UserId)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "select * from users where id = $1",
+            id.id())
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    pub fn get_all<'a>(db: impl Executor<'a, Database = Postgres> + 'a)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "select * from users order by email")
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Everyone who's going to the event, or might be
     pub fn get_all_by_event_id<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId)
     -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
         sqlx::query_as!(
             Self,
-            r#"select distinct users.*
+            r#"select users.*
             from users
-            join interests on interests.user_id = users.id
-            join games on interests.game_id = games.id
-            where games.event_id = $1"#,
+            join rsvps on rsvps.user_id = users.id
+            where rsvps.event_id = $1 and rsvps.status <> 'not_going'
+            order by rsvps.created_at"#,
+            event_id.id())
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    pub fn get_organizers_by_event_id<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"select users.*
+            from users
+            join event_organizers on event_organizers.user_id = users.id
+            where event_organizers.event_id = $1
+            order by event_organizers.created_at"#,
             event_id.id())
             .fetch_all(db)
             .map_err(Error::from)
@@ -213,156 +258,1416 @@ impl User<UserId> {
             .map_ok(|_| ())
             .map_err(Error::from)
     }
+
+    /// Records that the account's email address has been shown to work. Keeps the first time.
+    pub fn verify_email<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"update users set "email_verified_at" = $2 where email = $1 and email_verified_at is null"#,
+            email, now)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Gives the user `handle` as their passkey user handle, unless they already have one
+    pub fn claim_passkey_handle<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: UserId, handle: Uuid)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update users set "passkey_handle" = coalesce(passkey_handle, $2) where id = $1 returning *"#,
+            id.id(), handle)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Claims the right to send the account an email about its registration,
+    /// if one hasn't been sent since `since`. Returns false for unknown accounts.
+    pub fn claim_account_email<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, now: NaiveDateTime, since: NaiveDateTime)
+    -> impl Future<Output = Result<bool, Error>> + 'a {
+        sqlx::query_scalar!(
+            r#"update users set "account_email_sent_at" = $2
+            where email = $1 and (account_email_sent_at is null or account_email_sent_at < $3)
+            returning id"#,
+            email, now, since)
+            .fetch_optional(db)
+            .map_ok(|id| id.is_some())
+            .map_err(Error::from)
+    }
+
+    /// Locks (with Some(time)) or unlocks (with None) an account
+    pub fn set_locked<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, locked_at: Option<NaiveDateTime>)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update users set "locked_at" = $1 where email = $2 returning *"#,
+            locked_at, email)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Starts TOTP enrollment with an (encrypted) secret. Fails once enrollment has been confirmed.
+    pub fn set_totp_secret<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, sealed: Vec<u8>)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update users set "totp_secret" = $1, "totp_last_step" = null
+            where email = $2 and totp_confirmed_at is null returning *"#,
+            sealed, email)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    pub fn confirm_totp<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, step: i64, now: NaiveDateTime)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update users set "totp_confirmed_at" = $1, "totp_last_step" = $2
+            where email = $3 and totp_secret is not null returning *"#,
+            now, step, email)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Records a used TOTP time step. False if that step (or a later one) was already used.
+    pub fn use_totp_step<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, step: i64)
+    -> impl Future<Output = Result<bool, Error>> + 'a {
+        sqlx::query!(
+            r#"update users set "totp_last_step" = $1
+            where email = $2 and (totp_last_step is null or totp_last_step < $1)"#,
+            step, email)
+            .execute(db)
+            .map_ok(|result| result.rows_affected() == 1)
+            .map_err(Error::from)
+    }
+
+    pub fn clear_totp<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update users set "totp_secret" = null, "totp_confirmed_at" = null, "totp_last_step" = null
+            where email = $1 returning *"#,
+            email)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Removes the account itself. Its interests, tokens and games have to be dealt with first;
+    /// the events it created are left to their other organizers.
+    pub fn delete<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: UserId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"with uncredited as (update events set creator_id = null where creator_id = $1)
+            delete from users where id = $1"#,
+            id.id())
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    pub fn email_taken<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String)
+    -> impl Future<Output = Result<bool, Error>> + 'a {
+        sqlx::query_scalar!(
+            r#"select exists(select 1 from users where email = $1) as "taken!""#,
+            email)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Fails if the address already belongs to another account
+    pub fn change_email<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: UserId, new_email: String)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update users set "email" = $2 where id = $1 returning *"#,
+            id.id(), new_email)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Replaces the password with one that can't log in, so that it has to be reset
+    pub fn clear_password<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update users set "encrypted_password" = 'empty password cannot log in' where email = $1 returning *"#,
+            email)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
 }
 
-id_type!(RevocationId(i64));
+id_type!(RecoveryCodeId(i64));
 
 #[derive(sqlx::FromRow, Default, Debug)]
 #[allow(dead_code)] // Have to match DB
-pub(crate) struct Revocation<T> {
+pub(crate) struct RecoveryCode<T> {
     pub id: T,
-    pub data: String,
-    pub expires: NaiveDateTime,
-    pub revoked: Option<NaiveDateTime>,
-    pub username: String,
-    pub clienthint: Option<String>
+    pub user_id: i64,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
 }
 
-// XXX Can we just use chrono?
-fn system_to_naive(sys: SystemTime) -> NaiveDateTime {
-    let dur = sys.duration_since(std::time::UNIX_EPOCH).expect("1970 to be in the past");
-    let (sec, nsec) = (dur.as_secs() as i64, dur.subsec_nanos());
-    Utc.timestamp_opt(sec,nsec).unwrap().naive_utc()
-}
+impl RecoveryCode<RecoveryCodeId> {
+    /// Replaces any existing codes for the user with the given hashes
+    pub fn replace_for_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId, hashes: Vec<String>)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"with cleared as (delete from recovery_codes where user_id = $1)
+            insert into recovery_codes (user_id, code_hash)
+            select $1, unnest($2::text[])"#,
+            user_id.id(), &hashes)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
 
-impl Revocation<NoId> {
-    pub fn add_batch<'a>(db: impl Executor<'a, Database = Postgres> + 'a, rids: Vec<String>, username: String, expiry: SystemTime)
-    -> impl Future<Output = Result<Vec<RevocationId>, Error>> + 'a {
-        let expiry = system_to_naive(expiry);
+    /// Marks a code used, if it belongs to the user and hasn't been used already
+    pub fn consume<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId, hash: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update recovery_codes set "used_at" = $1
+            where user_id = $2 and code_hash = $3 and used_at is null returning *"#,
+            now, user_id.id(), hash)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    pub fn clear_for_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
         sqlx::query!(
-            r#"insert into revocations
-                ("expires", "username", "data")
-            select $1 as expires, $2 as username, unnest($3::text[])
-            returning id
-            "#, expiry, username, &rids)
+            "delete from recovery_codes where user_id = $1",
+            user_id.id())
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+#[derive(sqlx::FromRow, Default, Debug)]
+pub(crate) struct LoginFailure {
+    pub email: String,
+    pub failures: i32,
+    pub last_failed_at: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+impl LoginFailure {
+    pub fn get<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "select * from login_failures where email = $1",
+            email)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    pub fn get_locked<'a>(db: impl Executor<'a, Database = Postgres> + 'a, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "select * from login_failures where locked_until > $1",
+            now)
             .fetch_all(db)
-            .map_ok(|maps| maps.into_iter().map(|rec| rec.id.into()).collect())
             .map_err(Error::from)
     }
 
-    pub fn cleanup<'a>(db: impl Executor<'a, Database = Postgres> + 'a, system_expired: SystemTime)
+    /// Counts a failed login, returning the new tally
+    pub fn record<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"insert into login_failures as lf ("email", "failures", "last_failed_at")
+            values ($1, 1, $2)
+            on conflict (email) do update set "failures" = lf.failures + 1, "last_failed_at" = $2
+            returning *"#,
+            email, now)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Locks the email out until `until`, starting the count again afterwards
+    pub fn lock<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, until: NaiveDateTime)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update login_failures set "locked_until" = $2, "failures" = 0 where email = $1 returning *"#,
+            email, until)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    pub fn clear<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            "delete from login_failures where email = $1",
+            email)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Forgets failures older than `before`, unless they've left a lock still in force
+    pub fn cleanup<'a>(db: impl Executor<'a, Database = Postgres> + 'a, before: NaiveDateTime, now: NaiveDateTime)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"delete from login_failures
+            where last_failed_at < $1 and (locked_until is null or locked_until < $2)"#,
+            before, now)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+/// Which address a confirmation of an email change came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum EmailChangeSide {
+    Old,
+    New,
+}
+
+impl EmailChangeSide {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            EmailChangeSide::Old => "old",
+            EmailChangeSide::New => "new",
+        }
+    }
+}
+
+#[derive(sqlx::FromRow, Default, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct EmailChange {
+    pub user_id: i64,
+    pub new_email: String,
+    pub old_confirmed_at: Option<NaiveDateTime>,
+    pub new_confirmed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl EmailChange {
+    pub fn is_confirmed(&self) -> bool {
+        self.old_confirmed_at.is_some() && self.new_confirmed_at.is_some()
+    }
+
+    /// Starts a change, replacing any that was pending
+    pub fn request<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId, new_email: String)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"insert into email_changes ("user_id", "new_email") values ($1, $2)
+            on conflict (user_id) do update set
+                ("new_email", "old_confirmed_at", "new_confirmed_at", "created_at") = ($2, null, null, now())
+            returning *"#,
+            user_id.id(), new_email)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    pub fn for_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "select * from email_changes where user_id = $1",
+            user_id.id())
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    /// Records one address's confirmation, if the change to `new_email` is still pending
+    pub fn confirm<'a>(
+        db: impl Executor<'a, Database = Postgres> + 'a,
+        user_id: UserId,
+        new_email: String,
+        side: EmailChangeSide,
+        now: NaiveDateTime
+    ) -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update email_changes set
+                "old_confirmed_at" = case when $3 = 'old' then $4 else old_confirmed_at end,
+                "new_confirmed_at" = case when $3 = 'new' then $4 else new_confirmed_at end
+            where user_id = $1 and new_email = $2
+            returning *"#,
+            user_id.id(), new_email, side.as_str(), now)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    pub fn remove<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            "delete from email_changes where user_id = $1",
+            user_id.id())
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    pub fn cleanup<'a>(db: impl Executor<'a, Database = Postgres> + 'a, before: NaiveDateTime)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            "delete from email_changes where created_at < $1",
+            before)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+#[derive(sqlx::FromRow, Default, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct OidcLogin {
+    pub state: String,
+    pub nonce: String,
+    pub pkce_verifier: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl OidcLogin {
+    pub fn add<'a>(db: impl Executor<'a, Database = Postgres> + 'a, state: String, nonce: String, pkce_verifier: String)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"insert into oidc_logins ("state", "nonce", "pkce_verifier") values ($1, $2, $3) returning *"#,
+            state, nonce, pkce_verifier)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Removes the login begun with `state`, if it was begun after `since`, so it can't be used again
+    pub fn take<'a>(db: impl Executor<'a, Database = Postgres> + 'a, state: String, since: NaiveDateTime)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "delete from oidc_logins where state = $1 and created_at > $2 returning *",
+            state, since)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    pub fn cleanup<'a>(db: impl Executor<'a, Database = Postgres> + 'a, before: NaiveDateTime)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            "delete from oidc_logins where created_at < $1",
+            before)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+#[derive(sqlx::FromRow, Default, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub user_id: i64,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub last_login_at: Option<NaiveDateTime>,
+}
+
+impl OidcIdentity {
+    /// Notes a login by the provider's account, if it's been linked to one of ours
+    pub fn touch<'a>(db: impl Executor<'a, Database = Postgres> + 'a, issuer: String, subject: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update oidc_identities set "last_login_at" = $3 where issuer = $1 and subject = $2 returning *"#,
+            issuer, subject, now)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    /// Links the provider's account to the user with the same email, if there is one
+    pub fn link_by_email<'a>(db: impl Executor<'a, Database = Postgres> + 'a, issuer: String, subject: String, email: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"insert into oidc_identities ("issuer", "subject", "user_id", "email", "last_login_at")
+            select $1, $2, users.id, users.email, $4 from users where users.email = $3
+            on conflict (issuer, subject) do nothing
+            returning *"#,
+            issuer, subject, email, now)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+}
+
+id_type!(PasskeyId(i64));
+
+#[derive(sqlx::FromRow, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct Passkey {
+    pub id: PasskeyId,
+    pub user_id: i64,
+    pub credential_id: Vec<u8>,
+    pub label: String,
+    pub passkey: serde_json::Value,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+impl Passkey {
+    pub fn add<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId, credential_id: Vec<u8>, label: String, passkey: serde_json::Value)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"insert into passkeys ("user_id", "credential_id", "label", "passkey")
+            values ($1, $2, $3, $4)
+            returning *"#,
+            user_id.id(), credential_id, label, passkey)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    pub fn get_for_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "select * from passkeys where user_id = $1 order by created_at",
+            user_id.id())
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Notes a login with the passkey, saving its updated counter
+    pub fn record_use<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: PasskeyId, passkey: serde_json::Value, now: NaiveDateTime)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"update passkeys set "passkey" = $2, "last_used_at" = $3 where id = $1"#,
+            id.id(), passkey, now)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    pub fn remove<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: PasskeyId, user_id: UserId)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "delete from passkeys where id = $1 and user_id = $2 returning *",
+            id.id(), user_id.id())
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct PasskeyChallenge {
+    pub id: String,
+    pub user_id: i64,
+    pub registration: bool,
+    pub state: serde_json::Value,
+    pub created_at: NaiveDateTime,
+}
+
+impl PasskeyChallenge {
+    pub fn add<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: String, user_id: UserId, registration: bool, state: serde_json::Value)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"insert into passkey_challenges ("id", "user_id", "registration", "state")
+            values ($1, $2, $3, $4)
+            returning *"#,
+            id, user_id.id(), registration, state)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Removes the user's challenge, if it was issued after `since`, so it can't be answered twice
+    pub fn take<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: String, user_id: UserId, registration: bool, since: NaiveDateTime)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"delete from passkey_challenges
+            where id = $1 and user_id = $2 and registration = $3 and created_at > $4
+            returning *"#,
+            id, user_id.id(), registration, since)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    pub fn cleanup<'a>(db: impl Executor<'a, Database = Postgres> + 'a, before: NaiveDateTime)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            "delete from passkey_challenges where created_at < $1",
+            before)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+/// What happened, as recorded in `audit_events.action`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AuditAction {
+    Login,
+    Logout,
+    LoginLock,
+    LoginLockNotified,
+    LoginLinkRequested,
+    LoginLinkSent,
+    PasswordChange,
+    PasswordResetRequested,
+    PasswordResetSent,
+    ForcedPasswordReset,
+    Registration,
+    RegistrationSent,
+    VerificationRequested,
+    SessionsRevoked,
+    SessionRefresh,
+    AccountLocked,
+    AccountUnlocked,
+    AccountExported,
+    AccountDeletionRequested,
+    AccountDeletionSent,
+    AccountDeleted,
+    EmailChangeRequested,
+    EmailChangeSent,
+    EmailChangeConfirmed,
+    EmailChanged,
+    ApiTokenRevoked,
+}
+
+impl AuditAction {
+    pub(crate) fn as_str(self) -> &'static str {
+        use AuditAction::*;
+        match self {
+            Login                  => "login",
+            Logout                 => "logout",
+            LoginLock              => "login_lock",
+            LoginLockNotified      => "login_lock_notified",
+            LoginLinkRequested     => "login_link_requested",
+            LoginLinkSent          => "login_link_sent",
+            PasswordChange         => "password_change",
+            PasswordResetRequested => "password_reset_requested",
+            PasswordResetSent      => "password_reset_sent",
+            ForcedPasswordReset    => "forced_password_reset",
+            Registration           => "registration",
+            RegistrationSent       => "registration_sent",
+            VerificationRequested  => "verification_requested",
+            SessionsRevoked        => "sessions_revoked",
+            SessionRefresh         => "session_refresh",
+            AccountLocked          => "account_locked",
+            AccountUnlocked        => "account_unlocked",
+            AccountExported        => "account_exported",
+            AccountDeletionRequested => "account_deletion_requested",
+            AccountDeletionSent    => "account_deletion_sent",
+            AccountDeleted         => "account_deleted",
+            EmailChangeRequested   => "email_change_requested",
+            EmailChangeSent        => "email_change_sent",
+            EmailChangeConfirmed   => "email_change_confirmed",
+            EmailChanged           => "email_changed",
+            ApiTokenRevoked        => "api_token_revoked",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AuditOutcome {
+    Success,
+    Failure,
+    /// Refused without checking, because of too many failures
+    Throttled,
+}
+
+impl AuditOutcome {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            AuditOutcome::Success   => "success",
+            AuditOutcome::Failure   => "failure",
+            AuditOutcome::Throttled => "throttled",
+        }
+    }
+}
+
+id_type!(AuditEventId(i64));
+
+#[derive(sqlx::FromRow, Default, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct AuditEvent<T> {
+    pub id: T,
+    pub email: String,
+    pub actor: Option<String>,
+    pub action: String,
+    pub outcome: String,
+    pub client_ip: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl AuditEvent<NoId> {
+    pub fn new(email: String, action: AuditAction, outcome: AuditOutcome) -> Self {
+        Self{
+            email,
+            action: action.as_str().to_string(),
+            outcome: outcome.as_str().to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn actor(self, actor: String) -> Self {
+        Self{ actor: Some(actor), ..self }
+    }
+
+    pub fn client_ip(self, addr: SocketAddr) -> Self {
+        Self{ client_ip: Some(addr.ip().to_string()), ..self }
+    }
+
+    pub fn detail(self, detail: &str) -> Self {
+        Self{ detail: Some(detail.to_string()), ..self }
+    }
+
+    pub fn record<'a>(self, db: impl Executor<'a, Database = Postgres> + 'a)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"insert into audit_events ("email", "actor", "action", "outcome", "client_ip", "detail")
+            values ($1, $2, $3, $4, $5, $6)"#,
+            self.email, self.actor, self.action, self.outcome, self.client_ip, self.detail)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// For auditing something that's already happened, or that shouldn't fail just because the audit log can't be written:
+    /// a failure is logged rather than returned
+    pub async fn record_or_warn<'a>(self, db: impl Executor<'a, Database = Postgres> + 'a) {
+        let (email, action) = (self.email.clone(), self.action.clone());
+        if let Err(error) = self.record(db).await {
+            warn!("Couldn't audit {action} for {email}: {error:?}")
+        }
+    }
+
+    pub fn prune<'a>(db: impl Executor<'a, Database = Postgres> + 'a, before: NaiveDateTime)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            "delete from audit_events where created_at < $1",
+            before)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+impl AuditEvent<AuditEventId> {
+    /// Follows the account to its new email, so its owner can still see its history
+    pub fn rename_email<'a>(db: impl Executor<'a, Database = Postgres> + 'a, old: String, new: String)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"update audit_events
+            set "email" = case when email = $1 then $2 else email end,
+                "actor" = case when actor = $1 then $2 else actor end
+            where email = $1 or actor = $1"#,
+            old, new)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// The newest `limit` events about the account
+    pub fn get_for_email<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String, limit: i64)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "select * from audit_events where email = $1 order by created_at desc, id desc limit $2",
+            email, limit)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// The newest `limit` events matching whichever filters are given
+    pub fn search<'a>(
+        db: impl Executor<'a, Database = Postgres> + 'a,
+        email: Option<String>,
+        action: Option<String>,
+        since: Option<NaiveDateTime>,
+        limit: i64
+    ) -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"select * from audit_events
+            where ($1::text is null or email = $1)
+                and ($2::text is null or action = $2)
+                and ($3::timestamp is null or created_at >= $3)
+            order by created_at desc, id desc
+            limit $4"#,
+            email, action, since, limit)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+}
+
+id_type!(RevocationId(i64));
+
+#[derive(sqlx::FromRow, Default, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct Revocation<T> {
+    pub id: T,
+    pub data: String,
+    pub expires: NaiveDateTime,
+    pub revoked: Option<NaiveDateTime>,
+    pub username: String,
+    pub clienthint: Option<String>,
+    pub client_ip: Option<String>,
+    pub label: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub event_id: Option<i64>,
+    pub session_chain: Option<String>,
+    pub refresh: bool,
+    pub session: bool,
+}
+
+// XXX Can we just use chrono?
+fn system_to_naive(sys: SystemTime) -> NaiveDateTime {
+    let dur = sys.duration_since(std::time::UNIX_EPOCH).expect("1970 to be in the past");
+    let (sec, nsec) = (dur.as_secs() as i64, dur.subsec_nanos());
+    Utc.timestamp_opt(sec,nsec).unwrap().naive_utc()
+}
+
+impl Revocation<NoId> {
+    pub fn add_batch<'a>(
+        db: impl Executor<'a, Database = Postgres> + 'a,
+        rids: Vec<String>,
+        username: String,
+        expiry: SystemTime,
+        clienthint: Option<String>,
+        client_ip: Option<String>
+    ) -> impl Future<Output = Result<Vec<RevocationId>, Error>> + 'a {
+        let expiry = system_to_naive(expiry);
+        sqlx::query!(
+            r#"insert into revocations
+                ("expires", "username", "clienthint", "client_ip", "data")
+            select $1 as expires, $2 as username, $3 as clienthint, $4 as client_ip, unnest($5::text[])
+            returning id
+            "#, expiry, username, clienthint, client_ip, &rids)
+            .fetch_all(db)
+            .map_ok(|maps| maps.into_iter().map(|rec| rec.id.into()).collect())
+            .map_err(Error::from)
+    }
+
+    /// Records a session's access token, as part of its chain
+    pub fn add_session_access<'a>(
+        db: impl Executor<'a, Database = Postgres> + 'a,
+        rids: Vec<String>,
+        username: String,
+        expiry: SystemTime,
+        chain: String
+    ) -> impl Future<Output = Result<Vec<RevocationId>, Error>> + 'a {
+        let expiry = system_to_naive(expiry);
+        sqlx::query!(
+            r#"insert into revocations
+                ("expires", "username", "session_chain", "data")
+            select $1 as expires, $2 as username, $3 as session_chain, unnest($4::text[])
+            returning id
+            "#, expiry, username, chain, &rids)
+            .fetch_all(db)
+            .map_ok(|maps| maps.into_iter().map(|rec| rec.id.into()).collect())
+            .map_err(Error::from)
+    }
+
+    /// Records a session's refresh token. It outlives the access token, so it's marked as the session
+    /// in the sessions list, and carries the client details.
+    pub fn add_session_refresh<'a>(
+        db: impl Executor<'a, Database = Postgres> + 'a,
+        rids: Vec<String>,
+        username: String,
+        expiry: SystemTime,
+        chain: String,
+        clienthint: Option<String>,
+        client_ip: Option<String>
+    ) -> impl Future<Output = Result<Vec<RevocationId>, Error>> + 'a {
+        let expiry = system_to_naive(expiry);
+        sqlx::query!(
+            r#"insert into revocations
+                ("expires", "username", "session_chain", "refresh", "session", "clienthint", "client_ip", "data")
+            select $1 as expires, $2 as username, $3 as session_chain, true, true, $4 as clienthint, $5 as client_ip, unnest($6::text[])
+            returning id
+            "#, expiry, username, chain, clienthint, client_ip, &rids)
+            .fetch_all(db)
+            .map_ok(|maps| maps.into_iter().map(|rec| rec.id.into()).collect())
+            .map_err(Error::from)
+    }
+
+    /// Records a personal API token. Only its first revocation id is needed:
+    /// revoking that revokes any token attenuated from it.
+    pub fn add_labeled<'a>(
+        db: impl Executor<'a, Database = Postgres> + 'a,
+        rid: String,
+        username: String,
+        expiry: SystemTime,
+        label: String
+    ) -> impl Future<Output = Result<Revocation<RevocationId>, Error>> + 'a {
+        let expiry = system_to_naive(expiry);
+        sqlx::query_as!(Revocation::<RevocationId>,
+            r#"insert into revocations
+                ("expires", "username", "label", "data")
+            values ($1, $2, $3, $4)
+            returning *
+            "#, expiry, username, label, rid)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    /// Records a read-only link to an event, made by one of its organizers
+    pub fn add_share<'a>(
+        db: impl Executor<'a, Database = Postgres> + 'a,
+        rid: String,
+        username: String,
+        expiry: SystemTime,
+        label: String,
+        event_id: EventId
+    ) -> impl Future<Output = Result<Revocation<RevocationId>, Error>> + 'a {
+        let expiry = system_to_naive(expiry);
+        sqlx::query_as!(Revocation::<RevocationId>,
+            r#"insert into revocations
+                ("expires", "username", "label", "event_id", "data")
+            values ($1, $2, $3, $4, $5)
+            returning *
+            "#, expiry, username, label, event_id.id(), rid)
+            .fetch_one(db)
+            .map_err(Error::from)
+    }
+
+    pub fn cleanup<'a>(db: impl Executor<'a, Database = Postgres> + 'a, system_expired: SystemTime)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        let expired = system_to_naive(system_expired);
+        sqlx::query!(
+            r#"delete from revocations where $1 > expires"#,
+            expired)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+impl Revocation<RevocationId> {
+    /// Revokes the tokens, and the rest of the session they belong to
+    pub fn revoke<'a>(db: impl Executor<'a, Database = Postgres> + 'a, rids: Vec<String>, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations set "revoked" =  $2
+            where data = any($1)
+                or session_chain in (select session_chain from revocations where data = any($1))
+            returning *"#,
+            &rids, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Revokes one-time tokens, returning only those that hadn't already been used
+    pub fn consume<'a>(db: impl Executor<'a, Database = Postgres> + 'a, rids: Vec<String>, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations set "revoked" = $2 where data = any($1) and revoked is null returning *"#,
+            &rids, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Revokes whatever is still live in the sessions the tokens belong to,
+    /// if they were used before `used_before`.
+    /// A refresh token that's already been used turning up again means it was copied,
+    /// and we can't tell which copy is the legitimate one.
+    pub fn revoke_chain_of<'a>(db: impl Executor<'a, Database = Postgres> + 'a, rids: Vec<String>, used_before: NaiveDateTime, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations set "revoked" = $3
+            where revoked is null
+                and session_chain in (select session_chain from revocations where data = any($1) and revoked < $2)
+            returning *"#,
+            &rids, used_before, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    pub fn revoke_for_username<'a>(db: impl Executor<'a, Database = Postgres> + 'a, username: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations set "revoked" = $2  where username = $1 returning *"#,
+            username, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Follows the user to their new email, so that their tokens can still be listed and revoked
+    pub fn rename_username<'a>(db: impl Executor<'a, Database = Postgres> + 'a, old: String, new: String)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"update revocations set "username" = $2 where username = $1"#,
+            old, new)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Revokes every token the user had, and strips the rows of anything that identifies them.
+    /// The rows themselves have to stay until the tokens expire, or the tokens would work again.
+    pub fn scrub_for_username<'a>(db: impl Executor<'a, Database = Postgres> + 'a, username: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations
+            set "revoked" = coalesce(revoked, $2), "username" = '', "clienthint" = null, "client_ip" = null, "label" = null
+            where username = $1
+            returning *"#,
+            username, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    pub fn revoke_by_id<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: RevocationId, username: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations set "revoked" = $3 where id = $1 and username = $2 and revoked is null returning *"#,
+            id.id(), username, now)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    /// Revokes a session, with every token in its chain. The id has to be the session's marked token:
+    /// API tokens, feeds and share links are revoked through their own routes.
+    pub fn revoke_session_by_id<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: RevocationId, username: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations set "revoked" = $3
+            where username = $2 and revoked is null
+                and ((id = $1 and session) or session_chain in (select session_chain from revocations where id = $1 and session))
+            returning *"#,
+            id.id(), username, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Keeps the sessions that the `keep` tokens belong to
+    pub fn revoke_others_for_username<'a>(db: impl Executor<'a, Database = Postgres> + 'a, username: String, keep: Vec<String>, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations set "revoked" = $3
+            where username = $1 and revoked is null and label is null and not (data = any($2))
+                and (session_chain is null
+                    or session_chain not in (select session_chain from revocations where data = any($2) and session_chain is not null))
+            returning *"#,
+            username, &keep, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// The user's sessions, each listed by its marked token
+    pub fn get_live_for_username<'a>(db: impl Executor<'a, Database = Postgres> + 'a, username: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"select * from revocations
+            where username = $1 and revoked is null and session and $2 < expires
+            order by expires desc"#,
+            username, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// The sessions the tokens belong to
+    pub fn chains_for<'a>(db: impl Executor<'a, Database = Postgres> + 'a, rids: Vec<String>)
+    -> impl Future<Output = Result<Vec<String>, Error>> + 'a {
+        sqlx::query_scalar!(
+            r#"select distinct session_chain as "session_chain!" from revocations where data = any($1) and session_chain is not null"#,
+            &rids)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    pub fn get_labeled_for_username<'a>(db: impl Executor<'a, Database = Postgres> + 'a, username: String, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"select * from revocations
+            where username = $1 and revoked is null and label is not null and event_id is null and $2 < expires
+            order by created_at"#,
+            username, now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    pub fn get_shares_for_event<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"select * from revocations where event_id = $1 and revoked is null and $2 < expires order by created_at"#,
+            event_id.id(), now)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Any of an event's organizers may revoke links to it, not just the one who made them
+    pub fn revoke_share<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: RevocationId, event_id: EventId, now: NaiveDateTime)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#"update revocations set "revoked" = $3 where id = $1 and event_id = $2 and revoked is null returning *"#,
+            id.id(), event_id.id(), now)
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    /// Refresh tokens are left out: they're only accepted by the refresh exchange, which checks them itself
+    pub fn get_revoked<'a>(db: impl Executor<'a, Database = Postgres> + 'a, now: NaiveDateTime)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!( Self,
+            r#" select * from revocations where revoked is not null and not refresh and $1 < expires"#,
+            now)
+            .fetch_all(db)
+            .map_err(Error::from)
+
+    }
+}
+
+id_type!(EventId(i64));
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EventSort {
+    #[default]
+    Date,
+    Name,
+    Loc,
+}
+
+impl EventSort {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            EventSort::Date => "date",
+            EventSort::Name => "name",
+            EventSort::Loc => "loc",
+        }
+    }
+}
+
+// As a string, so that it can fill in URI templates
+impl Serialize for EventSort {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Which events `Event::search` returns, and in what order
+#[derive(Default, Debug)]
+pub(crate) struct EventFilter {
+    pub include_archived: bool,
+    pub after: Option<NaiveDateTime>,
+    pub before: Option<NaiveDateTime>,
+    pub text: Option<String>,
+    pub sort: EventSort,
+    pub descending: bool,
+}
+
+/// `date` (the start) and `ends_at` are in UTC; `time_zone` is the IANA name of where the event happens
+#[derive(sqlx::FromRow, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct Event<T> {
+    pub id: T,
+    pub name: Option<String>,
+    pub date: Option<NaiveDateTime>,
+    pub r#where: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub description: Option<String>,
+    pub creator_id: Option<i64>,
+    pub archived_at: Option<NaiveDateTime>,
+    pub ends_at: Option<NaiveDateTime>,
+    pub time_zone: String,
+}
+
+impl<T: Default> Default for Event<T> {
+    fn default() -> Self {
+        Self {
+            id: T::default(),
+            name: None,
+            date: None,
+            r#where: None,
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            description: None,
+            creator_id: None,
+            archived_at: None,
+            ends_at: None,
+            time_zone: "UTC".to_string(),
+        }
+    }
+}
+
+impl<F> Event<F> {
+    pub fn with_id<T: PrimaryKey>(&self, id: T) -> Event<T> {
+        Event::<T>{
+            id,
+            name: self.name.clone(),
+            date: self.date,
+            r#where: self.r#where.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            description: self.description.clone(),
+            creator_id: self.creator_id,
+            archived_at: self.archived_at,
+            ends_at: self.ends_at,
+            time_zone: self.time_zone.clone(),
+        }
+    }
+}
+
+impl Event<NoId> {
+    /// Adds the event, with its creator as the first organizer
+    pub fn add_new<'a>(&self, db: impl Executor<'a, Database = Postgres> + 'a, creator: String)
+    -> impl Future<Output = Result<EventId, Error>> + 'a {
+        sqlx::query_scalar!(
+            r#"with new_event as (
+                insert into events ("name", "date", "ends_at", "time_zone", "where", "description", "creator_id")
+                values ($1, $2, $3, $4, $5, $6, (select id from users where email = $7))
+                returning id, creator_id
+            )
+            insert into event_organizers ("event_id", "user_id")
+            select id, creator_id from new_event
+            returning event_id"#,
+            self.name, self.date, self.ends_at, self.time_zone, self.r#where, self.description, creator)
+            .fetch_one(db)
+            .map_ok(|n| n.into())
+            .map_err(Error::from)
+    }
+
+}
+
+impl Event<EventId> {
+    /// One page of the events that pass the filter, in its order, with ties broken by id; all of them without a `limit`.
+    /// Events that have started but not ended count as after `filter.after`.
+    pub fn search<'a>(db: impl Executor<'a, Database = Postgres> + 'a, filter: EventFilter, limit: Option<i64>, offset: i64)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        let pattern = filter.text.map(|text| format!("%{}%", text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")));
+        sqlx::query_as!(
+            Self,
+            r#"select * from events
+            where ($1 or archived_at is null)
+              and ($2::timestamp is null or coalesce(ends_at, date) >= $2)
+              and ($3::timestamp is null or date < $3)
+              and ($4::text is null or name ilike $4 or "where" ilike $4 or description ilike $4)
+            order by
+              case when $5 = 'name' and not $6 then name end asc,
+              case when $5 = 'name' and $6 then name end desc,
+              case when $5 = 'loc' and not $6 then "where" end asc,
+              case when $5 = 'loc' and $6 then "where" end desc,
+              case when not $6 then date end asc,
+              case when $6 then date end desc,
+              id
+            limit $7 offset $8"#,
+            filter.include_archived, filter.after, filter.before, pattern, filter.sort.as_str(), filter.descending, limit, offset)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// The unarchived events the user is going to or might go to, in date order.
+    /// Marking interest in a game counts as going, unless the user said otherwise.
+    pub fn get_for_attendee<'a>(db: impl Executor<'a, Database = Postgres> + 'a, email: String)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"select events.* from events
+            join rsvps on rsvps.event_id = events.id
+            join users on rsvps.user_id = users.id
+            where archived_at is null
+              and users.email = $1
+              and rsvps.status <> 'not_going'
+            order by date, id"#,
+            email)
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    pub fn get_by_id<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: EventId)
+    -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            "select * from events where id = $1",
+            id.id())
+            .fetch_optional(db)
+            .map_err(Error::from)
+    }
+
+    pub fn add_organizer<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: EventId, email: String)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"insert into event_organizers ("event_id", "user_id")
+            select $1, id from users where email = $2
+            on conflict do nothing"#,
+            id.id(), email)
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    /// Locks the event's organizers until the transaction ends, so that two removals can't both see
+    /// another organizer left and leave the event with none
+    pub fn lock_organizers<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: EventId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"select user_id from event_organizers where event_id = $1 for update"#,
+            id.id())
+            .fetch_all(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    pub fn remove_organizer<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: EventId, email: String)
     -> impl Future<Output = Result<(), Error>> + 'a {
-        let expired = system_to_naive(system_expired);
         sqlx::query!(
-            r#"delete from revocations where $1 > expires"#,
-            expired)
+            r#"delete from event_organizers
+            where event_id = $1 and user_id = (select id from users where email = $2)"#,
+            id.id(), email)
             .execute(db)
             .map_ok(|_| ())
             .map_err(Error::from)
     }
-}
 
-impl Revocation<RevocationId> {
-    pub fn revoke<'a>(db: impl Executor<'a, Database = Postgres> + 'a, rids: Vec<String>, now: NaiveDateTime)
-    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
-        sqlx::query_as!( Self,
-            r#"update revocations set "revoked" =  $2  where data = any($1) returning *"#,
-            &rids, now)
-            .fetch_all(db)
+    pub fn update<'a>(&self, db: impl Executor<'a, Database = Postgres> + 'a)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update events set ("name", "date", "ends_at", "time_zone", "where", "description") = ($1, $2, $3, $4, $5, $6)
+            where id = $7 returning *"#,
+            self.name, self.date, self.ends_at, self.time_zone, self.r#where, self.description, self.id.id())
+            .fetch_one(db)
             .map_err(Error::from)
     }
 
-    pub fn revoke_for_username<'a>(db: impl Executor<'a, Database = Postgres> + 'a, username: String, now: NaiveDateTime)
-    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
-        sqlx::query_as!( Self,
-            r#"update revocations set "revoked" = $2  where username = $1 returning *"#,
-            username, now)
-            .fetch_all(db)
+    /// Archives the event as of `archived_at`, or brings it back with `None`
+    pub fn set_archived<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: EventId, archived_at: Option<NaiveDateTime>)
+    -> impl Future<Output = Result<Self, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"update events set "archived_at" = $2 where id = $1 returning *"#,
+            id.id(), archived_at)
+            .fetch_one(db)
             .map_err(Error::from)
     }
 
-    pub fn get_revoked<'a>(db: impl Executor<'a, Database = Postgres> + 'a, now: NaiveDateTime)
-    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
-        sqlx::query_as!( Self,
-            r#" select * from revocations where revoked is not null and $1 < expires"#,
-            now)
-            .fetch_all(db)
+    /// Organizers and share links go with the event; its games and their interests have to be removed first
+    pub fn delete<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: EventId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            "delete from events where id = $1",
+            id.id())
+            .execute(db)
+            .map_ok(|_| ())
             .map_err(Error::from)
-
     }
-}
 
-id_type!(EventId(i64));
+}
 
-#[derive(sqlx::FromRow, Default, Debug)]
-#[allow(dead_code)] // Have to match DB
-pub(crate) struct Event<T> {
-    pub id: T,
-    pub name: Option<String>,
-    pub date: Option<NaiveDateTime>,
-    pub r#where: Option<String>,
-    pub created_at: NaiveDateTime,
-    pub updated_at: NaiveDateTime,
-    pub description: Option<String>,
+/// Whether someone is coming to an event, as recorded in `rsvps.status`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, sqlx::Type, Deserialize, Serialize)]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RsvpStatus {
+    Going,
+    Maybe,
+    NotGoing,
 }
 
-impl<F> Event<F> {
-    pub fn with_id<T: PrimaryKey>(&self, id: T) -> Event<T> {
-        Event::<T>{
-            id,
-            name: self.name.clone(),
-            date: self.date,
-            r#where: self.r#where.clone(),
-            created_at: self.created_at,
-            updated_at: self.updated_at,
-            description: self.description.clone(),
+impl RsvpStatus {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            RsvpStatus::Going    => "going",
+            RsvpStatus::Maybe    => "maybe",
+            RsvpStatus::NotGoing => "not_going",
         }
     }
 }
 
-impl Event<NoId> {
-    pub fn add_new<'a>(&self, db: impl Executor<'a, Database = Postgres> + 'a)
-    -> impl Future<Output = Result<EventId, Error>> + 'a {
-        sqlx::query_scalar!(
-            r#"insert into events ("name", "date", "where", "description") values ($1, $2, $3, $4) returning id"#,
-            self.name, self.date, self.r#where, self.description)
-            .fetch_one(db)
-            .map_ok(|n| n.into())
-            .map_err(Error::from)
-    }
-
+/// A user's answer about coming to an event. Arrival and departure are in UTC, like the event's times.
+#[derive(sqlx::FromRow, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct Rsvp {
+    pub event_id: EventId,
+    pub email: String,
+    pub name: Option<String>,
+    pub status: RsvpStatus,
+    pub arrives_at: Option<NaiveDateTime>,
+    pub departs_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
 }
 
-impl Event<EventId> {
-    pub fn get_all<'a>(db: impl Executor<'a, Database = Postgres> + 'a)
+impl Rsvp {
+    /// Going and maybe first, then in the order people answered
+    pub fn get_for_event<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId)
     -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
         sqlx::query_as!(
             Self,
-            "select * from events")
+            r#"select rsvps.event_id as "event_id: EventId", users.email, users.name, rsvps.status as "status: RsvpStatus", rsvps.arrives_at, rsvps.departs_at, rsvps.created_at, rsvps.updated_at
+            from rsvps
+            join users on rsvps.user_id = users.id
+            where rsvps.event_id = $1
+            order by rsvps.status = 'not_going', rsvps.created_at"#,
+            event_id.id())
             .fetch_all(db)
             .map_err(Error::from)
     }
 
-    pub fn get_by_id<'a>(db: impl Executor<'a, Database = Postgres> + 'a, id: EventId)
+    pub fn get<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId, email: String)
     -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
         sqlx::query_as!(
             Self,
-            "select * from events where id = $1",
-            id.id())
+            r#"select rsvps.event_id as "event_id: EventId", users.email, users.name, rsvps.status as "status: RsvpStatus", rsvps.arrives_at, rsvps.departs_at, rsvps.created_at, rsvps.updated_at
+            from rsvps
+            join users on rsvps.user_id = users.id
+            where rsvps.event_id = $1 and users.email = $2"#,
+            event_id.id(), email)
             .fetch_optional(db)
             .map_err(Error::from)
     }
 
-    pub fn update<'a>(&self, db: impl Executor<'a, Database = Postgres> + 'a)
-    -> impl Future<Output = Result<Self, Error>> + 'a {
+    pub fn get_for_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
         sqlx::query_as!(
             Self,
-            r#"update events set ("name", "date", "where", "description") = ($1, $2, $3, $4) where id = $5 returning *"#,
-            self.name, self.date, self.r#where, self.description, self.id.id())
-            .fetch_one(db)
+            r#"select rsvps.event_id as "event_id: EventId", users.email, users.name, rsvps.status as "status: RsvpStatus", rsvps.arrives_at, rsvps.departs_at, rsvps.created_at, rsvps.updated_at
+            from rsvps
+            join users on rsvps.user_id = users.id
+            where rsvps.user_id = $1
+            order by rsvps.created_at"#,
+            user_id.id())
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Records the user's answer, replacing any earlier one
+    pub fn set<'a>(
+        db: impl Executor<'a, Database = Postgres> + 'a,
+        event_id: EventId,
+        email: String,
+        status: RsvpStatus,
+        arrives_at: Option<NaiveDateTime>,
+        departs_at: Option<NaiveDateTime>,
+    ) -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"insert into rsvps ("event_id", "user_id", "status", "arrives_at", "departs_at")
+            select $1, id, $3, $4, $5 from users where email = $2
+            on conflict (event_id, user_id) do update
+            set status = excluded.status, arrives_at = excluded.arrives_at, departs_at = excluded.departs_at, updated_at = now()"#,
+            event_id.id(), email, status.as_str(), arrives_at, departs_at)
+            .execute(db)
+            .map_ok(|_| ())
             .map_err(Error::from)
     }
 
+    /// Whether there was an answer to remove
+    pub fn remove<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId, email: String)
+    -> impl Future<Output = Result<bool, Error>> + 'a {
+        sqlx::query!(
+            r#"delete from rsvps
+            where event_id = $1 and user_id = (select id from users where email = $2)"#,
+            event_id.id(), email)
+            .execute(db)
+            .map_ok(|result| result.rows_affected() > 0)
+            .map_err(Error::from)
+    }
 }
 
 id_type!(GameId(i64));
@@ -517,7 +1822,7 @@ impl<U, I> Game<NoId, EventId, U, I> {
 
 impl Game<GameId, NoId, NoId, Omit> {
     pub fn update<'a>(&self, db: impl Executor<'a, Database = Postgres> + 'a)
-    -> impl Future<Output = Result<Game<GameId, EventId, UserId, Omit>, Error>> + 'a {
+    -> impl Future<Output = Result<Game<GameId, EventId, Option<UserId>, Omit>, Error>> + 'a {
         let data = &self.data;
         sqlx::query_as(
             r#"update games
@@ -538,7 +1843,50 @@ impl Game<GameId, NoId, NoId, Omit> {
     }
 }
 
-impl Game<GameId, EventId, UserId, RecommendData> {
+impl Game<GameId, EventId, Option<UserId>, Omit> {
+    /// The event a game was suggested for, so that what's allowed at the event can be allowed for its games
+    pub fn event_of<'a>(db: impl Executor<'a, Database = Postgres> + 'a, game_id: GameId)
+    -> impl Future<Output = Result<Option<EventId>, Error>> + 'a {
+        sqlx::query_scalar!(
+            r#"select event_id from games where id = $1"#,
+            game_id.id())
+            .fetch_optional(db)
+            .map_ok(|event_id| event_id.map(EventId::from))
+            .map_err(Error::from)
+    }
+
+    pub fn get_suggested_by<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as(
+            r#"select * from games where suggestor_id = $1 order by created_at"#)
+            .bind(user_id.id())
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    /// Games outlive the account that suggested them, since others may be interested
+    pub fn anonymize_for_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"update games set suggestor_id = null where suggestor_id = $1"#,
+            user_id.id())
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    pub fn remove_for_event<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"delete from games where event_id = $1"#,
+            event_id.id())
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
+impl Game<GameId, EventId, Option<UserId>, RecommendData> {
     pub fn get_recommendation<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId, user_ids: Vec<UserId>, extra_players: u8)
     -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
         let must_play = (user_ids.len() + (extra_players as usize)) as i32;
@@ -568,7 +1916,7 @@ impl Game<GameId, EventId, UserId, RecommendData> {
     }
 }
 
-impl Game<GameId, EventId, UserId, InterestData> {
+impl Game<GameId, EventId, Option<UserId>, InterestData> {
     pub fn get_by_id_and_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, game_id: GameId, user_id: String)
     -> impl Future<Output = Result<Option<Self>, Error>> + 'a {
         sqlx::query_as(
@@ -590,7 +1938,7 @@ impl Game<GameId, EventId, UserId, InterestData> {
     }
 }
 
-impl Game<GameId, EventId, UserId, PlayerData> {
+impl Game<GameId, EventId, Option<UserId>, PlayerData> {
     pub fn get_all_for_event_and_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId, email: String)
     -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
         sqlx::query_as(
@@ -619,17 +1967,26 @@ impl Game<GameId, EventId, UserId, PlayerData> {
 }
 
 impl<E, U> Game<GameId, E, U, InterestData> {
+    /// Marking interest in a game also says the user is going to its event, unless they've already answered
     pub fn update_interests<'a>(&self, db: impl Executor<'a, Database = Postgres> + 'a, user_id: String)
     -> impl Future<Output = Result<(), Error>> + 'a {
         let interest = &self.extra;
         (if Some(true) == interest.interested {
             sqlx::query!(
-            r#"insert into interests
+            r#"with interest as (
+                insert into interests
                     ("game_id", "notes", "can_teach", "user_id")
                     values ($1, $2, $3, (select id from users where email = $4))
                 on conflict (game_id, user_id) do update set
                     ("notes", "can_teach") =
-                    ($2, $3)"#,
+                    ($2, $3)
+                returning game_id, user_id
+            )
+            insert into rsvps ("event_id", "user_id", "status")
+            select games.event_id, interest.user_id, 'going'
+            from interest join games on games.id = interest.game_id
+            where games.event_id is not null
+            on conflict do nothing"#,
                 self.id.id(), interest.notes, interest.can_teach, user_id)
         } else {
             sqlx::query!(
@@ -641,23 +1998,401 @@ impl<E, U> Game<GameId, E, U, InterestData> {
     }
 }
 
+id_type!(InterestId(i64));
+
+#[derive(sqlx::FromRow, Debug)]
+#[allow(dead_code)] // Have to match DB
+pub(crate) struct Interest<T> {
+    pub id: T,
+    pub game_id: i64,
+    pub user_id: i64,
+    pub notes: Option<String>,
+    pub can_teach: Option<bool>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Interest<InterestId> {
+    pub fn get_for_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<Vec<Self>, Error>> + 'a {
+        sqlx::query_as!(
+            Self,
+            r#"select * from interests where user_id = $1 order by created_at"#,
+            user_id.id())
+            .fetch_all(db)
+            .map_err(Error::from)
+    }
+
+    pub fn remove_for_user<'a>(db: impl Executor<'a, Database = Postgres> + 'a, user_id: UserId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"delete from interests where user_id = $1"#,
+            user_id.id())
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+
+    pub fn remove_for_event<'a>(db: impl Executor<'a, Database = Postgres> + 'a, event_id: EventId)
+    -> impl Future<Output = Result<(), Error>> + 'a {
+        sqlx::query!(
+            r#"delete from interests where game_id in (select id from games where event_id = $1)"#,
+            event_id.id())
+            .execute(db)
+            .map_ok(|_| ())
+            .map_err(Error::from)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use chrono::NaiveDate;
     use sqlx::Pool;
 
     #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
     async fn test_add_user(pool: Pool<Postgres>) {
-        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap();
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap().expect("a new user");
         let also_testy = User::by_email(&pool, testy.email).await.unwrap();
         assert_eq!(testy.id, also_testy.id);
+        assert!(!also_testy.is_admin);
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_lock_user(pool: Pool<Postgres>) {
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap().expect("a new user");
+        assert!(testy.locked_at.is_none());
+
+        let locked = User::set_locked(&pool, testy.email.clone(), Some(Utc::now().naive_utc())).await.unwrap();
+        assert!(locked.locked_at.is_some());
+
+        let unlocked = User::set_locked(&pool, testy.email.clone(), None).await.unwrap();
+        assert!(unlocked.locked_at.is_none());
+
+        assert!(User::set_locked(&pool, "nobody@nowhere.com".into(), None).await.is_err());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_session_revocation(pool: Pool<Postgres>) {
+        let expires = SystemTime::now() + std::time::Duration::from_secs(60);
+        let now = Utc::now().naive_utc();
+        let email = "test@mctesterson.net".to_string();
+        for rid in ["one", "two", "three"] {
+            Revocation::add_session_refresh(&pool, vec![rid.to_string()], email.clone(), expires, rid.to_string(),
+                Some("Testy/1.0".into()), Some("127.0.0.1".into())).await.unwrap();
+        }
+        Revocation::add_batch(&pool, vec!["login link".to_string()], email.clone(), expires, None, None).await.unwrap();
+
+        let live = Revocation::get_live_for_username(&pool, email.clone(), now).await.unwrap();
+        assert_eq!(live.len(), 3, "single-use tokens aren't sessions");
+        assert_eq!(live[0].clienthint.as_deref(), Some("Testy/1.0"));
+
+        let other = Revocation::revoke_by_id(&pool, live[0].id, "someone@else.com".into(), now).await.unwrap();
+        assert!(other.is_none(), "shouldn't be able to revoke someone else's session");
+
+        let revoked = Revocation::revoke_others_for_username(&pool, email.clone(), vec!["one".to_string()], now).await.unwrap();
+        assert_eq!(revoked.len(), 3, "the other sessions, and the login link");
+
+        let live = Revocation::get_live_for_username(&pool, email.clone(), now).await.unwrap();
+        assert_eq!(live.len(), 1);
+        assert_eq!(live[0].data, "one");
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_refresh_chains(pool: Pool<Postgres>) {
+        let expires = SystemTime::now() + std::time::Duration::from_secs(60);
+        let now = Utc::now().naive_utc();
+        let email = "test@mctesterson.net".to_string();
+        for (chain, n) in [("phone", 1), ("phone", 2), ("laptop", 1)] {
+            Revocation::add_session_refresh(&pool, vec![format!("{chain}-refresh-{n}")], email.clone(), expires, chain.to_string(),
+                Some("Testy/1.0".into()), None).await.unwrap();
+            Revocation::add_session_access(&pool, vec![format!("{chain}-access-{n}")], email.clone(), expires, chain.to_string()).await.unwrap();
+        }
+        Revocation::consume(&pool, vec!["phone-refresh-1".to_string()], now).await.unwrap();
+
+        let live = Revocation::get_live_for_username(&pool, email.clone(), now).await.unwrap();
+        let mut listed = live.iter().map(|s| s.data.as_str()).collect::<Vec<_>>();
+        listed.sort();
+        assert_eq!(listed, vec!["laptop-refresh-1", "phone-refresh-2"], "sessions are listed by their live refresh token");
+        assert_eq!(Revocation::chains_for(&pool, vec!["phone-access-2".to_string()]).await.unwrap(), vec!["phone".to_string()]);
+
+        let revoked = Revocation::revoke_others_for_username(&pool, email.clone(), vec!["phone-access-2".to_string()], now).await.unwrap();
+        let mut revoked = revoked.iter().map(|s| s.data.as_str()).collect::<Vec<_>>();
+        revoked.sort();
+        assert_eq!(revoked, vec!["laptop-access-1", "laptop-refresh-1"], "the current session is kept whole");
+
+        let revoked = Revocation::revoke_chain_of(&pool, vec!["phone-refresh-1".to_string()], now, now).await.unwrap();
+        assert!(revoked.is_empty(), "a refresh token used at the same moment could be a second tab");
+        let later = now + chrono::Duration::minutes(1);
+        let revoked = Revocation::revoke_chain_of(&pool, vec!["phone-refresh-1".to_string()], later, later).await.unwrap();
+        assert_eq!(revoked.len(), 3, "reusing a refresh token revokes everything live in its chain");
+        assert!(Revocation::get_live_for_username(&pool, email.clone(), now).await.unwrap().is_empty());
+        assert!(Revocation::get_revoked(&pool, now).await.unwrap().iter().all(|rev| !rev.refresh));
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_labeled_tokens(pool: Pool<Postgres>) {
+        let expires = SystemTime::now() + std::time::Duration::from_secs(60);
+        let now = Utc::now().naive_utc();
+        let email = "test@mctesterson.net".to_string();
+        Revocation::add_session_refresh(&pool, vec!["session".to_string()], email.clone(), expires, "session".to_string(), None, None).await.unwrap();
+        let token = Revocation::add_labeled(&pool, "bot".to_string(), email.clone(), expires, "game poster".to_string()).await.unwrap();
+
+        let sessions = Revocation::get_live_for_username(&pool, email.clone(), now).await.unwrap();
+        assert_eq!(sessions.iter().map(|s| s.data.as_str()).collect::<Vec<_>>(), vec!["session"]);
+        let tokens = Revocation::get_labeled_for_username(&pool, email.clone(), now).await.unwrap();
+        assert_eq!(tokens.iter().map(|t| t.label.as_deref()).collect::<Vec<_>>(), vec![Some("game poster")]);
+
+        let revoked = Revocation::revoke_others_for_username(&pool, email.clone(), vec![], now).await.unwrap();
+        assert_eq!(revoked.len(), 1, "logging out other sessions should leave API tokens alone");
+
+        Revocation::revoke_by_id(&pool, token.id, email.clone(), now).await.unwrap().expect("token to be revoked");
+        assert!(Revocation::get_labeled_for_username(&pool, email.clone(), now).await.unwrap().is_empty());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_event_shares(pool: Pool<Postgres>) {
+        let expires = SystemTime::now() + std::time::Duration::from_secs(60);
+        let now = Utc::now().naive_utc();
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        let event_id = Event{
+            id: NoId,
+            name: Some("event".into()),
+            ..Event::default()
+        }.add_new(&pool, one.email.clone()).await.unwrap();
+
+        let share = Revocation::add_share(&pool, "share".to_string(), one.email.clone(), expires, "for the club".to_string(), event_id).await.unwrap();
+        assert!(Revocation::get_labeled_for_username(&pool, one.email.clone(), now).await.unwrap().is_empty(),
+            "links to events aren't API tokens");
+        let shares = Revocation::get_shares_for_event(&pool, event_id, now).await.unwrap();
+        assert_eq!(shares.iter().map(|s| s.id).collect::<Vec<_>>(), vec![share.id]);
+
+        assert!(Revocation::revoke_share(&pool, share.id, EventId::from(event_id.id() + 1), now).await.unwrap().is_none());
+        Revocation::revoke_share(&pool, share.id, event_id, now).await.unwrap().expect("share to be revoked");
+        assert!(Revocation::get_shares_for_event(&pool, event_id, now).await.unwrap().is_empty());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_email_verification(pool: Pool<Postgres>) {
+        let now = Utc::now().naive_utc();
+        let later = now + chrono::Duration::minutes(10);
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap()
+            .expect("a new account");
+        assert!(testy.email_verified_at.is_none());
+        assert!(User::create(&pool, "test@mctesterson.net", "Someone Else", "else").await.unwrap().is_none());
+        let testy = User::by_email(&pool, testy.email.clone()).await.unwrap();
+        assert_eq!(testy.name.as_deref(), Some("Testy McTesterson"), "registering again leaves the account alone");
+
+        assert!(User::claim_account_email(&pool, testy.email.clone(), now, now - chrono::Duration::minutes(5)).await.unwrap());
+        assert!(!User::claim_account_email(&pool, testy.email.clone(), now, now - chrono::Duration::minutes(5)).await.unwrap(),
+            "emails are rate limited");
+        assert!(User::claim_account_email(&pool, testy.email.clone(), later, later - chrono::Duration::minutes(5)).await.unwrap());
+        assert!(!User::claim_account_email(&pool, "nobody@nowhere.com".into(), now, now).await.unwrap());
+
+        User::verify_email(&pool, testy.email.clone(), now).await.unwrap();
+        User::verify_email(&pool, testy.email.clone(), later).await.unwrap();
+        let testy = User::by_email(&pool, testy.email.clone()).await.unwrap();
+        assert!(testy.email_verified_at.is_some_and(|at| at < later), "the first verification is kept");
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_login_failures(pool: Pool<Postgres>) {
+        let now = Utc::now().naive_utc();
+        let email = "nobody@nowhere.com".to_string();
+        assert!(LoginFailure::get(&pool, email.clone()).await.unwrap().is_none());
+
+        LoginFailure::record(&pool, email.clone(), now).await.unwrap();
+        let failure = LoginFailure::record(&pool, email.clone(), now).await.unwrap();
+        assert_eq!(failure.failures, 2, "unknown emails are counted too");
+
+        let locked = LoginFailure::lock(&pool, email.clone(), now + chrono::Duration::minutes(15)).await.unwrap();
+        assert_eq!(locked.failures, 0);
+        assert_eq!(LoginFailure::get_locked(&pool, now).await.unwrap().len(), 1);
+
+        LoginFailure::cleanup(&pool, now + chrono::Duration::minutes(1), now).await.unwrap();
+        assert!(LoginFailure::get(&pool, email.clone()).await.unwrap().is_some(), "locks outlive cleanup");
+
+        LoginFailure::clear(&pool, email.clone()).await.unwrap();
+        assert!(LoginFailure::get(&pool, email.clone()).await.unwrap().is_none());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_oidc_logins(pool: Pool<Postgres>) {
+        let now = Utc::now().naive_utc();
+        let an_hour_ago = now - chrono::Duration::hours(1);
+        OidcLogin::add(&pool, "state".to_string(), "nonce".to_string(), "verifier".to_string()).await.unwrap();
+        assert!(OidcLogin::take(&pool, "state".to_string(), now + chrono::Duration::minutes(1)).await.unwrap().is_none(),
+            "logins begun too long ago can't be finished");
+        let login = OidcLogin::take(&pool, "state".to_string(), an_hour_ago).await.unwrap().expect("a pending login");
+        assert_eq!(login.pkce_verifier, "verifier");
+        assert!(OidcLogin::take(&pool, "state".to_string(), an_hour_ago).await.unwrap().is_none(), "logins are used once");
+
+        let issuer = "https://id.example.com".to_string();
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap().expect("a new user");
+        assert!(OidcIdentity::touch(&pool, issuer.clone(), "sub".to_string(), now).await.unwrap().is_none());
+        assert!(OidcIdentity::link_by_email(&pool, issuer.clone(), "sub".to_string(), "nobody@nowhere.com".to_string(), now)
+            .await.unwrap().is_none(), "only existing users are linked");
+        let linked = OidcIdentity::link_by_email(&pool, issuer.clone(), "sub".to_string(), testy.email.clone(), now)
+            .await.unwrap().expect("a linked identity");
+        assert_eq!(linked.user_id, testy.id.id());
+        let touched = OidcIdentity::touch(&pool, issuer.clone(), "sub".to_string(), now).await.unwrap().expect("a linked identity");
+        assert_eq!(touched.user_id, testy.id.id());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_passkeys(pool: Pool<Postgres>) {
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap().expect("a new user");
+        let other = User::create(&pool, "other@mctesterson.net", "Other McTesterson", "other").await.unwrap().expect("a new user");
+        let now = Utc::now().naive_utc();
+
+        let handle = User::claim_passkey_handle(&pool, testy.id, Uuid::from_u128(1)).await.unwrap().passkey_handle;
+        assert_eq!(handle, Some(Uuid::from_u128(1)));
+        let again = User::claim_passkey_handle(&pool, testy.id, Uuid::from_u128(2)).await.unwrap().passkey_handle;
+        assert_eq!(again, handle, "the handle is kept once given");
+
+        PasskeyChallenge::add(&pool, "chal".to_string(), testy.id, true, serde_json::json!({})).await.unwrap();
+        assert!(PasskeyChallenge::take(&pool, "chal".to_string(), testy.id, false, now - chrono::Duration::minutes(5))
+            .await.unwrap().is_none(), "registration challenges aren't for logging in");
+        assert!(PasskeyChallenge::take(&pool, "chal".to_string(), other.id, true, now - chrono::Duration::minutes(5))
+            .await.unwrap().is_none(), "challenges belong to one user");
+        assert!(PasskeyChallenge::take(&pool, "chal".to_string(), testy.id, true, now - chrono::Duration::minutes(5))
+            .await.unwrap().is_some());
+        assert!(PasskeyChallenge::take(&pool, "chal".to_string(), testy.id, true, now - chrono::Duration::minutes(5))
+            .await.unwrap().is_none(), "challenges are answered once");
+
+        let passkey = Passkey::add(&pool, testy.id, vec![1, 2, 3], "phone".to_string(), serde_json::json!({"counter": 0})).await.unwrap();
+        assert!(Passkey::add(&pool, other.id, vec![1, 2, 3], "stolen".to_string(), serde_json::json!({})).await.is_err(),
+            "a credential belongs to one account");
+        Passkey::record_use(&pool, passkey.id, serde_json::json!({"counter": 1}), now).await.unwrap();
+        let listed = Passkey::get_for_user(&pool, testy.id).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].passkey, serde_json::json!({"counter": 1}));
+
+        assert!(Passkey::remove(&pool, passkey.id, other.id).await.unwrap().is_none());
+        assert!(Passkey::remove(&pool, passkey.id, testy.id).await.unwrap().is_some());
+        assert!(Passkey::get_for_user(&pool, testy.id).await.unwrap().is_empty());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_audit_events(pool: Pool<Postgres>) {
+        let email = "test@mctesterson.net".to_string();
+        let addr: SocketAddr = "127.0.0.1:3000".parse().unwrap();
+        AuditEvent::new(email.clone(), AuditAction::Login, AuditOutcome::Failure).client_ip(addr).detail("password")
+            .record(&pool).await.unwrap();
+        AuditEvent::new(email.clone(), AuditAction::Login, AuditOutcome::Success).actor(email.clone()).client_ip(addr)
+            .record(&pool).await.unwrap();
+        AuditEvent::new("other@mctesterson.net".to_string(), AuditAction::PasswordResetSent, AuditOutcome::Success)
+            .record(&pool).await.unwrap();
+
+        let own = AuditEvent::get_for_email(&pool, email.clone(), 10).await.unwrap();
+        assert_eq!(own.iter().map(|ev| ev.outcome.as_str()).collect::<Vec<_>>(), vec!["success", "failure"], "newest first");
+        assert_eq!(own[1].client_ip.as_deref(), Some("127.0.0.1"));
+        assert_eq!(AuditEvent::get_for_email(&pool, email.clone(), 1).await.unwrap().len(), 1);
+
+        let logins = AuditEvent::search(&pool, None, Some("login".to_string()), None, 10).await.unwrap();
+        assert_eq!(logins.len(), 2);
+        let all = AuditEvent::search(&pool, None, None, None, 10).await.unwrap();
+        assert_eq!(all.len(), 3);
+        let later = Utc::now().naive_utc() + chrono::Duration::minutes(1);
+        assert!(AuditEvent::search(&pool, None, None, Some(later), 10).await.unwrap().is_empty());
+
+        assert!(sqlx::query!("update audit_events set outcome = 'success'").execute(&pool).await.is_err(),
+            "entries can't be rewritten");
+
+        AuditEvent::rename_email(&pool, email.clone(), "new@mctesterson.net".to_string()).await.unwrap();
+        assert!(AuditEvent::get_for_email(&pool, email.clone(), 10).await.unwrap().is_empty());
+        let moved = AuditEvent::get_for_email(&pool, "new@mctesterson.net".to_string(), 10).await.unwrap();
+        assert_eq!(moved.len(), 2, "the history follows the account");
+        assert_eq!(moved[0].actor.as_deref(), Some("new@mctesterson.net"));
+
+        AuditEvent::prune(&pool, later).await.unwrap();
+        assert!(AuditEvent::search(&pool, None, None, None, 10).await.unwrap().is_empty());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_totp_enrollment(pool: Pool<Postgres>) {
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap().expect("a new user");
+        let now = Utc::now().naive_utc();
+
+        User::set_totp_secret(&pool, testy.email.clone(), vec![1, 2, 3]).await.unwrap();
+        let confirmed = User::confirm_totp(&pool, testy.email.clone(), 100, now).await.unwrap();
+        assert_eq!(confirmed.totp_secret, Some(vec![1, 2, 3]));
+        assert!(User::set_totp_secret(&pool, testy.email.clone(), vec![4, 5, 6]).await.is_err(),
+            "a confirmed secret shouldn't be replaced");
+
+        assert!(!User::use_totp_step(&pool, testy.email.clone(), 100).await.unwrap());
+        assert!(User::use_totp_step(&pool, testy.email.clone(), 101).await.unwrap());
+
+        RecoveryCode::replace_for_user(&pool, testy.id, vec!["a".into(), "b".into()]).await.unwrap();
+        assert!(RecoveryCode::consume(&pool, testy.id, "a".into(), now).await.unwrap().is_some());
+        assert!(RecoveryCode::consume(&pool, testy.id, "a".into(), now).await.unwrap().is_none());
+
+        RecoveryCode::clear_for_user(&pool, testy.id).await.unwrap();
+        assert!(RecoveryCode::consume(&pool, testy.id, "b".into(), now).await.unwrap().is_none());
+        let cleared = User::clear_totp(&pool, testy.email.clone()).await.unwrap();
+        assert!(cleared.totp_secret.is_none() && cleared.totp_confirmed_at.is_none());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_consume_one_time_token(pool: Pool<Postgres>) {
+        let expires = SystemTime::now() + std::time::Duration::from_secs(60);
+        let now = Utc::now().naive_utc();
+        Revocation::add_batch(&pool, vec!["once".to_string()], "test@mctesterson.net".into(), expires, None, None).await.unwrap();
+
+        let consumed = Revocation::consume(&pool, vec!["once".to_string()], now).await.unwrap();
+        assert_eq!(consumed.len(), 1);
+        let consumed = Revocation::consume(&pool, vec!["once".to_string()], now).await.unwrap();
+        assert!(consumed.is_empty(), "a token should only be consumed once");
+        let consumed = Revocation::consume(&pool, vec!["unknown".to_string()], now).await.unwrap();
+        assert!(consumed.is_empty(), "unrecorded tokens can't be consumed");
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_event_organizers(pool: Pool<Postgres>) {
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        let two = User::create(&pool, "two@example.com", "User Two", "two").await.unwrap().expect("a new user");
+        let event_id = Event{
+            id: NoId,
+            name: Some("event".into()),
+            ..Event::default()
+        }.add_new(&pool, one.email.clone()).await.unwrap();
+
+        let event = Event::get_by_id(&pool, event_id).await.unwrap().unwrap();
+        assert_eq!(event.creator_id, Some(one.id.into()));
+
+        let organizers = User::get_organizers_by_event_id(&pool, event_id).await.unwrap();
+        assert_eq!(organizers.iter().map(|u| u.id).collect::<Vec<_>>(), vec![one.id]);
+
+        Event::add_organizer(&pool, event_id, two.email.clone()).await.unwrap();
+        Event::add_organizer(&pool, event_id, two.email.clone()).await.unwrap();
+        let organizers = User::get_organizers_by_event_id(&pool, event_id).await.unwrap();
+        assert_eq!(organizers.len(), 2, "adding an organizer twice should be harmless");
+
+        Event::remove_organizer(&pool, event_id, one.email.clone()).await.unwrap();
+        let organizers = User::get_organizers_by_event_id(&pool, event_id).await.unwrap();
+        assert_eq!(organizers.iter().map(|u| u.id).collect::<Vec<_>>(), vec![two.id]);
+
+        Event::add_organizer(&pool, event_id, one.email.clone()).await.unwrap();
+        let mut first = pool.begin().await.unwrap();
+        Event::lock_organizers(&mut *first, event_id).await.unwrap();
+        let pool_two = pool.clone();
+        let second = tokio::spawn(async move {
+            let mut second = pool_two.begin().await.unwrap();
+            Event::lock_organizers(&mut *second, event_id).await.unwrap();
+            User::get_organizers_by_event_id(&mut *second, event_id).await.unwrap().len()
+        });
+        Event::remove_organizer(&mut *first, event_id, one.email.clone()).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!second.is_finished(), "the second removal waits for the first");
+        first.commit().await.unwrap();
+        assert_eq!(second.await.unwrap(), 1, "and then sees the first's removal");
     }
 
     #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
     async fn test_join_in_on_a_game(pool: Pool<Postgres>) {
-        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap();
-        let two = User::create(&pool, "two@example.com", "User Two", "two").await.unwrap();
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        let two = User::create(&pool, "two@example.com", "User Two", "two").await.unwrap().expect("a new user");
         let event_id = Event{
             id: NoId,
             name: Some("event".into()),
@@ -665,7 +2400,7 @@ mod tests {
             r#where: Some("location".into()),
             description: Some("its great".into()),
             ..Event::default()
-        }.add_new(&pool).await.unwrap();
+        }.add_new(&pool, one.email.clone()).await.unwrap();
 
         let default_game = Game::<NoId, NoId, NoId, Omit>::default();
 
@@ -711,4 +2446,278 @@ mod tests {
         let games = Game::get_all_for_event_and_user(&pool, event_id, two.email.clone()).await.unwrap();
         assert_eq!(dbg!(games).len(), 1, "With no interest, game two should have been removed");
     }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_delete_account(pool: Pool<Postgres>) {
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        let two = User::create(&pool, "two@example.com", "User Two", "two").await.unwrap().expect("a new user");
+        let event_id = Event{
+            id: NoId,
+            name: Some("event".into()),
+            ..Event::default()
+        }.add_new(&pool, one.email.clone()).await.unwrap();
+        Event::add_organizer(&pool, event_id, two.email.clone()).await.unwrap();
+
+        let game = Game {
+            data: GameData{ name: Some("game".into()), ..GameData::default() },
+            ..Game::<NoId, NoId, NoId, Omit>::default()
+        }.with_event_id(event_id);
+        let game_id = game.add_new(&pool, one.email.clone()).await.unwrap();
+        let game = game.with_id(game_id).with_interest_data(InterestData {
+            interested: Some(true),
+            can_teach: Some(true),
+            notes: Some("I know it well".into())
+        });
+        game.update_interests(&pool, one.email.clone()).await.unwrap();
+        game.update_interests(&pool, two.email.clone()).await.unwrap();
+
+        let expires = SystemTime::now() + std::time::Duration::from_secs(60);
+        let now = Utc::now().naive_utc();
+        Revocation::add_batch(&pool, vec!["session".to_string()], one.email.clone(), expires, Some("Testy/1.0".into()), None).await.unwrap();
+
+        assert_eq!(Game::get_suggested_by(&pool, one.id).await.unwrap().len(), 1);
+        let interests = Interest::get_for_user(&pool, one.id).await.unwrap();
+        assert_eq!(interests.iter().map(|i| i.notes.as_deref()).collect::<Vec<_>>(), vec![Some("I know it well")]);
+
+        let mut tx = pool.begin().await.unwrap();
+        Interest::remove_for_user(&mut *tx, one.id).await.unwrap();
+        Game::anonymize_for_user(&mut *tx, one.id).await.unwrap();
+        let scrubbed = Revocation::scrub_for_username(&mut *tx, one.email.clone(), now).await.unwrap();
+        User::delete(&mut *tx, one.id).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(User::by_email(&pool, one.email.clone()).await.is_err());
+        assert_eq!(scrubbed.len(), 1);
+        assert!(scrubbed[0].revoked.is_some() && scrubbed[0].username.is_empty() && scrubbed[0].clienthint.is_none());
+        assert!(Revocation::get_revoked(&pool, now).await.unwrap().iter().any(|rev| rev.data == "session"),
+            "the old session stays revoked");
+
+        let games = Game::get_all_for_event_and_user(&pool, event_id, two.email.clone()).await.unwrap();
+        assert_eq!(games.len(), 1, "the game stays with its event");
+        assert_eq!(games[0].suggestor_id, None);
+        assert_eq!(games[0].extra.recco.interest_level, 1, "only the other user's interest is left");
+        assert_eq!(Event::get_by_id(&pool, event_id).await.unwrap().unwrap().creator_id, None);
+        let organizers = User::get_organizers_by_event_id(&pool, event_id).await.unwrap();
+        assert_eq!(organizers.iter().map(|u| u.id).collect::<Vec<_>>(), vec![two.id]);
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_email_change(pool: Pool<Postgres>) {
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        User::create(&pool, "two@example.com", "User Two", "two").await.unwrap().expect("a new user");
+        let now = Utc::now().naive_utc();
+
+        EmailChange::request(&pool, one.id, "elsewhere@example.com".into()).await.unwrap();
+        EmailChange::request(&pool, one.id, "new@example.com".into()).await.unwrap();
+        assert!(EmailChange::confirm(&pool, one.id, "elsewhere@example.com".into(), EmailChangeSide::Old, now).await.unwrap().is_none(),
+            "a replaced change can't be confirmed");
+
+        let change = EmailChange::confirm(&pool, one.id, "new@example.com".into(), EmailChangeSide::New, now).await.unwrap()
+            .expect("a pending change");
+        assert!(!change.is_confirmed());
+        let change = EmailChange::confirm(&pool, one.id, "new@example.com".into(), EmailChangeSide::Old, now).await.unwrap()
+            .expect("a pending change");
+        assert!(change.is_confirmed());
+
+        assert!(User::email_taken(&pool, "two@example.com".into()).await.unwrap());
+        assert!(!User::email_taken(&pool, "new@example.com".into()).await.unwrap());
+        assert!(User::change_email(&pool, one.id, "two@example.com".into()).await.is_err());
+
+        let expires = SystemTime::now() + std::time::Duration::from_secs(60);
+        Revocation::add_batch(&pool, vec!["session".to_string()], one.email.clone(), expires, None, None).await.unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        User::change_email(&mut *tx, one.id, "new@example.com".into()).await.unwrap();
+        Revocation::rename_username(&mut *tx, one.email.clone(), "new@example.com".into()).await.unwrap();
+        let revoked = Revocation::revoke_for_username(&mut *tx, "new@example.com".into(), now).await.unwrap();
+        EmailChange::remove(&mut *tx, one.id).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(revoked.iter().map(|rev| rev.data.as_str()).collect::<Vec<_>>(), vec!["session"]);
+        assert_eq!(User::by_email(&pool, "new@example.com".into()).await.unwrap().id, one.id);
+        assert!(User::by_email(&pool, one.email.clone()).await.is_err());
+        assert!(EmailChange::for_user(&pool, one.id).await.unwrap().is_none());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_archive_and_delete_event(pool: Pool<Postgres>) {
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        let old_id = Event{ id: NoId, name: Some("old".into()), ..Event::default() }.add_new(&pool, one.email.clone()).await.unwrap();
+        let new_id = Event{ id: NoId, name: Some("new".into()), ..Event::default() }.add_new(&pool, one.email.clone()).await.unwrap();
+
+        let now = Utc::now().naive_utc();
+        let archived = Event::set_archived(&pool, old_id, Some(now)).await.unwrap();
+        assert!(archived.archived_at.is_some());
+        let listed = |include_archived| {
+            let pool = pool.clone();
+            async move {
+                let filter = EventFilter{ include_archived, ..EventFilter::default() };
+                Event::search(&pool, filter, None, 0).await.unwrap().into_iter().map(|ev| ev.id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(listed(false).await, vec![new_id]);
+        assert_eq!(listed(true).await, vec![old_id, new_id]);
+        assert!(Event::get_by_id(&pool, old_id).await.unwrap().is_some(), "archived events stay readable");
+        Event::set_archived(&pool, old_id, None).await.unwrap();
+        assert_eq!(listed(false).await, vec![old_id, new_id]);
+
+        let game = Game {
+            data: GameData{ name: Some("game".into()), ..GameData::default() },
+            ..Game::<NoId, NoId, NoId, Omit>::default()
+        }.with_event_id(old_id);
+        let game_id = game.add_new(&pool, one.email.clone()).await.unwrap();
+        game.with_id(game_id).with_interest_data(InterestData {
+            interested: Some(true),
+            ..InterestData::default()
+        }).update_interests(&pool, one.email.clone()).await.unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        Interest::remove_for_event(&mut *tx, old_id).await.unwrap();
+        Game::remove_for_event(&mut *tx, old_id).await.unwrap();
+        Event::delete(&mut *tx, old_id).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert!(Event::get_by_id(&pool, old_id).await.unwrap().is_none());
+        assert!(Interest::get_for_user(&pool, one.id).await.unwrap().is_empty());
+        assert!(Game::get_suggested_by(&pool, one.id).await.unwrap().is_empty());
+        assert!(User::get_organizers_by_event_id(&pool, old_id).await.unwrap().is_empty());
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_events_for_attendee(pool: Pool<Postgres>) {
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        let two = User::create(&pool, "two@example.com", "User Two", "two").await.unwrap().expect("a new user");
+        let day = |d| NaiveDate::from_ymd_opt(2026, 10, d).unwrap().and_hms_opt(18, 0, 0).unwrap();
+        let later_id = Event{ id: NoId, name: Some("later".into()), date: Some(day(20)), ..Event::default() }.add_new(&pool, one.email.clone()).await.unwrap();
+        let sooner_id = Event{ id: NoId, name: Some("sooner".into()), date: Some(day(10)), ..Event::default() }.add_new(&pool, one.email.clone()).await.unwrap();
+        let other_id = Event{ id: NoId, name: Some("other".into()), date: Some(day(15)), ..Event::default() }.add_new(&pool, one.email.clone()).await.unwrap();
+
+        for (event_id, email) in [(later_id, &one.email), (sooner_id, &one.email), (sooner_id, &two.email), (other_id, &two.email)] {
+            let game = Game {
+                data: GameData{ name: Some("game".into()), ..GameData::default() },
+                ..Game::<NoId, NoId, NoId, Omit>::default()
+            }.with_event_id(event_id);
+            let game_id = game.add_new(&pool, email.clone()).await.unwrap();
+            game.with_id(game_id).with_interest_data(InterestData {
+                interested: Some(true),
+                ..InterestData::default()
+            }).update_interests(&pool, email.clone()).await.unwrap();
+        }
+
+        let attending = |email: &String| {
+            let (pool, email) = (pool.clone(), email.clone());
+            async move {
+                Event::get_for_attendee(&pool, email).await.unwrap().into_iter().map(|ev| ev.id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(attending(&one.email).await, vec![sooner_id, later_id]);
+        assert_eq!(attending(&two.email).await, vec![sooner_id, other_id]);
+
+        Rsvp::set(&pool, other_id, one.email.clone(), RsvpStatus::Maybe, None, None).await.unwrap();
+        Rsvp::set(&pool, later_id, one.email.clone(), RsvpStatus::NotGoing, None, None).await.unwrap();
+        assert_eq!(attending(&one.email).await, vec![sooner_id, other_id], "RSVPs count, and not going beats interest");
+
+        Event::set_archived(&pool, sooner_id, Some(Utc::now().naive_utc())).await.unwrap();
+        assert_eq!(attending(&one.email).await, vec![other_id], "archived events drop off the feed");
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_rsvps(pool: Pool<Postgres>) {
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        let two = User::create(&pool, "two@example.com", "User Two", "two").await.unwrap().expect("a new user");
+        let three = User::create(&pool, "three@example.com", "User Three", "three").await.unwrap().expect("a new user");
+        let event_id = Event{ id: NoId, name: Some("con".into()), ..Event::default() }.add_new(&pool, one.email.clone()).await.unwrap();
+
+        let arrives = NaiveDate::from_ymd_opt(2026, 10, 16).unwrap().and_hms_opt(18, 0, 0).unwrap();
+        Rsvp::set(&pool, event_id, one.email.clone(), RsvpStatus::Going, Some(arrives), None).await.unwrap();
+        Rsvp::set(&pool, event_id, two.email.clone(), RsvpStatus::NotGoing, None, None).await.unwrap();
+        Rsvp::set(&pool, event_id, three.email.clone(), RsvpStatus::Going, None, None).await.unwrap();
+        Rsvp::set(&pool, event_id, three.email.clone(), RsvpStatus::Maybe, None, Some(arrives)).await.unwrap();
+
+        let answers: Vec<_> = Rsvp::get_for_event(&pool, event_id).await.unwrap().into_iter()
+            .map(|rsvp| (rsvp.email, rsvp.status)).collect();
+        assert_eq!(answers, vec![
+            (one.email.clone(), RsvpStatus::Going),
+            (three.email.clone(), RsvpStatus::Maybe),
+            (two.email.clone(), RsvpStatus::NotGoing),
+        ]);
+        let mine = Rsvp::get(&pool, event_id, one.email.clone()).await.unwrap().expect("an RSVP");
+        assert_eq!(mine.arrives_at, Some(arrives));
+        assert_eq!(Rsvp::get_for_user(&pool, three.id).await.unwrap()[0].departs_at, Some(arrives), "answers are replaced");
+
+        let users: Vec<_> = User::get_all_by_event_id(&pool, event_id).await.unwrap().into_iter().map(|user| user.email).collect();
+        assert_eq!(users, vec![one.email.clone(), three.email.clone()], "attendees come from RSVPs, without those not going");
+
+        let four = User::create(&pool, "four@example.com", "User Four", "four").await.unwrap().expect("a new user");
+        let game = Game {
+            data: GameData{ name: Some("game".into()), ..GameData::default() },
+            ..Game::<NoId, NoId, NoId, Omit>::default()
+        }.with_event_id(event_id);
+        let game_id = game.add_new(&pool, four.email.clone()).await.unwrap();
+        assert_eq!(Game::event_of(&pool, game_id).await.unwrap(), Some(event_id));
+        let interested = game.with_id(game_id).with_interest_data(InterestData {
+            interested: Some(true),
+            ..InterestData::default()
+        });
+        interested.update_interests(&pool, four.email.clone()).await.unwrap();
+        interested.update_interests(&pool, two.email.clone()).await.unwrap();
+        let users: Vec<_> = User::get_all_by_event_id(&pool, event_id).await.unwrap().into_iter().map(|user| user.email).collect();
+        assert_eq!(users, vec![one.email.clone(), three.email.clone(), four.email.clone()],
+            "interest alone says you're going, but doesn't change an answer");
+
+        assert!(Rsvp::set(&pool, event_id, two.email.clone(), RsvpStatus::Going, Some(arrives), Some(arrives - chrono::Duration::hours(1))).await.is_err(),
+            "can't leave before arriving");
+        assert!(Rsvp::remove(&pool, event_id, two.email.clone()).await.unwrap());
+        assert!(!Rsvp::remove(&pool, event_id, two.email.clone()).await.unwrap());
+
+        User::delete(&pool, three.id).await.unwrap();
+        Interest::remove_for_event(&pool, event_id).await.unwrap();
+        Game::remove_for_event(&pool, event_id).await.unwrap();
+        Event::delete(&pool, event_id).await.unwrap();
+        assert!(Rsvp::get_for_user(&pool, one.id).await.unwrap().is_empty(), "RSVPs go with their event");
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_search_events(pool: Pool<Postgres>) {
+        let one = User::create(&pool, "one@example.com", "User One", "one").await.unwrap().expect("a new user");
+        let day = |d| NaiveDate::from_ymd_opt(2026, 10, d).unwrap().and_hms_opt(18, 0, 0).unwrap();
+        let add = |name: &'static str, place: &'static str, date| {
+            let (pool, email) = (pool.clone(), one.email.clone());
+            async move {
+                Event{ id: NoId, name: Some(name.into()), r#where: Some(place.into()), date: Some(date), ..Event::default() }
+                    .add_new(&pool, email).await.unwrap()
+            }
+        };
+        let games_night = add("Games Night", "The Pub", day(3)).await;
+        let con = add("Big Con", "Convention Centre", day(10)).await;
+        let percent = add("100% Euro", "Home", day(20)).await;
+
+        let search = |filter, limit, offset| {
+            let pool = pool.clone();
+            async move { Event::search(&pool, filter, limit, offset).await.unwrap().into_iter().map(|ev| ev.id).collect::<Vec<_>>() }
+        };
+        assert_eq!(search(EventFilter::default(), None, 0).await, vec![games_night, con, percent]);
+        assert_eq!(search(EventFilter{ descending: true, ..EventFilter::default() }, None, 0).await, vec![percent, con, games_night]);
+        assert_eq!(search(EventFilter{ sort: EventSort::Name, ..EventFilter::default() }, None, 0).await, vec![percent, con, games_night]);
+        assert_eq!(search(EventFilter{ sort: EventSort::Loc, ..EventFilter::default() }, None, 0).await, vec![con, percent, games_night]);
+
+        assert_eq!(search(EventFilter{ after: Some(day(5)), ..EventFilter::default() }, None, 0).await, vec![con, percent]);
+        assert_eq!(search(EventFilter{ after: Some(day(5)), before: Some(day(15)), ..EventFilter::default() }, None, 0).await, vec![con]);
+
+        assert_eq!(search(EventFilter{ text: Some("pub".into()), ..EventFilter::default() }, None, 0).await, vec![games_night]);
+        assert_eq!(search(EventFilter{ text: Some("%".into()), ..EventFilter::default() }, None, 0).await, vec![percent],
+            "wildcards are matched literally");
+
+        assert_eq!(search(EventFilter::default(), Some(2), 0).await, vec![games_night, con]);
+        assert_eq!(search(EventFilter::default(), Some(2), 2).await, vec![percent]);
+
+        let weekend = Event{ id: NoId, name: Some("Weekend".into()), date: Some(day(8)), ends_at: Some(day(11)), time_zone: "Europe/Berlin".into(), ..Event::default() }
+            .add_new(&pool, one.email.clone()).await.unwrap();
+        assert_eq!(search(EventFilter{ after: Some(day(10)), ..EventFilter::default() }, None, 0).await, vec![weekend, con, percent],
+            "an event that's still on counts as upcoming");
+        let stored = Event::get_by_id(&pool, weekend).await.unwrap().expect("the event");
+        assert_eq!((stored.ends_at, stored.time_zone.as_str()), (Some(day(11)), "Europe/Berlin"));
+        assert!(Event{ id: NoId, date: Some(day(11)), ends_at: Some(day(8)), ..Event::default() }
+            .add_new(&pool, one.email.clone()).await.is_err(), "events can't end before they start");
+    }
 }
@@ -0,0 +1,131 @@
+use axum::response::{IntoResponse, Response};
+use chrono::NaiveDateTime;
+use hyper::{header, HeaderMap};
+
+use crate::db::{Event, EventId};
+
+pub(crate) const CONTENT_TYPE: &str = "text/calendar; charset=utf-8";
+
+// RFC 5545 wants lines no longer than this, in octets, before the CRLF
+const LINE_LIMIT: usize = 75;
+
+/// Whether the client asked for iCalendar rather than JSON
+pub(crate) fn wants_calendar(headers: &HeaderMap) -> bool {
+    headers.get_all(header::ACCEPT).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.split(';').next().is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/calendar")))
+}
+
+/// A VCALENDAR of the events, as a response. Events without a start can't go on a calendar, so they're left out.
+/// Times are written in UTC, which every calendar app turns into its own zone.
+pub(crate) fn respond(name: &str, events: &[Event<EventId>]) -> Response {
+    ([(header::CONTENT_TYPE, CONTENT_TYPE)], calendar(name, events)).into_response()
+}
+
+pub(crate) fn calendar(name: &str, events: &[Event<EventId>]) -> String {
+    let mut out = String::new();
+    line(&mut out, "BEGIN:VCALENDAR");
+    line(&mut out, "VERSION:2.0");
+    line(&mut out, "PRODID:-//Wag the Pig//Events//EN");
+    line(&mut out, "CALSCALE:GREGORIAN");
+    line(&mut out, &format!("X-WR-CALNAME:{}", text(name)));
+    for event in events {
+        let Some(start) = event.date else { continue };
+        line(&mut out, "BEGIN:VEVENT");
+        line(&mut out, &format!("UID:event-{}@wagthepig", event.id));
+        line(&mut out, &format!("DTSTAMP:{}", utc(event.updated_at)));
+        line(&mut out, &format!("DTSTART:{}", utc(start)));
+        if let Some(end) = event.ends_at {
+            line(&mut out, &format!("DTEND:{}", utc(end)));
+        }
+        if let Some(name) = &event.name {
+            line(&mut out, &format!("SUMMARY:{}", text(name)));
+        }
+        if let Some(place) = &event.r#where {
+            line(&mut out, &format!("LOCATION:{}", text(place)));
+        }
+        if let Some(description) = &event.description {
+            line(&mut out, &format!("DESCRIPTION:{}", text(description)));
+        }
+        line(&mut out, "END:VEVENT");
+    }
+    line(&mut out, "END:VCALENDAR");
+    out
+}
+
+fn utc(time: NaiveDateTime) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapes a TEXT value
+fn text(value: &str) -> String {
+    value.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Folds the content line onto continuation lines, without splitting a character
+fn line(out: &mut String, content: &str) {
+    let mut width = 0;
+    for ch in content.chars() {
+        if width + ch.len_utf8() > LINE_LIMIT {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(ch);
+        width += ch.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use hyper::header::HeaderValue;
+
+    use crate::db::NoId;
+
+    use super::*;
+
+    #[test]
+    fn writes_events_as_vevents() {
+        let start = NaiveDate::from_ymd_opt(2026, 10, 16).unwrap().and_hms_opt(14, 0, 0).unwrap();
+        let event = Event{
+            name: Some("Big Con, 2026".into()),
+            date: Some(start),
+            ends_at: Some(start + chrono::Duration::days(2)),
+            r#where: Some("Hall; upstairs".into()),
+            description: Some(format!("Bring {}\nand snacks", "games ".repeat(20))),
+            ..Event::<NoId>::default()
+        }.with_id(EventId::from(7));
+        let undated = Event::<NoId>{ name: Some("Someday".into()), ..Event::default() }.with_id(EventId::from(8));
+
+        let cal = calendar("Wag the Pig", &[event, undated]);
+        assert!(cal.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(cal.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(cal.matches("BEGIN:VEVENT").count(), 1, "events without a start are left out");
+        assert!(cal.contains("\r\nUID:event-7@wagthepig\r\n"));
+        assert!(cal.contains("\r\nDTSTART:20261016T140000Z\r\nDTEND:20261018T140000Z\r\n"));
+        assert!(cal.contains("\r\nSUMMARY:Big Con\\, 2026\r\n"));
+        assert!(cal.contains("\r\nLOCATION:Hall\\; upstairs\r\n"));
+        assert!(cal.contains("\r\nDESCRIPTION:Bring games "));
+        assert!(cal.split("\r\n").all(|line| line.len() <= LINE_LIMIT), "long lines are folded");
+        assert!(cal.replace("\r\n ", "").contains("games \\nand snacks"));
+    }
+
+    #[test]
+    fn negotiates_on_accept() {
+        let accepting = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+            wants_calendar(&headers)
+        };
+        assert!(accepting("text/calendar"));
+        assert!(accepting("application/json;q=0.5, text/calendar;q=0.9"));
+        assert!(!accepting("application/json"));
+        assert!(!wants_calendar(&HeaderMap::new()));
+    }
+}
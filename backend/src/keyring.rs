@@ -0,0 +1,294 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::Path,
+    sync::Arc
+};
+
+use axum::extract::Request;
+use biscuit_auth::{error, Algorithm, BiscuitBuilder, BiscuitWebKey, Biscuit, KeyPair, PrivateKey, PublicKey, RootKeyProvider};
+use chrono::{DateTime, Utc};
+use mattak::biscuits::middleware::setup::{GetPublic, GetPublicSource};
+use serde::{Deserialize, Serialize};
+
+/// Tokens from before keys had ids were all signed by the one key there was,
+/// which `Authentication` published as key 1
+const LEGACY_KEY_ID: u32 = 1;
+
+/// API tokens, share links and calendar feeds last up to this long, longer than anything else we sign;
+/// their lifetimes are derived from it. A replaced key has to keep verifying for that long.
+pub(crate) const LONGEST_TOKEN_LIFETIME: chrono::Duration = chrono::Duration::days(366);
+
+/// The keys that sign and verify our tokens.
+/// One key signs new tokens; the keys it replaced only verify, until they retire.
+#[derive(Clone)]
+pub(crate) struct KeyRing {
+    active: u32,
+    keys: Arc<Vec<RingKey>>,
+}
+
+#[derive(Clone)]
+struct RingKey {
+    id: u32,
+    private_key: PrivateKey,
+    retires_at: Option<DateTime<Utc>>,
+}
+
+/// The key ring as persisted at `AUTH_KEYPAIR`
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct StoredRing {
+    active: u32,
+    keys: Vec<StoredKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all="camelCase")]
+struct StoredKey {
+    id: u32,
+    /// Hex, as `Authentication` stored it
+    private_key: String,
+    created_at: DateTime<Utc>,
+    retires_at: Option<DateTime<Utc>>,
+}
+
+/// What a rotation did, for the operator's benefit
+#[derive(Debug)]
+pub(crate) struct Rotation {
+    pub active: u32,
+    pub retiring: Vec<(u32, DateTime<Utc>)>,
+    pub removed: Vec<u32>,
+}
+
+impl KeyRing {
+    /// Loads the ring from `persist_path`, creating it with one key if it doesn't exist yet.
+    /// Keys past their retirement are left out.
+    pub(crate) fn load<P: AsRef<Path>>(persist_path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let stored = match StoredRing::read(persist_path.as_ref()) {
+            Ok(stored) => stored,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let stored = StoredRing::new(Utc::now());
+                stored.write(persist_path.as_ref())?;
+                stored
+            }
+            Err(e) => Err(e)?,
+        };
+        stored.into_ring(Utc::now())
+    }
+
+    /// Rotates the ring at `persist_path`, which the server must have created already.
+    /// A running server only picks up the new key when it restarts.
+    pub(crate) fn rotate<P: AsRef<Path>>(persist_path: P) -> Result<Rotation, Box<dyn std::error::Error>> {
+        let mut stored = match StoredRing::read(persist_path.as_ref()) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound =>
+                return Err(format!("no key ring at {}; the server creates one when it first starts", persist_path.as_ref().display()).into()),
+            res => res?,
+        };
+        let rotation = stored.rotate(Utc::now());
+        stored.write(persist_path.as_ref())?;
+        Ok(rotation)
+    }
+
+    /// A ring of one fresh key, kept only in memory
+    #[cfg(test)]
+    pub(crate) fn generate() -> Self {
+        StoredRing::new(Utc::now()).into_ring(Utc::now()).expect("a fresh ring is whole")
+    }
+
+    /// Signs `builder` with the active key, naming it so that verifiers can pick it out of the ring
+    pub(crate) fn sign(&self, builder: BiscuitBuilder) -> Result<Biscuit, error::Token> {
+        let key = self.keys.iter()
+            .find(|key| key.id == self.active)
+            .expect("the active key is checked on load");
+        builder.root_key_id(key.id).build(&KeyPair::from(&key.private_key))
+    }
+
+    /// Every key still good for verifying, for `/.well-known/biscuit-web-keys`
+    pub(crate) fn web_keys(&self) -> Vec<BiscuitWebKey> {
+        self.keys.iter().map(|key| BiscuitWebKey{
+            public_key: key.private_key.public(),
+            key_id: key.id,
+            issuer: None,
+            expires_at: key.retires_at.map(Into::into),
+        }).collect()
+    }
+}
+
+impl RootKeyProvider for KeyRing {
+    fn choose(&self, key_id: Option<u32>) -> Result<PublicKey, error::Format> {
+        let key_id = key_id.unwrap_or(LEGACY_KEY_ID);
+        self.keys.iter()
+            .find(|key| key.id == key_id)
+            .map(|key| key.private_key.public())
+            .ok_or(error::Format::UnknownPublicKey)
+    }
+}
+
+impl GetPublic for KeyRing {
+    type PublicKey = KeyRing;
+    fn get_public(&self, _: &Request) -> Result<Self::PublicKey, mattak::biscuits::Error> {
+        Ok(self.clone())
+    }
+}
+
+impl GetPublicSource for KeyRing {
+    type Public = Self;
+    fn make_get_public(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl StoredRing {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self{
+            active: LEGACY_KEY_ID,
+            keys: vec![StoredKey::generate(LEGACY_KEY_ID, now)],
+        }
+    }
+
+    /// Reads the ring, or the single hex key that `Authentication` kept in the same place
+    fn read(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        if let Ok(private_key) = PrivateKey::from_bytes_hex(contents.trim(), Algorithm::Ed25519) {
+            return Ok(Self{
+                active: LEGACY_KEY_ID,
+                keys: vec![StoredKey{
+                    id: LEGACY_KEY_ID,
+                    private_key: private_key.to_bytes_hex(),
+                    created_at: fs::metadata(path)?.modified()?.into(),
+                    retires_at: None,
+                }],
+            })
+        }
+        serde_json::from_str(&contents).map_err(io::Error::from)
+    }
+
+    /// Writes through a temporary file, so that a running server never reads half a ring
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let tmp = path.with_extension("new");
+        let mut f = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+        f.write_all(json.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Adds a new active key. The keys it replaces retire once every token they signed has expired,
+    /// and keys already past retirement are dropped.
+    fn rotate(&mut self, now: DateTime<Utc>) -> Rotation {
+        let retire_at = now + LONGEST_TOKEN_LIFETIME;
+        let mut retiring = vec![];
+        for key in self.keys.iter_mut().filter(|key| key.retires_at.is_none()) {
+            key.retires_at = Some(retire_at);
+            retiring.push((key.id, retire_at));
+        }
+
+        let mut removed = vec![];
+        self.keys.retain(|key| {
+            let keep = key.retires_at.is_none_or(|at| at > now);
+            if !keep {
+                removed.push(key.id);
+            }
+            keep
+        });
+
+        let id = self.keys.iter().map(|key| key.id).max().unwrap_or(0) + 1;
+        self.keys.push(StoredKey::generate(id, now));
+        self.active = id;
+        Rotation{ active: id, retiring, removed }
+    }
+
+    fn into_ring(self, now: DateTime<Utc>) -> Result<KeyRing, Box<dyn std::error::Error>> {
+        let mut keys = vec![];
+        for key in self.keys {
+            if key.retires_at.is_some_and(|at| at <= now) {
+                continue
+            }
+            keys.push(RingKey{
+                id: key.id,
+                private_key: PrivateKey::from_bytes_hex(key.private_key.trim(), Algorithm::Ed25519)?,
+                retires_at: key.retires_at,
+            });
+        }
+        if !keys.iter().any(|key| key.id == self.active) {
+            return Err(format!("active key {} isn't in the key ring", self.active).into())
+        }
+        Ok(KeyRing{ active: self.active, keys: Arc::new(keys) })
+    }
+}
+
+impl StoredKey {
+    fn generate(id: u32, now: DateTime<Utc>) -> Self {
+        Self{
+            id,
+            private_key: KeyPair::new().private().to_bytes_hex(),
+            created_at: now,
+            retires_at: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use biscuit_auth::macros::biscuit;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("wtp-{name}-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn rotated_keys_verify_until_they_retire() {
+        let path = temp_path("keyring");
+        let first = KeyRing::load(&path).expect("a new ring");
+        let old_token = first.sign(biscuit!("user(\"one@example.com\");")).expect("a token");
+        assert_eq!(old_token.root_key_id(), Some(LEGACY_KEY_ID));
+
+        let now = Utc::now();
+        let mut stored = StoredRing::read(&path).expect("the stored ring");
+        let rotation = stored.rotate(now);
+        stored.write(&path).expect("the ring to save");
+        assert_eq!(rotation.active, 2);
+        assert_eq!(rotation.retiring, vec![(1, now + LONGEST_TOKEN_LIFETIME)]);
+
+        let second = KeyRing::load(&path).expect("the rotated ring");
+        let new_token = second.sign(biscuit!("user(\"one@example.com\");")).expect("a token");
+        assert_eq!(new_token.root_key_id(), Some(2));
+        Biscuit::from_base64(old_token.to_base64().unwrap(), second.clone()).expect("old tokens still verify");
+        Biscuit::from_base64(new_token.to_base64().unwrap(), second.clone()).expect("new tokens verify");
+        assert!(Biscuit::from_base64(new_token.to_base64().unwrap(), first).is_err(),
+            "a server that hasn't reloaded doesn't know the new key");
+        assert_eq!(second.web_keys().iter().map(|key| key.key_id).collect::<Vec<_>>(), vec![1, 2]);
+
+        let later = now + LONGEST_TOKEN_LIFETIME + chrono::Duration::days(1);
+        let third = StoredRing::read(&path).expect("the stored ring").into_ring(later).expect("a ring");
+        assert!(Biscuit::from_base64(old_token.to_base64().unwrap(), third.clone()).is_err(),
+            "retired keys verify nothing");
+        assert_eq!(third.web_keys().iter().map(|key| key.key_id).collect::<Vec<_>>(), vec![2]);
+
+        let mut stored = StoredRing::read(&path).expect("the stored ring");
+        let rotation = stored.rotate(later);
+        assert_eq!(rotation.removed, vec![1]);
+        assert_eq!(rotation.retiring, vec![(2, later + LONGEST_TOKEN_LIFETIME)]);
+        assert_eq!(rotation.active, 3);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reads_a_single_legacy_key() {
+        let path = temp_path("legacy-keypair");
+        let legacy = KeyPair::new();
+        fs::write(&path, legacy.private().to_bytes_hex()).expect("the legacy key to save");
+        let ring = KeyRing::load(&path).expect("the legacy key to load");
+        let _ = fs::remove_file(&path);
+
+        // As `Authentication` built them, without a key id
+        let token = biscuit!("user(\"one@example.com\");").build(&legacy).expect("a token");
+        assert_eq!(token.root_key_id(), None);
+        Biscuit::from_base64(token.to_base64().unwrap(), ring).expect("unnamed keys are the legacy key");
+    }
+}
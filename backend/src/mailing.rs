@@ -1,5 +1,6 @@
 use std::time::{Duration, SystemTime};
 
+use chrono::{NaiveDateTime, Utc};
 use indoc::formatdoc;
 use lettre::{
     message::header::ContentType,
@@ -8,13 +9,12 @@ use lettre::{
     AsyncTransport as _, Message,
     Tokio1Executor
 };
-use mattak::biscuits;
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use sqlxmq::{job, CurrentJob, JobRegistry, JobRunnerHandle};
 use tracing::debug;
 
-use crate::db::Revocation;
+use crate::{db::{AuditAction, AuditEvent, AuditOutcome, EmailChange, EmailChangeSide, LoginFailure, OidcLogin, PasskeyChallenge, Revocation, User}, tokens::TokenIssuer};
 
 pub type Transport = AsyncSmtpTransport<Tokio1Executor>;
 
@@ -24,12 +24,17 @@ pub struct AdminEmail(pub String);
 #[derive(Clone)]
 pub struct CanonDomain(pub String);
 
+/// How long audit events are kept
+#[derive(Clone)]
+pub struct AuditRetention(pub chrono::Duration);
+
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct ResetDetails {
     pub email: String,
 }
 
+const FIFTEEN_MINUTES: Duration = Duration::from_secs(60 * 15);
 const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
 const ONE_DAY: Duration = Duration::from_secs(60 * 60 * 24);
 
@@ -38,16 +43,18 @@ pub(crate) async fn queue_listener(
     admin: String,
     canon_domain: String,
     transport: Transport,
-    auth: biscuits::Authentication
+    issuer: TokenIssuer,
+    audit_retention: AuditRetention,
 ) -> Result<JobRunnerHandle, sqlx::Error> {
-    let mut registry = JobRegistry::new(&[cleanup_revocations, request_reset, request_registration]);
+    let mut registry = JobRegistry::new(&[cleanup_revocations, request_reset, request_login_link, request_account_deletion, request_email_change, notify_email_changed, request_registration, notify_existing_account, notify_login_lock]);
     // Here is where you can configure the registry
     // registry.set_error_handler(...)
 
     registry.set_context(AdminEmail(admin));
     registry.set_context(CanonDomain(canon_domain));
     registry.set_context(transport);
-    registry.set_context(auth);
+    registry.set_context(issuer);
+    registry.set_context(audit_retention);
 
     let runner = registry
         .runner(&pool)
@@ -61,10 +68,19 @@ pub(crate) async fn queue_listener(
 }
 
 #[job(channel_name = "cleanup")]
-pub(crate) async fn cleanup_revocations( current_job: CurrentJob ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+pub(crate) async fn cleanup_revocations(
+    current_job: CurrentJob,
+    AuditRetention(keep_for): AuditRetention,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let db = current_job.pool();
     let too_old = SystemTime::now() - ONE_DAY;
     Revocation::cleanup(db, too_old).await?;
+    let now = Utc::now().naive_utc();
+    LoginFailure::cleanup(db, now - ONE_DAY, now).await?;
+    OidcLogin::cleanup(db, now - ONE_DAY).await?;
+    EmailChange::cleanup(db, now - ONE_DAY).await?;
+    PasskeyChallenge::cleanup(db, now - ONE_DAY).await?;
+    AuditEvent::prune(db, now - keep_for).await?;
     Ok(())
 }
 
@@ -74,14 +90,14 @@ pub(crate) async fn request_reset(
     transport: Transport,
     CanonDomain(domain): CanonDomain,
     AdminEmail(admin): AdminEmail,
-    auth: biscuits::Authentication,
+    issuer: TokenIssuer,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let details: ResetDetails = current_job.json()?.ok_or(crate::Error::Job("no job details".to_string()))?;
     let db = current_job.pool();
 
     let expires = SystemTime::now() + ONE_HOUR;
-    let bundle = auth.reset_password(&details.email, expires)?;
-    let _ = Revocation::add_batch(db, bundle.revocation_ids, details.email.clone(), expires).await?;
+    let bundle = issuer.reset_password(&details.email, expires)?;
+    let _ = Revocation::add_batch(db, bundle.revocation_ids, details.email.clone(), expires, None, None).await?;
 
     let noreply_domain = domain.split(":").next().unwrap_or("example.com");
 
@@ -106,6 +122,264 @@ pub(crate) async fn request_reset(
             email = details.email
         ))?;
 
+    transport.send(msg).await?;
+    AuditEvent::new(details.email.clone(), AuditAction::PasswordResetSent, AuditOutcome::Success)
+        .record_or_warn(db).await;
+
+    current_job.complete().await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct LoginLinkDetails {
+    pub email: String,
+}
+
+#[job(channel_name = "emails")]
+pub(crate) async fn request_login_link(
+    mut current_job: CurrentJob,
+    transport: Transport,
+    CanonDomain(domain): CanonDomain,
+    AdminEmail(admin): AdminEmail,
+    issuer: TokenIssuer,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let details: LoginLinkDetails = current_job.json()?.ok_or(crate::Error::Job("no job details".to_string()))?;
+    let db = current_job.pool();
+
+    // The request always looks successful, so quietly drop links for unknown or locked accounts
+    match User::by_email(db, details.email.clone()).await {
+        Ok(user) if user.locked_at.is_none() => (),
+        _ => {
+            debug!("No login link for {:?}", details.email);
+            current_job.complete().await?;
+            return Ok(())
+        }
+    }
+
+    let expires = SystemTime::now() + FIFTEEN_MINUTES;
+    let bundle = issuer.login_link(&details.email, expires)?;
+    let _ = Revocation::add_batch(db, bundle.revocation_ids, details.email.clone(), expires, None, None).await?;
+
+    let noreply_domain = domain.split(":").next().unwrap_or("example.com");
+
+    let msg = Message::builder()
+        .from(format!("Wag the Pig <noreply@{noreply_domain}>").parse()?)
+        .reply_to(admin.parse()?)
+        .to(details.email.parse()?)
+        .subject("Your Login Link")
+        .header(ContentType::TEXT_PLAIN)
+        .body(formatdoc!(r#"
+                Hey!
+
+                Someone asked for a link to log in to your account. If it wasn't you, delete this message.
+
+                Otherwise, follow this URL in the next fifteen minutes to log in:
+                https://{domain}/handle_login_link/{email}#{token}
+
+                The link only works once.
+
+                Regards,
+                Wag, the pig
+                "#,
+            token = bundle.token,
+            email = details.email
+        ))?;
+
+    transport.send(msg).await?;
+    AuditEvent::new(details.email.clone(), AuditAction::LoginLinkSent, AuditOutcome::Success)
+        .record_or_warn(db).await;
+
+    current_job.complete().await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct AccountDeletionDetails {
+    pub email: String,
+}
+
+#[job(channel_name = "emails")]
+pub(crate) async fn request_account_deletion(
+    mut current_job: CurrentJob,
+    transport: Transport,
+    CanonDomain(domain): CanonDomain,
+    AdminEmail(admin): AdminEmail,
+    issuer: TokenIssuer,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let details: AccountDeletionDetails = current_job.json()?.ok_or(crate::Error::Job("no job details".to_string()))?;
+    let db = current_job.pool();
+
+    let expires = SystemTime::now() + ONE_HOUR;
+    let bundle = issuer.delete_account(&details.email, expires)?;
+    let _ = Revocation::add_batch(db, bundle.revocation_ids, details.email.clone(), expires, None, None).await?;
+
+    let noreply_domain = domain.split(":").next().unwrap_or("example.com");
+
+    let msg = Message::builder()
+        .from(format!("Wag the Pig <noreply@{noreply_domain}>").parse()?)
+        .reply_to(admin.parse()?)
+        .to(details.email.parse()?)
+        .subject("Delete Your Account?")
+        .header(ContentType::TEXT_PLAIN)
+        .body(formatdoc!(r#"
+                Hey!
+
+                Someone asked to delete your account. If it wasn't you, delete this message
+                - and consider changing your password, since they were logged in as you.
+
+                Otherwise, follow this URL in the next hour to confirm:
+                https://{domain}/confirm_account_deletion/{email}#{token}
+
+                Your interests and sessions will be removed, and games you suggested
+                will stay with their events without your name on them. This can't be undone.
+
+                Regards,
+                Wag, the pig
+                "#,
+            token = bundle.token,
+            email = details.email
+        ))?;
+
+    transport.send(msg).await?;
+    AuditEvent::new(details.email.clone(), AuditAction::AccountDeletionSent, AuditOutcome::Success)
+        .record_or_warn(db).await;
+
+    current_job.complete().await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct EmailChangeDetails {
+    pub email: String,
+    pub new_email: String,
+}
+
+/// Sends a confirmation link to each address. If the new one already has an account,
+/// it's told so instead, and the change can't be completed.
+#[job(channel_name = "emails")]
+pub(crate) async fn request_email_change(
+    mut current_job: CurrentJob,
+    transport: Transport,
+    CanonDomain(domain): CanonDomain,
+    AdminEmail(admin): AdminEmail,
+    issuer: TokenIssuer,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let details: EmailChangeDetails = current_job.json()?.ok_or(crate::Error::Job("no job details".to_string()))?;
+    let db = current_job.pool();
+
+    let expires = SystemTime::now() + ONE_DAY;
+    let old_bundle = issuer.email_change(&details.email, &details.new_email, EmailChangeSide::Old.as_str(), expires)?;
+    let new_bundle = issuer.email_change(&details.email, &details.new_email, EmailChangeSide::New.as_str(), expires)?;
+    let rids = old_bundle.revocation_ids.into_iter().chain(new_bundle.revocation_ids).collect();
+    let _ = Revocation::add_batch(db, rids, details.email.clone(), expires, None, None).await?;
+    let taken = User::email_taken(db, details.new_email.clone()).await?;
+
+    let noreply_domain = domain.split(":").next().unwrap_or("example.com");
+
+    let old_msg = Message::builder()
+        .from(format!("Wag the Pig <noreply@{noreply_domain}>").parse()?)
+        .reply_to(admin.parse()?)
+        .to(details.email.parse()?)
+        .subject("Change Your Email?")
+        .header(ContentType::TEXT_PLAIN)
+        .body(formatdoc!(r#"
+                Hey!
+
+                Someone asked to move your account to {new_email}. If it wasn't you, delete this message
+                - and consider changing your password, since they were logged in as you.
+
+                Otherwise, follow this URL in the next day to confirm:
+                https://{domain}/confirm_email_change/{email}#{token}
+
+                We've sent a link to the new address too; the change happens once both are followed.
+
+                Regards,
+                Wag, the pig
+                "#,
+            token = old_bundle.token,
+            email = details.email,
+            new_email = details.new_email
+        ))?;
+
+    let new_msg = Message::builder()
+        .from(format!("Wag the Pig <noreply@{noreply_domain}>").parse()?)
+        .reply_to(admin.parse()?)
+        .to(details.new_email.parse()?);
+    let new_msg = if taken {
+        new_msg
+            .subject("Change Your Email?")
+            .header(ContentType::TEXT_PLAIN)
+            .body(formatdoc!(r#"
+                    Hey!
+
+                    We got a request to move another account to this email address, but you already have one.
+                    If you didn't ask for this, you can delete this email.
+
+                    Regards,
+                    Wag, the pig
+                    "#
+            ))?
+    } else {
+        new_msg
+            .subject("Confirm Your New Email")
+            .header(ContentType::TEXT_PLAIN)
+            .body(formatdoc!(r#"
+                    Hey!
+
+                    Someone asked to use this address for their Wag the Pig account. If it wasn't you, delete this message.
+
+                    Otherwise, follow this URL in the next day to confirm:
+                    https://{domain}/confirm_email_change/{email}#{token}
+
+                    Regards,
+                    Wag, the pig
+                    "#,
+                token = new_bundle.token,
+                email = details.email
+            ))?
+    };
+
+    transport.send(old_msg).await?;
+    transport.send(new_msg).await?;
+    AuditEvent::new(details.email.clone(), AuditAction::EmailChangeSent, AuditOutcome::Success)
+        .detail(if taken { "existing_account" } else { "sent" })
+        .record_or_warn(db).await;
+
+    current_job.complete().await?;
+    Ok(())
+}
+
+/// Lets the old address know the account has moved, in case it wasn't its owner who moved it
+#[job(channel_name = "emails")]
+pub(crate) async fn notify_email_changed(
+    mut current_job: CurrentJob,
+    transport: Transport,
+    CanonDomain(domain): CanonDomain,
+    AdminEmail(admin): AdminEmail,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let details: EmailChangeDetails = current_job.json()?.ok_or(crate::Error::Job("no job details".to_string()))?;
+
+    let noreply_domain = domain.split(":").next().unwrap_or("example.com");
+
+    let msg = Message::builder()
+        .from(format!("Wag the Pig <noreply@{noreply_domain}>").parse()?)
+        .reply_to(admin.parse()?)
+        .to(details.email.parse()?)
+        .subject("Your Email Has Changed")
+        .header(ContentType::TEXT_PLAIN)
+        .body(formatdoc!(r#"
+                Hey!
+
+                Your Wag the Pig account now logs in as {new_email}, and every session has been logged out.
+
+                If you didn't ask for this, please let us know by replying to this email.
+
+                Regards,
+                Wag, the pig
+                "#,
+            new_email = details.new_email
+        ))?;
+
     transport.send(msg).await?;
 
     current_job.complete().await?;
@@ -130,14 +404,14 @@ pub(crate) async fn request_registration(
     transport: AsyncSmtpTransport<Tokio1Executor>,
     CanonDomain(domain): CanonDomain,
     AdminEmail(admin): AdminEmail,
-    auth: biscuits::Authentication,
+    issuer: TokenIssuer,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
     let details: RegistrationDetails = current_job.json()?.ok_or(crate::Error::Job("no job details".to_string()))?;
     let db = current_job.pool();
 
     let expires = SystemTime::now() + ONE_HOUR;
-    let bundle = log_err("bundle token", auth.reset_password(&details.email, expires))?;
-    let _ = Revocation::add_batch(db, bundle.revocation_ids, details.email.clone(), expires).await?;
+    let bundle = log_err("bundle token", issuer.reset_password(&details.email, expires))?;
+    let _ = Revocation::add_batch(db, bundle.revocation_ids, details.email.clone(), expires, None, None).await?;
 
     let noreply_domain = domain.split(":").next().unwrap_or("example.com");
 
@@ -166,12 +440,112 @@ pub(crate) async fn request_registration(
 
     debug!("Sending message");
     transport.send(msg).await?;
+    AuditEvent::new(details.email.clone(), AuditAction::RegistrationSent, AuditOutcome::Success)
+        .record_or_warn(db).await;
 
     debug!("Marking job complete");
     current_job.complete().await?;
     Ok(())
 }
 
+/// Sent instead of a registration link when someone registers an email that already has an account,
+/// so that registering doesn't reveal who has one
+#[job(channel_name = "emails")]
+pub(crate) async fn notify_existing_account(
+    mut current_job: CurrentJob,
+    transport: Transport,
+    CanonDomain(domain): CanonDomain,
+    AdminEmail(admin): AdminEmail,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let details: RegistrationDetails = current_job.json()?.ok_or(crate::Error::Job("no job details".to_string()))?;
+
+    let noreply_domain = domain.split(":").next().unwrap_or("example.com");
+
+    let msg = Message::builder()
+        .from(format!("Wag the Pig <noreply@{noreply_domain}>").parse()?)
+        .reply_to(admin.parse()?)
+        .to(details.email.parse()?)
+        .subject("Account Registration")
+        .header(ContentType::TEXT_PLAIN)
+        .body(formatdoc!(r#"
+                Hey!
+
+                We got a request to register an account for this email address, but you already have one.
+                If you didn't ask for this, you can delete this email.
+
+                You can log in, or reset your password if you've forgotten it, here:
+                https://{domain}/login
+
+                Regards,
+                Wag, the pig
+                "#
+        ))?;
+
+    transport.send(msg).await?;
+    AuditEvent::new(details.email.clone(), AuditAction::RegistrationSent, AuditOutcome::Success).detail("existing_account")
+        .record_or_warn(current_job.pool()).await;
+
+    current_job.complete().await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct LoginLockDetails {
+    pub email: String,
+    pub until: NaiveDateTime,
+}
+
+#[job(channel_name = "emails")]
+pub(crate) async fn notify_login_lock(
+    mut current_job: CurrentJob,
+    transport: Transport,
+    CanonDomain(domain): CanonDomain,
+    AdminEmail(admin): AdminEmail,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    let details: LoginLockDetails = current_job.json()?.ok_or(crate::Error::Job("no job details".to_string()))?;
+    let db = current_job.pool();
+
+    // Unknown emails get locked out too, but there's nobody to tell
+    if User::by_email(db, details.email.clone()).await.is_err() {
+        debug!("No lockout notice for {:?}", details.email);
+        current_job.complete().await?;
+        return Ok(())
+    }
+
+    let noreply_domain = domain.split(":").next().unwrap_or("example.com");
+
+    let msg = Message::builder()
+        .from(format!("Wag the Pig <noreply@{noreply_domain}>").parse()?)
+        .reply_to(admin.parse()?)
+        .to(details.email.parse()?)
+        .subject("Too Many Login Attempts")
+        .header(ContentType::TEXT_PLAIN)
+        .body(formatdoc!(r#"
+                Hey!
+
+                There have been too many failed attempts to log in to your account,
+                so we've stopped accepting passwords for it until {until} UTC.
+
+                If that was you, you can still log in with a login link, or reset your password:
+                https://{domain}/login
+
+                If it wasn't you, someone may be guessing at your password. Reply to this email
+                and the site administrator can help.
+
+                Regards,
+                Wag, the pig
+                "#,
+            until = details.until.format("%Y-%m-%d %H:%M"),
+        ))?;
+
+    transport.send(msg).await?;
+    AuditEvent::new(details.email.clone(), AuditAction::LoginLockNotified, AuditOutcome::Success)
+        .record_or_warn(db).await;
+
+    current_job.complete().await?;
+    Ok(())
+}
+
 #[test]
 fn test_mail_parsing() {
     let domain = "localhost";
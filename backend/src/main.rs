@@ -1,12 +1,13 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{collections::BTreeSet, net::SocketAddr, path::PathBuf, time::Duration};
 
 use axum::{
-    extract::{self}, http::StatusCode, middleware, response::{IntoResponse, Result}, routing::{get, post, put}, Router
+    extract::{self}, http::StatusCode, middleware, response::{IntoResponse, Result}, routing::{delete, get, post, put}, Json, Router
 };
 
 use bcrypt::BcryptError;
-use biscuit_auth::macros::authorizer;
-use clap::Parser;
+use argon2::password_hash;
+use biscuit_auth::{builder::Term, macros::authorizer, AuthorizerBuilder};
+use clap::{Args, Parser, Subcommand};
 use lettre::{AsyncSmtpTransport, Tokio1Executor};
 use resources::authentication;
 use sqlx::{postgres::{PgConnectOptions, PgPoolOptions}, Pool, Postgres};
@@ -15,15 +16,25 @@ use tower_http::trace::TraceLayer;
 use tracing::{debug, info, warn};
 use tracing_subscriber::{EnvFilter, prelude::*};
 
-use crate::routing::RouteMap;
+use crate::{keyring::KeyRing, oidc::OidcClient, passkeys::Passkeys, password_policy::PasswordPolicy, revocation_cache::RevocationCache, routing::RouteMap, throttle::LoginThrottle, tokens::TokenIssuer, totp::TotpKey};
 
-use mattak::{biscuits::{self, resources::WellKnownKeySet, Authentication}, cachecontrol::CacheControlLayer, ratelimiting::{self, GovernorConfigBuilder, IpExtractor}, routing::{route_config, Route as _}};
+use mattak::{biscuits::{self, resources::WellKnownKeySet}, cachecontrol::CacheControlLayer, ratelimiting::{self, GovernorConfigBuilder, IpExtractor}, routing::{route_config, Route as _}};
 
 // app modules
 mod routing;
 mod resources;
 mod db;
+mod ical;
+mod keyring;
 mod mailing;
+mod oidc;
+mod passkeys;
+mod password_policy;
+mod passwords;
+mod revocation_cache;
+mod throttle;
+mod tokens;
+mod totp;
 
 #[derive(Clone)]
 struct BggApiUrl(String);
@@ -31,13 +42,61 @@ struct BggApiUrl(String);
 #[derive(extract::FromRef, Clone)]
 struct AppState {
     pool: Pool<Postgres>,
-    auth: Authentication,
+    keys: KeyRing,
+    issuer: TokenIssuer,
+    totp_key: TotpKey,
+    revocations: RevocationCache,
+    throttle: LoginThrottle,
+    password_policy: PasswordPolicy,
+    oidc: Option<OidcClient>,
+    passkeys: Passkeys,
     bgg_api_url: BggApiUrl
 }
 
 
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    config: Option<Config>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start signing tokens with a new key.
+    /// The keys it replaces keep verifying until every token they could have signed has expired,
+    /// and are dropped by the rotation after that.
+    /// Restart the server afterwards so that it picks up the new key.
+    RotateKeys {
+        /// Path to store token authenication secrets
+        #[arg(long, env = "AUTH_KEYPAIR")]
+        authentication_path: String,
+    },
+}
+
+impl Command {
+    fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Command::RotateKeys{ authentication_path } => {
+                let rotation = KeyRing::rotate(&authentication_path)?;
+                println!("Signing with key {}", rotation.active);
+                for (id, at) in rotation.retiring {
+                    println!("Key {id} retires at {at}");
+                }
+                for id in rotation.removed {
+                    println!("Removed retired key {id}");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Args)]
 struct Config {
     /// The local address
     #[arg(long, env = "LOCAL_ADDR", default_value = "127.0.0.1:3000")]
@@ -90,6 +149,45 @@ struct Config {
     #[arg(long, env = "AUTH_KEYPAIR")]
     authentication_path: String,
 
+    /// Path to store the key that encrypts two-factor secrets
+    #[arg(long, env = "TOTP_KEY")]
+    totp_key_path: String,
+
+    /// Failed logins for an account before it's locked out for a while
+    #[arg(long, env = "LOGIN_LOCK_THRESHOLD", default_value = "10")]
+    login_lock_threshold: i32,
+
+    /// How long, in minutes, an account is locked out after too many failed logins
+    #[arg(long, env = "LOGIN_LOCK_MINUTES", default_value = "15")]
+    login_lock_minutes: u64,
+
+    /// The lowest zxcvbn strength score, from 0 to 4, accepted for new passwords
+    #[arg(long, env = "PASSWORD_MIN_SCORE", default_value = "3")]
+    password_min_score: u8,
+
+    /// A directory of Have I Been Pwned range files (e.g. 0A1B2.txt) to check new passwords against.
+    /// Operators download these themselves, e.g. with the PwnedPasswordsDownloader.
+    #[arg(long, env = "PASSWORD_BREACH_DIR")]
+    password_breach_dir: Option<String>,
+
+    /// The OpenID Connect provider to offer logins through, e.g. https://accounts.google.com.
+    /// Provider logins are disabled if this isn't set.
+    /// The provider should send users back to https://{CANON_DOMAIN}/oidc_login
+    #[arg(long, env = "OIDC_ISSUER", requires = "oidc_client_id")]
+    oidc_issuer: Option<String>,
+
+    /// Our client ID with the OpenID Connect provider
+    #[arg(long, env = "OIDC_CLIENT_ID")]
+    oidc_client_id: Option<String>,
+
+    /// Our client secret with the OpenID Connect provider, if it gave us one
+    #[arg(long, env = "OIDC_CLIENT_SECRET")]
+    oidc_client_secret: Option<String>,
+
+    /// How many days to keep the security audit log
+    #[arg(long, env = "AUDIT_RETENTION_DAYS", default_value = "365")]
+    audit_retention_days: i64,
+
     /// Can we trust the X-Forwarded-For header?
     /// Otherwise we have to use the peer IP for rate limiting.
     /// In other words, if hosting behind e.g. nginx,
@@ -111,7 +209,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with(EnvFilter::from_default_env())
         .init();
 
-    let config = Config::parse();
+    let cli = Cli::parse();
+    if let Some(command) = cli.command {
+        return command.run()
+    }
+    let config = cli.config.expect("clap requires the server's arguments without a command");
 
     let transport = config.build_mailing_transport()?;
     match transport.test_connection().await {
@@ -130,30 +232,56 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await
         .expect("can't connect to database");
 
-    let auth = Authentication::new(config.authentication_path.clone())?;
+    let keys = KeyRing::load(&config.authentication_path)?;
+    let issuer = TokenIssuer::new(keys.clone());
+    let totp_key = TotpKey::new(config.totp_key_path.clone())?;
 
     let _runner = mailing::queue_listener(
         pool.clone(),
         config.admin_address.to_string(),
         config.canon_domain.to_string(),
         transport,
-        auth.clone(),
+        issuer.clone(),
+        mailing::AuditRetention(chrono::Duration::days(config.audit_retention_days)),
     ).await?;
 
-    let state = AppState{pool, auth: auth.clone(), bgg_api_url: BggApiUrl(config.bgg_api_url.clone())};
+    let revocations = RevocationCache::start(pool.clone());
+
+    let throttle = LoginThrottle{
+        threshold: config.login_lock_threshold,
+        lock_for: Duration::from_secs(config.login_lock_minutes * 60),
+    };
+
+    let password_policy = PasswordPolicy{
+        min_score: config.password_min_score.try_into()?,
+        breach_dir: config.password_breach_dir.clone().map(PathBuf::from),
+    };
+
+    let oidc = config.oidc_issuer.clone().zip(config.oidc_client_id.clone())
+        .map(|(oidc_issuer, client_id)| OidcClient::new(
+            oidc_issuer,
+            client_id,
+            config.oidc_client_secret.clone(),
+            format!("https://{}/oidc_login", config.canon_domain),
+        ))
+        .transpose()?;
+
+    let passkeys = Passkeys::new(&config.canon_domain, &totp_key.derive("passkey decoys"))?;
+
+    let state = AppState{pool, keys: keys.clone(), issuer, totp_key, revocations, throttle, password_policy, oidc, passkeys, bgg_api_url: BggApiUrl(config.bgg_api_url.clone())};
 
     let rate_key = IpExtractor::trust(config.trust_forwarded_header);
 
     let app = Router::new()
-        .route(&WellKnownKeySet::axum_route(), get(biscuits::resources::get_well_known_key_set::<Authentication>))
+        .route(&WellKnownKeySet::axum_route(), get(resources::key_set::get))
         .nest("/api",
             root_api_router(rate_key)
                 .merge(open_api_router(rate_key))
-                .merge(secured_api_router(state.clone(), auth, rate_key))
+                .merge(secured_api_router(state.clone(), keys, rate_key))
         );
 
     let app = spa(app, &config)?
-        .layer(TraceLayer::new_for_http())
+        .layer(TraceLayer::new_for_http().make_span_with(redacted_span))
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(config.local_addr.to_string()).await.expect("couldn't bind on local addr");
@@ -178,6 +306,29 @@ fn spa(router: Router<AppState>, config: &Config) -> Result<Router<AppState>, Bo
     axum_spa::leaked_livereload(router, &config.frontend_path)
 }
 
+/// Like the default request span, but share links and calendar feeds carry their tokens in the query,
+/// and the logs shouldn't collect them
+fn redacted_span(request: &extract::Request) -> tracing::Span {
+    tracing::debug_span!("request",
+        method = %request.method(),
+        uri = %redact_query(request.uri()),
+        version = ?request.version(),
+    )
+}
+
+fn redact_query(uri: &axum::http::Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.to_string()
+    };
+    let query: Vec<_> = query.split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key @ ("token" | "share"), _)) => format!("{key}=[redacted]"),
+            _ => pair.to_string(),
+        })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
 async fn sitemap(nested_at: extract::NestedPath, extract::State(BggApiUrl(bgg_api_url)): extract::State<BggApiUrl>) -> impl IntoResponse {
     routing::api_doc(nested_at.as_str(), &bgg_api_url)
 }
@@ -197,7 +348,7 @@ fn root_api_router(extractor: IpExtractor) -> Router<AppState> {
 fn open_api_router(extractor: IpExtractor) -> Router<AppState> {
     let path = |rm| route_config(rm).axum_route();
 
-    use resources::authentication;
+    use resources::{authentication, oidc, passkey};
 
     use RouteMap::*;
     Router::new()
@@ -207,22 +358,36 @@ fn open_api_router(extractor: IpExtractor) -> Router<AppState> {
         .route(&path(Profile), put(authentication::register))
 
         .route(&path(PasswordReset), post(authentication::reset_password))
+
+        .route(&path(Verification), post(authentication::resend_verification))
+
+        .route(&path(LoginLink), put(authentication::request_login_link))
+
+        .route(&path(SessionRefresh), post(authentication::refresh_session))
+
+        .route(&path(OidcLogin),
+            post(oidc::begin)
+                .put(oidc::finish)
+        )
+
+        .route(&path(PasskeyLogin),
+            post(passkey::start_login)
+                .put(passkey::finish_login)
+        )
         .layer(ratelimiting::layer("anonymous", extractor, GovernorConfigBuilder::default()
             .per_second(1)
             .burst_size(10)
         ))
 }
 
-fn secured_api_router(state: AppState, auth: Authentication, extractor: IpExtractor) -> Router<AppState> {
-    use resources::{event, game, profile, recommendation};
+fn secured_api_router(state: AppState, keys: KeyRing, extractor: IpExtractor) -> Router<AppState> {
+    use resources::{account, admin, api_token, audit, calendar, event, game, organizer, passkey, profile, recommendation, rsvp, session, share, two_factor};
     use RouteMap::*;
 
     let path = |rm| route_config(rm).axum_route();
 
-    let profile_path = path(Profile);
-
     Router::new()
-        .route(&profile_path, get(profile::get))
+        .route(&path(Profile), get(profile::get))
 
         .route(&path(Authenticate),
             put(authentication::update_credentials)
@@ -230,6 +395,56 @@ fn secured_api_router(state: AppState, auth: Authentication, extractor: IpExtrac
 
         )
 
+        .route(&path(LoginLink), post(authentication::login_with_link))
+
+        .route(&path(TwoFactor),
+            get(two_factor::get)
+                .post(two_factor::enroll)
+                .put(two_factor::confirm)
+                .delete(two_factor::disable)
+        )
+
+        .route(&path(Sessions),
+            get(session::get_list)
+                .delete(session::revoke_others)
+        )
+
+        .route(&path(Session), delete(session::revoke))
+
+        .route(&path(ApiTokens),
+            get(api_token::get_list)
+                .post(api_token::create)
+        )
+
+        .route(&path(ApiToken), delete(api_token::revoke))
+
+        .route(&path(Passkeys),
+            get(passkey::get_list)
+                .post(passkey::start_registration)
+                .put(passkey::finish_registration)
+        )
+
+        .route(&path(Passkey), delete(passkey::remove))
+
+        .route(&path(AuditEvents), get(audit::get_list))
+
+        .route(&path(AccountExport), get(account::get_export))
+
+        .route(&path(AccountDeletion),
+            put(account::request_deletion)
+                .delete(account::delete)
+        )
+
+        .route(&path(EmailChange),
+            put(account::request_email_change)
+                .post(account::confirm_email_change)
+        )
+
+        .route(&path(CalendarFeed),
+            get(calendar::get)
+                .post(calendar::create)
+        )
+
         .route(&path(Events),
             get(event::get_list)
                 .post(event::create_new)
@@ -238,10 +453,38 @@ fn secured_api_router(state: AppState, auth: Authentication, extractor: IpExtrac
         .route(&path(Event),
             get(event::get)
                 .put(event::update)
+                .delete(event::delete)
+        )
+
+        .route(&path(EventArchive),
+            put(event::archive)
+                .delete(event::unarchive)
         )
 
         .route(&path(EventUsers), get(profile::get_event_list))
 
+        .route(&path(EventRsvps), get(rsvp::get_list))
+
+        .route(&path(EventRsvp),
+            get(rsvp::get)
+                .put(rsvp::update)
+                .delete(rsvp::remove)
+        )
+
+        .route(&path(EventShares),
+            get(share::get_list)
+                .post(share::create)
+        )
+
+        .route(&path(EventShare), delete(share::revoke))
+
+        .route(&path(EventOrganizers), get(organizer::get_list))
+
+        .route(&path(EventOrganizer),
+            put(organizer::add)
+                .delete(organizer::remove)
+        )
+
         .route(&path(EventGames),
             get(game::get_scoped_list)
                 .post(game::create_new)
@@ -253,29 +496,169 @@ fn secured_api_router(state: AppState, auth: Authentication, extractor: IpExtrac
 
         .route(&path(Recommend), post(recommendation::make))
 
+        .route(&path(AdminUsers), get(admin::get_users))
+
+        .route(&path(AdminUserLock),
+            put(admin::lock)
+                .delete(admin::unlock)
+        )
+
+        .route(&path(AdminPasswordReset), post(admin::force_password_reset))
+
+        .route(&path(AdminSessions), delete(admin::revoke_sessions))
+
+        .route(&path(AdminAuditEvents), get(audit::get_admin_list))
+
         .layer(tower::ServiceBuilder::new()
             .layer(ratelimiting::layer("authenticated", extractor, GovernorConfigBuilder::default()
                 .per_millisecond(20)
                 .burst_size(60)
             ))
             .layer(CacheControlLayer::new(1))
-            .layer(biscuits::middleware::setup(auth, "Authorization"))
-            .layer(middleware::from_fn_with_state(state, authentication::add_rejections))
-            .layer(biscuits::middleware::check(authorizer!(r#"
-                allow if route({profile_path}), path_param("user_id", $user), user($user);
-                deny if route({profile_path});
-
-                allow if route({auth_path}), path_param("user_id", $user), user($user);
-                allow if route({auth_path}), path_param("user_id", $user), method("PUT"), reset_password($user);
-                deny if route({auth_path});
-
-                allow if user($user);
-                "#,
-                auth_path = path(Authenticate)
-            )))
+            .layer(middleware::from_fn(authentication::share_from_query))
+            .layer(biscuits::middleware::setup(keys, "Authorization"))
+            .layer(middleware::from_fn_with_state(state.clone(), authentication::add_rejections))
+            .layer(middleware::from_fn_with_state(state.clone(), authentication::authorize))
+            .layer(middleware::from_fn_with_state(state, authentication::add_current_user))
         )
 }
 
+/// The authorization policy for the secured API.
+/// Routes scoped to a user (by email in `user_id`) may only be changed by that user;
+/// anyone logged in may read another user's view of events and games.
+/// Admin routes require an `admin` fact, which only site administrators' tokens carry.
+/// API tokens can't be used to manage the account that issued them, or to issue calendar feeds.
+/// Links to an event only let their holder read that event, its games and who's coming.
+fn secured_policy() -> AuthorizerBuilder {
+    use RouteMap::*;
+
+    let path = |rm| route_config(rm).axum_route();
+    let admin_paths: BTreeSet<Term> = [AdminUsers, AdminUserLock, AdminPasswordReset, AdminSessions, AdminAuditEvents]
+        .into_iter()
+        .map(|rm| path(rm).into())
+        .collect();
+    let account_paths: BTreeSet<Term> = [Authenticate, LoginLink, TwoFactor, Sessions, Session, ApiTokens, ApiToken, Passkeys, Passkey, AuditEvents,
+        AccountExport, AccountDeletion, EmailChange]
+        .into_iter()
+        .map(|rm| path(rm).into())
+        .collect();
+
+    let organizer_paths: BTreeSet<Term> = [EventArchive, EventShares, EventShare, EventOrganizer]
+        .into_iter()
+        .map(|rm| path(rm).into())
+        .collect();
+
+    let share_paths: BTreeSet<Term> = [Event, EventGames, EventUsers, EventRsvps]
+        .into_iter()
+        .map(|rm| path(rm).into())
+        .collect();
+
+    authorizer!(r#"
+        allow if share_event($event), route($route), {share_paths}.contains($route), method("GET"), path_param("event_id", $event);
+        deny if share_event($event);
+
+        deny if api_token($label), route($route), {account_paths}.contains($route);
+        deny if api_token($label), route({profile_path}), method($method), $method != "GET";
+        deny if api_token($label), route({calendar_feed_path}), method("POST");
+
+        allow if route($route), {admin_paths}.contains($route), admin($user), user($user);
+        deny if route($route), {admin_paths}.contains($route);
+
+        allow if route({profile_path}), path_param("user_id", $user), user($user);
+        deny if route({profile_path});
+
+        allow if route({auth_path}), path_param("user_id", $user), user($user);
+        allow if route({auth_path}), path_param("user_id", $user), method("PUT"), reset_password($user);
+        deny if route({auth_path});
+
+        allow if route({login_link_path}), path_param("user_id", $user), method("POST"), login_link($user);
+        deny if route({login_link_path});
+
+        allow if route({two_factor_path}), path_param("user_id", $user), user($user);
+        deny if route({two_factor_path});
+
+        allow if route({sessions_path}), path_param("user_id", $user), user($user);
+        deny if route({sessions_path});
+
+        allow if route({session_path}), path_param("user_id", $user), user($user);
+        deny if route({session_path});
+
+        allow if route({api_tokens_path}), path_param("user_id", $user), user($user);
+        deny if route({api_tokens_path});
+
+        allow if route({api_token_path}), path_param("user_id", $user), user($user);
+        deny if route({api_token_path});
+
+        allow if route({passkeys_path}), path_param("user_id", $user), user($user);
+        deny if route({passkeys_path});
+
+        allow if route({passkey_path}), path_param("user_id", $user), user($user);
+        deny if route({passkey_path});
+
+        allow if route({audit_events_path}), path_param("user_id", $user), user($user);
+        deny if route({audit_events_path});
+
+        allow if route({account_export_path}), path_param("user_id", $user), user($user);
+        deny if route({account_export_path});
+
+        allow if route({account_deletion_path}), path_param("user_id", $user), method("PUT"), user($user);
+        allow if route({account_deletion_path}), path_param("user_id", $user), method("DELETE"), delete_account($user);
+        deny if route({account_deletion_path});
+
+        allow if route({email_change_path}), path_param("user_id", $user), method("PUT"), user($user);
+        allow if route({email_change_path}), path_param("user_id", $user), method("POST"), email_change($user, $new, $side);
+        deny if route({email_change_path});
+
+        allow if route({calendar_feed_path}), path_param("user_id", $user), user($user);
+        deny if route({calendar_feed_path});
+
+        allow if route($route), {organizer_paths}.contains($route), path_param("event_id", $event), organizer($user, $event), user($user);
+        deny if route($route), {organizer_paths}.contains($route);
+
+        allow if route({event_path}), method("GET"), user($any);
+        allow if route({event_path}), path_param("event_id", $event), organizer($user, $event), user($user);
+        deny if route({event_path});
+
+        allow if route({event_rsvp_path}), path_param("user_id", $user), user($user);
+        allow if route({event_rsvp_path}), method("GET"), user($any);
+        deny if route({event_rsvp_path});
+
+        allow if route({event_games_path}), path_param("user_id", $user), user($user);
+        allow if route({event_games_path}), method("GET"), user($any);
+        deny if route({event_games_path});
+
+        allow if route({game_path}), path_param("user_id", $user), user($user);
+        allow if route({game_path}), method("GET"), user($any);
+        deny if route({game_path});
+
+        allow if user($user);
+        "#,
+        profile_path = path(Profile),
+        auth_path = path(Authenticate),
+        login_link_path = path(LoginLink),
+        two_factor_path = path(TwoFactor),
+        sessions_path = path(Sessions),
+        session_path = path(Session),
+        api_tokens_path = path(ApiTokens),
+        api_token_path = path(ApiToken),
+        passkeys_path = path(Passkeys),
+        passkey_path = path(Passkey),
+        audit_events_path = path(AuditEvents),
+        account_export_path = path(AccountExport),
+        account_deletion_path = path(AccountDeletion),
+        email_change_path = path(EmailChange),
+        calendar_feed_path = path(CalendarFeed),
+        event_path = path(Event),
+        event_rsvp_path = path(EventRsvp),
+        event_games_path = path(EventGames),
+        game_path = path(Game),
+        admin_paths = admin_paths,
+        account_paths = account_paths,
+        share_paths = share_paths,
+        organizer_paths = organizer_paths,
+    )
+}
+
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -286,13 +669,23 @@ pub enum Error {
     #[error("status code: ${0:?} - ${1}")]
     StatusCode(StatusCode, String),
     #[error("cryptographic issue: ${0:?}")]
-    Crypto(#[from] bcrypt::BcryptError),
+    Crypto(#[from] passwords::Error),
     #[error("Problem with job queue: ${0:?}")]
     Job(String),
     #[error("Problem setting up email: ${0:?}")]
     Email(#[from] mailing::Error),
     #[error("Couldn't serialize data: ${0:?}")]
     Serialization(#[from] serde_json::Error),
+    #[error("token error: ${0:?}")]
+    Token(#[from] biscuit_auth::error::Token),
+    #[error("two-factor secret: ${0:?}")]
+    Totp(#[from] totp::Error),
+    #[error("password policy: ${0:?}")]
+    PasswordPolicy(#[from] password_policy::Error),
+    #[error("identity provider: ${0:?}")]
+    Oidc(#[from] oidc::Error),
+    #[error("passkey: ${0:?}")]
+    Passkey(#[from] passkeys::Error),
 }
 
 
@@ -319,16 +712,310 @@ impl IntoResponse for Error {
             Error::Job(m) => (StatusCode::INTERNAL_SERVER_ERROR, m).into_response(),
             Error::Email(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", e)).into_response(),
             Error::Crypto(e) => match e {
-                BcryptError::Rand(_) |
-                BcryptError::InvalidSaltLen(_) |
-                BcryptError::InvalidPrefix(_) |
-                BcryptError::InvalidCost(_) |
-                BcryptError::CostNotAllowed(_) |
-                BcryptError::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
-                BcryptError::InvalidHash(_) |
-                BcryptError::InvalidBase64(_) => (StatusCode::BAD_REQUEST).into_response(),
+                passwords::Error::Bcrypt(e) => match e {
+                    BcryptError::Rand(_) |
+                    BcryptError::InvalidSaltLen(_) |
+                    BcryptError::InvalidPrefix(_) |
+                    BcryptError::InvalidCost(_) |
+                    BcryptError::CostNotAllowed(_) |
+                    BcryptError::Io(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+                    BcryptError::InvalidHash(_) |
+                    BcryptError::InvalidBase64(_) => (StatusCode::BAD_REQUEST).into_response(),
+                },
+                passwords::Error::Argon2(e) => match e {
+                    password_hash::Error::B64Encoding(_) |
+                    password_hash::Error::PhcStringField |
+                    password_hash::Error::PhcStringTrailingData => (StatusCode::BAD_REQUEST).into_response(),
+                    _ => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+                },
             },
-            Error::Serialization(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", e)).into_response()
+            Error::Serialization(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", e)).into_response(),
+            Error::Token(e) => biscuits::Error::from(e).into_response(),
+            Error::Totp(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+            Error::PasswordPolicy(e) => match e {
+                password_policy::Error::Rejected(rejection) => (StatusCode::BAD_REQUEST, Json(rejection)).into_response(),
+                password_policy::Error::IO(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+            },
+            Error::Oidc(e) => match e {
+                oidc::Error::Http(_) |
+                oidc::Error::Provider(_) => {
+                    warn!("Identity provider: {e}");
+                    (StatusCode::BAD_GATEWAY, "couldn't log in with the identity provider; try again later").into_response()
+                },
+                oidc::Error::Token(_) |
+                oidc::Error::NoKey |
+                oidc::Error::Nonce => (StatusCode::UNAUTHORIZED, "Authorization rejected").into_response(),
+            },
+            Error::Passkey(e) => match e {
+                passkeys::Error::Webauthn(webauthn_rs::prelude::WebauthnError::Configuration) |
+                passkeys::Error::State(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+                passkeys::Error::Webauthn(_) => (StatusCode::FORBIDDEN, "Authorization rejected").into_response(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use biscuit_auth::{macros::{authorizer, biscuit, fact}, AuthorizerLimits, KeyPair};
+    use mattak::routing::route_config;
+
+    use crate::routing::RouteMap::{self, *};
+    use super::{redact_query, secured_policy};
+
+    /// The same time limit mattak's check middleware allows,
+    /// so that busy test runs don't fail on the 1ms default
+    pub(crate) fn limits() -> AuthorizerLimits {
+        AuthorizerLimits{ max_time: Duration::from_millis(20), ..Default::default() }
+    }
+
+    fn authorized(user: &str, method: &str, rm: RouteMap, params: &[(&str, &str)]) -> bool {
+        let token = biscuit!(r#"user({user});"#).build(&KeyPair::new()).expect("token to build");
+        let route = route_config(rm).axum_route();
+        let mut builder = authorizer!(r#"route({route}); method({method});"#);
+        for &(key, value) in params {
+            builder = builder.fact(fact!("path_param({key}, {value})")).expect("fact to add");
         }
+        builder.merge(secured_policy()).set_limits(limits())
+            .build(&token).expect("authorizer to build")
+            .authorize().is_ok()
+    }
+
+    #[test]
+    fn event_games_bound_to_user() {
+        let own = [("event_id", "1"), ("user_id", "one@example.com")];
+        let other = [("event_id", "1"), ("user_id", "two@example.com")];
+
+        assert!(authorized("one@example.com", "POST", EventGames, &own));
+        assert!(!authorized("one@example.com", "POST", EventGames, &other),
+            "suggesting a game as another user should be rejected");
+        assert!(authorized("one@example.com", "GET", EventGames, &other),
+            "reading another user's view of an event should be allowed");
+    }
+
+    #[test]
+    fn game_bound_to_user() {
+        let own = [("game_id", "7"), ("user_id", "one@example.com")];
+        let other = [("game_id", "7"), ("user_id", "two@example.com")];
+
+        assert!(authorized("one@example.com", "PUT", Game, &own));
+        assert!(!authorized("one@example.com", "PUT", Game, &other),
+            "changing another user's interest should be rejected");
+        assert!(authorized("one@example.com", "GET", Game, &other),
+            "reading another user's interest should be allowed");
+    }
+
+    #[test]
+    fn rsvp_bound_to_user() {
+        let own = [("event_id", "1"), ("user_id", "one@example.com")];
+        let other = [("event_id", "1"), ("user_id", "two@example.com")];
+
+        assert!(authorized("one@example.com", "PUT", EventRsvp, &own));
+        assert!(authorized("one@example.com", "DELETE", EventRsvp, &own));
+        assert!(!authorized("one@example.com", "PUT", EventRsvp, &other),
+            "answering for another user should be rejected");
+        assert!(!authorized("one@example.com", "DELETE", EventRsvp, &other));
+        assert!(authorized("one@example.com", "GET", EventRsvp, &other),
+            "reading another user's RSVP should be allowed");
+        assert!(authorized("one@example.com", "GET", EventRsvps, &[("event_id", "1")]));
+    }
+
+    #[test]
+    fn sessions_bound_to_user() {
+        assert!(authorized("one@example.com", "GET", Sessions, &[("user_id", "one@example.com")]));
+        assert!(!authorized("one@example.com", "GET", Sessions, &[("user_id", "two@example.com")]));
+        assert!(!authorized("one@example.com", "DELETE", Session, &[("user_id", "two@example.com"), ("session_id", "3")]));
+    }
+
+    #[test]
+    fn passkeys_bound_to_user() {
+        assert!(authorized("one@example.com", "POST", Passkeys, &[("user_id", "one@example.com")]));
+        assert!(!authorized("one@example.com", "GET", Passkeys, &[("user_id", "two@example.com")]));
+        assert!(!authorized("one@example.com", "DELETE", Passkey, &[("user_id", "two@example.com"), ("passkey_id", "3")]));
+    }
+
+    #[test]
+    fn audit_events_bound_to_user() {
+        assert!(authorized("one@example.com", "GET", AuditEvents, &[("user_id", "one@example.com")]));
+        assert!(!authorized("one@example.com", "GET", AuditEvents, &[("user_id", "two@example.com")]));
+        assert!(!authorized("one@example.com", "GET", AdminAuditEvents, &[]), "only admins see everyone's");
+    }
+
+    #[test]
+    fn admin_routes_need_admin_fact() {
+        let token = biscuit!(r#"user("admin@example.com"); admin("admin@example.com");"#)
+            .build(&KeyPair::new()).expect("token to build");
+        let route = route_config(AdminUsers).axum_route();
+        let admitted = authorizer!(r#"route({route}); method("GET");"#)
+            .merge(secured_policy()).set_limits(limits())
+            .build(&token).expect("authorizer to build")
+            .authorize().is_ok();
+        assert!(admitted);
+
+        assert!(!authorized("one@example.com", "GET", AdminUsers, &[]));
+        assert!(!authorized("one@example.com", "PUT", AdminUserLock, &[("user_id", "two@example.com")]));
+        assert!(!authorized("one@example.com", "DELETE", AdminSessions, &[("user_id", "one@example.com")]));
+    }
+
+    #[test]
+    fn login_link_only_logs_in() {
+        let token = biscuit!(r#"login_link("one@example.com");"#).build(&KeyPair::new()).expect("token to build");
+        let try_route = |rm, method: &str, user: &str| {
+            let route = route_config(rm).axum_route();
+            authorizer!(r#"route({route}); method({method}); path_param("user_id", {user});"#)
+                .merge(secured_policy()).set_limits(limits())
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        assert!(try_route(LoginLink, "POST", "one@example.com"));
+        assert!(!try_route(LoginLink, "POST", "two@example.com"));
+        assert!(!try_route(Profile, "GET", "one@example.com"));
+        assert!(!try_route(Events, "GET", "one@example.com"));
+
+        assert!(!authorized("one@example.com", "POST", LoginLink, &[("user_id", "one@example.com")]),
+            "a session token isn't a login link");
+    }
+
+    #[test]
+    fn account_deletion_needs_the_emailed_token() {
+        let token = biscuit!(r#"delete_account("one@example.com");"#).build(&KeyPair::new()).expect("token to build");
+        let try_route = |rm, method: &str, user: &str| {
+            let route = route_config(rm).axum_route();
+            authorizer!(r#"route({route}); method({method}); path_param("user_id", {user});"#)
+                .merge(secured_policy()).set_limits(limits())
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        assert!(try_route(AccountDeletion, "DELETE", "one@example.com"));
+        assert!(!try_route(AccountDeletion, "DELETE", "two@example.com"));
+        assert!(!try_route(AccountDeletion, "PUT", "one@example.com"));
+        assert!(!try_route(Profile, "GET", "one@example.com"));
+
+        let own = [("user_id", "one@example.com")];
+        assert!(authorized("one@example.com", "PUT", AccountDeletion, &own));
+        assert!(!authorized("one@example.com", "DELETE", AccountDeletion, &own), "a session alone can't delete the account");
+        assert!(!authorized("one@example.com", "PUT", AccountDeletion, &[("user_id", "two@example.com")]));
+        assert!(authorized("one@example.com", "GET", AccountExport, &own));
+        assert!(!authorized("one@example.com", "GET", AccountExport, &[("user_id", "two@example.com")]));
+    }
+
+    #[test]
+    fn email_change_needs_the_emailed_tokens() {
+        let token = biscuit!(r#"email_change("one@example.com", "new@example.com", "old");"#).build(&KeyPair::new()).expect("token to build");
+        let try_route = |rm, method: &str, user: &str| {
+            let route = route_config(rm).axum_route();
+            authorizer!(r#"route({route}); method({method}); path_param("user_id", {user});"#)
+                .merge(secured_policy()).set_limits(limits())
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        assert!(try_route(EmailChange, "POST", "one@example.com"));
+        assert!(!try_route(EmailChange, "POST", "two@example.com"));
+        assert!(!try_route(EmailChange, "PUT", "one@example.com"));
+        assert!(!try_route(Profile, "GET", "one@example.com"));
+
+        let own = [("user_id", "one@example.com")];
+        assert!(authorized("one@example.com", "PUT", EmailChange, &own));
+        assert!(!authorized("one@example.com", "POST", EmailChange, &own), "a session alone can't confirm the change");
+        assert!(!authorized("one@example.com", "PUT", EmailChange, &[("user_id", "two@example.com")]));
+    }
+
+    #[test]
+    fn calendar_feeds_are_the_users_own() {
+        let own = [("user_id", "one@example.com")];
+        assert!(authorized("one@example.com", "GET", CalendarFeed, &own));
+        assert!(authorized("one@example.com", "POST", CalendarFeed, &own));
+        assert!(!authorized("one@example.com", "GET", CalendarFeed, &[("user_id", "two@example.com")]));
+        assert!(!authorized("one@example.com", "POST", CalendarFeed, &[("user_id", "two@example.com")]));
+    }
+
+    #[test]
+    fn refresh_tokens_are_not_sessions() {
+        let token = biscuit!(r#"refresh_token("one@example.com");"#).build(&KeyPair::new()).expect("token to build");
+        let try_route = |rm, method: &str| {
+            let route = route_config(rm).axum_route();
+            authorizer!(r#"route({route}); method({method}); path_param("user_id", "one@example.com");"#)
+                .merge(secured_policy()).set_limits(limits())
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        assert!(!try_route(Profile, "GET"));
+        assert!(!try_route(Sessions, "GET"));
+        assert!(!try_route(Events, "GET"));
+        assert!(!try_route(Authenticate, "DELETE"));
+    }
+
+    #[test]
+    fn share_links_only_read_their_event() {
+        let token = biscuit!(r#"share_event("7");"#).build(&KeyPair::new()).expect("token to build");
+        let try_route = |rm, method: &str, event_id: &str| {
+            let route = route_config(rm).axum_route();
+            authorizer!(r#"route({route}); method({method}); path_param("event_id", {event_id}); path_param("user_id", "one@example.com");"#)
+                .merge(secured_policy()).set_limits(limits())
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        assert!(try_route(Event, "GET", "7"));
+        assert!(try_route(EventGames, "GET", "7"));
+        assert!(try_route(EventUsers, "GET", "7"));
+        assert!(try_route(EventRsvps, "GET", "7"));
+        assert!(!try_route(EventRsvp, "PUT", "7"), "share links can't answer for anyone");
+        assert!(!try_route(Event, "GET", "8"), "other events stay private");
+        assert!(!try_route(Event, "PUT", "7"), "shared events can't be changed");
+        assert!(!try_route(EventGames, "POST", "7"));
+        assert!(!try_route(EventShares, "GET", "7"));
+        assert!(!try_route(Profile, "GET", "7"));
+    }
+
+    #[test]
+    fn events_are_managed_by_their_organizers() {
+        let token = biscuit!(r#"user("one@example.com");"#).build(&KeyPair::new()).expect("token to build");
+        let try_route = |rm, method: &str, event_id: &str| {
+            let route = route_config(rm).axum_route();
+            authorizer!(r#"route({route}); method({method}); path_param("event_id", {event_id}); organizer("one@example.com", "7");"#)
+                .merge(secured_policy()).set_limits(limits())
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        assert!(try_route(Event, "PUT", "7"));
+        assert!(try_route(Event, "DELETE", "7"));
+        assert!(try_route(EventArchive, "PUT", "7"));
+        assert!(try_route(EventShares, "POST", "7"));
+        assert!(try_route(EventShare, "DELETE", "7"));
+        assert!(try_route(EventOrganizer, "PUT", "7"));
+        assert!(!try_route(Event, "PUT", "8"), "organizing one event doesn't reach another");
+        assert!(!try_route(EventShares, "GET", "8"));
+        assert!(!try_route(EventOrganizer, "DELETE", "8"));
+
+        let event = [("event_id", "7")];
+        assert!(authorized("one@example.com", "GET", Event, &event), "anyone can look at an event");
+        assert!(authorized("one@example.com", "GET", EventOrganizers, &event));
+        assert!(!authorized("one@example.com", "PUT", Event, &event));
+        assert!(!authorized("one@example.com", "DELETE", EventArchive, &event));
+        assert!(!authorized("one@example.com", "GET", EventShares, &event));
+        assert!(!authorized("one@example.com", "PUT", EventOrganizer, &event));
+    }
+
+    #[test]
+    fn logged_uris_leave_out_tokens() {
+        let redacted = |uri: &str| redact_query(&uri.parse().expect("a URI"));
+        assert_eq!(redacted("/api/calendar/one@example.com?token=secret"), "/api/calendar/one@example.com?token=[redacted]");
+        assert_eq!(redacted("/api/events/7?share=secret&page=2"), "/api/events/7?share=[redacted]&page=2");
+        assert_eq!(redacted("/api/events?page=2"), "/api/events?page=2");
+        assert_eq!(redacted("/api/events"), "/api/events");
+    }
+
+    #[test]
+    fn profile_bound_to_user() {
+        assert!(authorized("one@example.com", "GET", Profile, &[("user_id", "one@example.com")]));
+        assert!(!authorized("one@example.com", "GET", Profile, &[("user_id", "two@example.com")]));
+    }
+
+    #[test]
+    fn two_factor_bound_to_user() {
+        assert!(authorized("one@example.com", "POST", TwoFactor, &[("user_id", "one@example.com")]));
+        assert!(!authorized("one@example.com", "DELETE", TwoFactor, &[("user_id", "two@example.com")]));
     }
 }
@@ -0,0 +1,359 @@
+use std::{sync::{Arc, RwLock}, time::{Duration, Instant}};
+
+use chacha20poly1305::aead::{rand_core::RngCore as _, OsRng};
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::{Client, Url};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use subtle::ConstantTimeEq as _;
+use tracing::warn;
+
+const RANDOM_LEN: usize = 32;
+// A login waits on the provider, so it shouldn't hang when the provider does
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Providers rarely change their endpoints; this is long enough to save a round trip per login, short enough to pick up a change
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Signs in with an OpenID Connect provider, using the authorization code flow with PKCE
+#[derive(Clone)]
+pub(crate) struct OidcClient {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// Where the provider sends the browser back to, with the code and state
+    pub redirect_uri: String,
+    http: Client,
+    /// Discovery, and when to fetch it again
+    discovery: Arc<RwLock<Option<(Instant, Discovery)>>>,
+}
+
+/// What we have to remember about a login while the user is off at the provider
+pub(crate) struct PendingLogin {
+    pub state: String,
+    pub nonce: String,
+    pub pkce_verifier: String,
+}
+
+/// The parts of the ID token we use
+#[derive(Deserialize, Debug)]
+pub(crate) struct Claims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub nonce: Option<String>,
+}
+
+impl Claims {
+    /// The email, if the provider vouches for it
+    pub(crate) fn verified_email(&self) -> Option<&str> {
+        self.email.as_deref().filter(|_| self.email_verified)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+impl OidcClient {
+    pub(crate) fn new(issuer: String, client_id: String, client_secret: Option<String>, redirect_uri: String) -> Result<Self, Error> {
+        Ok(Self{
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret,
+            redirect_uri,
+            http: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()?,
+            discovery: Default::default(),
+        })
+    }
+
+    /// The provider's endpoints, fetched again once they're `DISCOVERY_TTL` old
+    async fn discover(&self) -> Result<Discovery, Error> {
+        if let Some((stale_at, discovery)) = self.discovery.read().expect("discovery lock not poisoned").as_ref() {
+            if Instant::now() < *stale_at {
+                return Ok(discovery.clone())
+            }
+        }
+
+        let discovery: Discovery = self.http.get(format!("{}/.well-known/openid-configuration", self.issuer))
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+        if discovery.issuer.trim_end_matches('/') != self.issuer {
+            return Err(Error::Provider(format!("discovery names a different issuer: {}", discovery.issuer)))
+        }
+        *self.discovery.write().expect("discovery lock not poisoned") = Some((Instant::now() + DISCOVERY_TTL, discovery.clone()));
+        Ok(discovery)
+    }
+
+    /// Starts a login, producing the URL to send the user to.
+    /// The pending login has to be kept until they come back.
+    pub(crate) async fn begin(&self) -> Result<(Url, PendingLogin), Error> {
+        let discovery = self.discover().await?;
+        let pending = PendingLogin{
+            state: random_string(),
+            nonce: random_string(),
+            pkce_verifier: random_string(),
+        };
+        let url = Url::parse_with_params(&discovery.authorization_endpoint, &[
+            ("response_type", "code"),
+            ("client_id", &self.client_id),
+            ("redirect_uri", &self.redirect_uri),
+            ("scope", "openid email"),
+            ("state", &pending.state),
+            ("nonce", &pending.nonce),
+            ("code_challenge", &pkce_challenge(&pending.pkce_verifier)),
+            ("code_challenge_method", "S256"),
+        ]).map_err(|e| Error::Provider(format!("bad authorization endpoint: {e}")))?;
+        Ok((url, pending))
+    }
+
+    /// Exchanges the code the provider sent back for the user's verified identity
+    pub(crate) async fn finish(&self, code: &str, pending: &PendingLogin) -> Result<Claims, Error> {
+        let discovery = self.discover().await?;
+
+        let mut request = self.http.post(&discovery.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_uri),
+                ("client_id", &self.client_id),
+                ("code_verifier", &pending.pkce_verifier),
+            ]);
+        if let Some(secret) = &self.client_secret {
+            request = request.basic_auth(&self.client_id, Some(secret));
+        }
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            // The body can say why, but it's the provider's words, so it's only logged
+            let body = response.text().await.unwrap_or_default();
+            warn!("OIDC token request failed with {status}: {body}");
+            return Err(Error::Provider(format!("token request failed with {status}")))
+        }
+        let tokens: TokenResponse = response.json().await?;
+
+        let keys: JwkSet = self.http.get(&discovery.jwks_uri)
+            .send().await?
+            .error_for_status()?
+            .json().await?;
+        let claims = self.verify_id_token(&tokens.id_token, &keys)?;
+
+        let nonce_matches = claims.nonce.as_ref()
+            .is_some_and(|nonce| bool::from(nonce.as_bytes().ct_eq(pending.nonce.as_bytes())));
+        if !nonce_matches {
+            return Err(Error::Nonce)
+        }
+        Ok(claims)
+    }
+
+    fn verify_id_token(&self, id_token: &str, keys: &JwkSet) -> Result<Claims, Error> {
+        let header = decode_header(id_token)?;
+        // Provider keys are public; a shared-secret algorithm here would mean a forged token
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(Error::NoKey)
+        }
+        let jwk = match &header.kid {
+            Some(kid) => keys.find(kid),
+            None if keys.keys.len() == 1 => keys.keys.first(),
+            None => None,
+        }.ok_or(Error::NoKey)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer, &format!("{}/", self.issuer)]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        Ok(decode::<Claims>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims)
+    }
+}
+
+fn random_string() -> String {
+    let mut bytes = [0u8; RANDOM_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64URL_NOPAD.encode(&bytes)
+}
+
+/// RFC 7636's S256 transformation
+fn pkce_challenge(verifier: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(verifier.as_bytes()))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("couldn't reach identity provider: {0:?}")]
+    Http(#[from] reqwest::Error),
+    #[error("identity provider refused: {0}")]
+    Provider(String),
+    #[error("ID token rejected: {0:?}")]
+    Token(#[from] jsonwebtoken::errors::Error),
+    #[error("ID token signed with an unknown key")]
+    NoKey,
+    #[error("ID token is for a different login")]
+    Nonce,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::{atomic::{AtomicUsize, Ordering}, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+    use axum::{extract::State, routing::{get, post}, Form, Json, Router};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use ring::{rand::SystemRandom, signature::{Ed25519KeyPair, KeyPair as _}};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const CLIENT_ID: &str = "wagthepig";
+    const CODE: &str = "a-good-code";
+
+    /// Stands in for an identity provider, issuing ID tokens for whoever was last sent to it
+    #[derive(Clone)]
+    struct MockIssuer {
+        base: String,
+        pkcs8: Arc<Vec<u8>>,
+        public: Vec<u8>,
+        authorized: Arc<Mutex<HashMap<String, String>>>,
+        discoveries: Arc<AtomicUsize>,
+    }
+
+    impl MockIssuer {
+        async fn start() -> Self {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).expect("a key");
+            let public = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).expect("a key").public_key().as_ref().to_vec();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.expect("a port");
+            let issuer = Self{
+                base: format!("http://{}", listener.local_addr().expect("an address")),
+                pkcs8: Arc::new(pkcs8.as_ref().to_vec()),
+                public,
+                authorized: Default::default(),
+                discoveries: Default::default(),
+            };
+            let app = Router::new()
+                .route("/.well-known/openid-configuration", get(discovery))
+                .route("/jwks", get(jwks))
+                .route("/token", post(token))
+                .with_state(issuer.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            issuer
+        }
+
+        /// What the provider would do once the user signs in: note the login's challenge and nonce
+        fn authorize(&self, url: &Url) {
+            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+            let mut authorized = self.authorized.lock().unwrap();
+            authorized.insert("code_challenge".to_string(), params["code_challenge"].clone());
+            authorized.insert("nonce".to_string(), params["nonce"].clone());
+        }
+
+        fn client(&self) -> OidcClient {
+            OidcClient::new(self.base.clone(), CLIENT_ID.to_string(), Some("secret".to_string()), "https://example.com/oidc".to_string())
+                .expect("a client")
+        }
+    }
+
+    async fn discovery(State(issuer): State<MockIssuer>) -> Json<Value> {
+        issuer.discoveries.fetch_add(1, Ordering::SeqCst);
+        Json(json!({
+            "issuer": issuer.base,
+            "authorization_endpoint": format!("{}/authorize", issuer.base),
+            "token_endpoint": format!("{}/token", issuer.base),
+            "jwks_uri": format!("{}/jwks", issuer.base),
+        }))
+    }
+
+    async fn jwks(State(issuer): State<MockIssuer>) -> Json<Value> {
+        Json(json!({"keys": [{
+            "kty": "OKP", "crv": "Ed25519", "alg": "EdDSA", "kid": "mock",
+            "x": BASE64URL_NOPAD.encode(&issuer.public),
+        }]}))
+    }
+
+    async fn token(State(issuer): State<MockIssuer>, Form(form): Form<HashMap<String, String>>) -> Result<Json<Value>, hyper::StatusCode> {
+        let authorized = issuer.authorized.lock().unwrap().clone();
+        if form["code"] != CODE || pkce_challenge(&form["code_verifier"]) != authorized["code_challenge"] {
+            return Err(hyper::StatusCode::BAD_REQUEST)
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some("mock".to_string());
+        let id_token = encode(&header, &json!({
+            "iss": issuer.base,
+            "aud": CLIENT_ID,
+            "sub": "provider-1234",
+            "email": "test@mctesterson.net",
+            "email_verified": true,
+            "nonce": authorized["nonce"],
+            "iat": now,
+            "exp": now + 300,
+        }), &EncodingKey::from_ed_der(&issuer.pkcs8)).expect("a signed token");
+        Ok(Json(json!({"access_token": "opaque", "token_type": "Bearer", "id_token": id_token})))
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_pkce() {
+        let issuer = MockIssuer::start().await;
+        let client = issuer.client();
+
+        let (url, pending) = client.begin().await.expect("a login to begin");
+        assert!(url.as_str().starts_with(&format!("{}/authorize?", issuer.base)));
+        issuer.authorize(&url);
+
+        let claims = client.finish(CODE, &pending).await.expect("the login to finish");
+        assert_eq!(claims.sub, "provider-1234");
+        assert_eq!(claims.verified_email(), Some("test@mctesterson.net"));
+    }
+
+    #[tokio::test]
+    async fn rejects_mismatched_logins() {
+        let issuer = MockIssuer::start().await;
+        let client = issuer.client();
+
+        let (url, pending) = client.begin().await.expect("a login to begin");
+        issuer.authorize(&url);
+        let (_, other) = client.begin().await.expect("a login to begin");
+
+        assert!(matches!(client.finish(CODE, &other).await, Err(Error::Provider(_))),
+            "the code only works with its own verifier");
+
+        let replayed = PendingLogin{ nonce: random_string(), ..pending };
+        assert!(matches!(client.finish(CODE, &replayed).await, Err(Error::Nonce)),
+            "the ID token has to be for this login");
+
+        let mut elsewhere = issuer.client();
+        elsewhere.client_id = "someone-else".to_string();
+        let (url, pending) = elsewhere.begin().await.expect("a login to begin");
+        issuer.authorize(&url);
+        assert!(matches!(elsewhere.finish(CODE, &pending).await, Err(Error::Token(_))),
+            "ID tokens for other clients are refused");
+    }
+    #[tokio::test]
+    async fn remembers_discovery() {
+        let issuer = MockIssuer::start().await;
+        let client = issuer.client();
+
+        let (url, pending) = client.begin().await.expect("a login to begin");
+        issuer.authorize(&url);
+        client.finish(CODE, &pending).await.expect("the login to finish");
+        client.begin().await.expect("a login to begin");
+        assert_eq!(issuer.discoveries.load(Ordering::SeqCst), 1);
+
+        let stale = client.discovery.read().unwrap().clone().map(|(_, discovery)| (Instant::now(), discovery));
+        *client.discovery.write().unwrap() = stale;
+        client.begin().await.expect("a login to begin");
+        assert_eq!(issuer.discoveries.load(Ordering::SeqCst), 2, "stale discovery is fetched again");
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+
+use webauthn_rs::{
+    fake::{FakePasskeyDistribution, WebauthnFakeCredentialGenerator},
+    prelude::{
+        AuthenticationResult, CreationChallengeResponse, Passkey, PasskeyAuthentication, PasskeyRegistration,
+        PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Url, Uuid, Webauthn, WebauthnBuilder,
+        WebauthnError
+    }
+};
+use webauthn_rs_proto::AllowCredentials;
+
+const RP_NAME: &str = "Wag the Pig";
+
+/// Registers and checks passkeys for the site at `CANON_DOMAIN`.
+/// The state kept between a challenge and its answer is serialized, so it can wait in the database.
+#[derive(Clone)]
+pub(crate) struct Passkeys {
+    webauthn: Arc<Webauthn>,
+    decoys: Arc<WebauthnFakeCredentialGenerator<FakePasskeyDistribution>>,
+}
+
+impl Passkeys {
+    /// `decoy_key` has to stay the same between restarts, or the decoys would change and give themselves away
+    pub(crate) fn new(canon_domain: &str, decoy_key: &[u8]) -> Result<Self, Error> {
+        let origin = Url::parse(&format!("https://{canon_domain}")).map_err(|_| WebauthnError::Configuration)?;
+        let rp_id = origin.host_str().ok_or(WebauthnError::Configuration)?.to_string();
+        let webauthn = WebauthnBuilder::new(&rp_id, &origin)?
+            .rp_name(RP_NAME)
+            .build()?;
+        let decoys = WebauthnFakeCredentialGenerator::new(decoy_key)?;
+        Ok(Self{ webauthn: Arc::new(webauthn), decoys: Arc::new(decoys) })
+    }
+
+    /// `existing` passkeys are excluded, so the same authenticator isn't registered twice
+    pub(crate) fn start_registration(&self, handle: Uuid, email: &str, name: &str, existing: &[Passkey])
+    -> Result<(CreationChallengeResponse, serde_json::Value), Error> {
+        let exclude = existing.iter().map(|pk| pk.cred_id().clone()).collect::<Vec<_>>();
+        let (challenge, state) = self.webauthn.start_passkey_registration(handle, email, name, Some(exclude))?;
+        Ok((challenge, serde_json::to_value(state)?))
+    }
+
+    pub(crate) fn finish_registration(&self, credential: &RegisterPublicKeyCredential, state: serde_json::Value)
+    -> Result<Passkey, Error> {
+        let state: PasskeyRegistration = serde_json::from_value(state)?;
+        Ok(self.webauthn.finish_passkey_registration(credential, &state)?)
+    }
+
+    pub(crate) fn start_login(&self, passkeys: &[Passkey]) -> Result<(RequestChallengeResponse, serde_json::Value), Error> {
+        let (challenge, state) = self.webauthn.start_passkey_authentication(passkeys)?;
+        Ok((challenge, serde_json::to_value(state)?))
+    }
+
+    /// A challenge for an email without passkeys, naming made-up credentials that are always the same for the email,
+    /// so it looks like one with passkeys. Nothing can answer it.
+    pub(crate) fn start_decoy_login(&self, email: &str) -> Result<(RequestChallengeResponse, serde_json::Value), Error> {
+        let (mut challenge, state) = self.webauthn.start_passkey_authentication(&[])?;
+        challenge.public_key.allow_credentials = self.decoys.generate(email.as_bytes())?
+            .into_iter()
+            .map(|id| AllowCredentials{ type_: "public-key".to_string(), id: id.as_ref().into(), transports: None })
+            .collect();
+        Ok((challenge, serde_json::to_value(state)?))
+    }
+
+    /// The result names the passkey used, and has its new counter
+    pub(crate) fn finish_login(&self, credential: &PublicKeyCredential, state: serde_json::Value)
+    -> Result<AuthenticationResult, Error> {
+        let state: PasskeyAuthentication = serde_json::from_value(state)?;
+        Ok(self.webauthn.finish_passkey_authentication(credential, &state)?)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("webauthn: {0:?}")]
+    Webauthn(#[from] WebauthnError),
+    #[error("stored challenge state: {0:?}")]
+    State(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use webauthn_authenticator_rs::{softpasskey::SoftPasskey, WebauthnAuthenticator};
+
+    use super::*;
+
+    const DOMAIN: &str = "wagthepig.example";
+
+    fn origin() -> Url {
+        Url::parse(&format!("https://{DOMAIN}")).unwrap()
+    }
+
+    fn register(passkeys: &Passkeys, authenticator: &mut WebauthnAuthenticator<SoftPasskey>) -> Passkey {
+        let (challenge, state) = passkeys.start_registration(Uuid::new_v4(), "test@mctesterson.net", "Testy McTesterson", &[])
+            .expect("a registration challenge");
+        let credential = authenticator.do_registration(origin(), challenge).expect("the authenticator to register");
+        passkeys.finish_registration(&credential, state).expect("the registration to finish")
+    }
+
+    #[test]
+    fn registers_and_logs_in() {
+        let passkeys = Passkeys::new(DOMAIN, b"decoy key").unwrap();
+        // The soft passkey can't verify users itself, so it has to claim it did
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let mut passkey = register(&passkeys, &mut authenticator);
+
+        let (challenge, state) = passkeys.start_login(&[passkey.clone()]).expect("a login challenge");
+        let credential = authenticator.do_authentication(origin(), challenge).expect("the authenticator to sign");
+        let result = passkeys.finish_login(&credential, state).expect("the login to finish");
+        assert_eq!(result.cred_id(), passkey.cred_id());
+        assert_eq!(passkey.update_credential(&result), Some(true), "the counter moves on");
+    }
+
+    #[test]
+    fn challenges_only_answer_their_own_ceremony() {
+        let passkeys = Passkeys::new(DOMAIN, b"decoy key").unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let passkey = register(&passkeys, &mut authenticator);
+
+        let (_, registration) = passkeys.start_registration(Uuid::new_v4(), "test@mctesterson.net", "Testy McTesterson", &[])
+            .expect("a registration challenge");
+        let (challenge, _) = passkeys.start_login(std::slice::from_ref(&passkey)).expect("a login challenge");
+        let credential = authenticator.do_authentication(origin(), challenge).expect("the authenticator to sign");
+        assert!(matches!(passkeys.finish_login(&credential, registration), Err(Error::State(_))));
+
+        let (_, other_login) = passkeys.start_login(&[passkey]).expect("a login challenge");
+        assert!(matches!(passkeys.finish_login(&credential, other_login), Err(Error::Webauthn(_))),
+            "a signature is only good for the challenge it answered");
+    }
+
+    #[test]
+    fn decoys_stay_the_same_and_let_nothing_in() {
+        let passkeys = Passkeys::new(DOMAIN, b"decoy key").unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let passkey = register(&passkeys, &mut authenticator);
+
+        let ids = |passkeys: &Passkeys, email| passkeys.start_decoy_login(email).expect("a decoy challenge").0
+            .public_key.allow_credentials.into_iter().map(|cred| cred.id).collect::<Vec<_>>();
+        let restarted = Passkeys::new(DOMAIN, b"decoy key").unwrap();
+        assert_eq!(ids(&passkeys, "nobody@nowhere.com"), ids(&restarted, "nobody@nowhere.com"));
+        assert_ne!(ids(&passkeys, "nobody@nowhere.com"), ids(&passkeys, "somebody@nowhere.com"));
+
+        let (_, decoy) = passkeys.start_decoy_login("test@mctesterson.net").expect("a decoy challenge");
+        let (challenge, _) = passkeys.start_login(&[passkey]).expect("a login challenge");
+        let credential = authenticator.do_authentication(origin(), challenge).expect("the authenticator to sign");
+        assert!(passkeys.finish_login(&credential, decoy).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    fs,
+    io,
+    path::{Path, PathBuf},
+};
+
+use data_encoding::HEXUPPER;
+use serde::Serialize;
+use sha1::{Digest as _, Sha1};
+use tracing::warn;
+use zxcvbn::{zxcvbn, Score};
+
+const MIN_LENGTH: usize = 12;
+const PREFIX_LEN: usize = 5;
+
+/// What new passwords have to live up to
+#[derive(Clone, Debug)]
+pub(crate) struct PasswordPolicy {
+    /// The lowest zxcvbn score (0 to 4) we'll accept
+    pub min_score: Score,
+    /// A directory of Have I Been Pwned range files, one per hash prefix, e.g. `0A1B2.txt`,
+    /// as produced by the PwnedPasswordsDownloader
+    pub breach_dir: Option<PathBuf>,
+}
+
+/// Why a password was refused, in a form the frontend can explain
+#[derive(Serialize, Debug, PartialEq, thiserror::Error)]
+#[serde(tag = "reason", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum Rejection {
+    #[error("password is shorter than {min_length} characters")]
+    TooShort { min_length: usize },
+    #[error("password is too easy to guess")]
+    TooWeak {
+        score: u8,
+        required_score: u8,
+        warning: Option<String>,
+        suggestions: Vec<String>,
+    },
+    #[error("password has appeared in a data breach")]
+    Breached { times: u64 },
+}
+
+impl PasswordPolicy {
+    /// `user_inputs` are things like the user's name and email,
+    /// which make a password easier to guess if it contains them
+    pub(crate) fn check(&self, password: &str, user_inputs: &[&str]) -> Result<(), Error> {
+        if password.chars().count() < MIN_LENGTH {
+            return Err(Rejection::TooShort{ min_length: MIN_LENGTH }.into())
+        }
+
+        let entropy = zxcvbn(password, user_inputs);
+        if entropy.score() < self.min_score {
+            let feedback = entropy.feedback();
+            return Err(Rejection::TooWeak{
+                score: entropy.score().into(),
+                required_score: self.min_score.into(),
+                warning: feedback.and_then(|f| f.warning()).map(|w| w.to_string()),
+                suggestions: feedback.map(|f| f.suggestions().iter().map(|s| s.to_string()).collect()).unwrap_or_default(),
+            }.into())
+        }
+
+        if let Some(dir) = &self.breach_dir {
+            let times = breach_count(dir, password)?;
+            if times > 0 {
+                return Err(Rejection::Breached{ times }.into())
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Looks the password's SHA-1 up in its range file.
+/// Range files list the rest of each hash with a count, like `0018A45C4D1DEF81644B54AB7F969B88D65:10`
+fn breach_count(dir: &Path, password: &str) -> Result<u64, Error> {
+    let hash = HEXUPPER.encode(&Sha1::digest(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+
+    let range = match fs::read_to_string(dir.join(format!("{prefix}.txt"))) {
+        Ok(range) => range,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("No breached password range file for {prefix}; is the download complete?");
+            return Ok(0)
+        }
+        Err(e) => return Err(e.into())
+    };
+
+    Ok(range.lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(candidate, _)| candidate.eq_ignore_ascii_case(suffix))
+        .and_then(|(_, count)| count.parse().ok())
+        .unwrap_or(0))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("password rejected: {0}")]
+    Rejected(#[from] Rejection),
+    #[error("couldn't read breached password range: {0:?}")]
+    IO(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(breach_dir: Option<PathBuf>) -> PasswordPolicy {
+        PasswordPolicy{ min_score: Score::Three, breach_dir }
+    }
+
+    fn rejection(result: Result<(), Error>) -> Option<Rejection> {
+        match result {
+            Err(Error::Rejected(rejection)) => Some(rejection),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn rejects_short_and_guessable_passwords() {
+        let policy = policy(None);
+        assert_eq!(rejection(policy.check("short", &[])), Some(Rejection::TooShort{ min_length: MIN_LENGTH }));
+        assert!(matches!(rejection(policy.check("passwordpassword", &[])), Some(Rejection::TooWeak{ .. })));
+        assert!(matches!(rejection(policy.check("mctesterson1234", &["testy", "mctesterson"])), Some(Rejection::TooWeak{ .. })),
+            "passwords made of the user's details are easy to guess");
+        assert!(policy.check("plinth marmalade oboe thundercloud", &[]).is_ok());
+    }
+
+    #[test]
+    fn rejects_breached_passwords() {
+        let dir = std::env::temp_dir().join(format!("wtp-breaches-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let password = "plinth marmalade oboe thundercloud";
+        let hash = HEXUPPER.encode(&Sha1::digest(password.as_bytes()));
+        let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+        fs::write(dir.join(format!("{prefix}.txt")), format!("0000000000000000000000000000000000A:0\r\n{suffix}:42\r\n")).unwrap();
+
+        let policy = policy(Some(dir.clone()));
+        let result = policy.check(password, &[]);
+        let other = policy.check("quixotic lanterns juggle seventeen", &[]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rejection(result), Some(Rejection::Breached{ times: 42 }));
+        assert!(other.is_ok(), "passwords without a range file aren't known to be breached");
+    }
+
+    #[test]
+    fn rejections_explain_themselves() {
+        let json = serde_json::to_value(Rejection::TooShort{ min_length: 12 }).unwrap();
+        assert_eq!(json, serde_json::json!({"reason": "tooShort", "minLength": 12}));
+    }
+}
@@ -0,0 +1,72 @@
+use argon2::{
+    password_hash::{self, rand_core::OsRng, PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString},
+    Argon2
+};
+use tracing::debug;
+
+use crate::db::Password;
+
+// Rails (and so, existing accounts) used bcrypt.
+// We accept those hashes, but rehash as Argon2id on the next successful login.
+const BCRYPT_PREFIXES: [&str; 4] = ["$2a$", "$2b$", "$2x$", "$2y$"];
+
+/// Produces a PHC-format Argon2id hash of a password
+pub(crate) fn hash(password: &str) -> Result<String, Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+/// Checks a password against a stored hash, in either Argon2id PHC format or legacy bcrypt
+pub(crate) fn verify(password: &str, hashed: &Password) -> Result<bool, Error> {
+    let hashed = hashed.as_ref();
+    if is_legacy(hashed) {
+        debug!("Verifying against legacy bcrypt hash");
+        return Ok(bcrypt::verify(password, hashed)?)
+    }
+
+    let parsed = PasswordHash::new(hashed)?;
+    match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => Ok(true),
+        Err(password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// Should a successfully verified hash be replaced?
+pub(crate) fn needs_rehash(hashed: &Password) -> bool {
+    is_legacy(hashed.as_ref())
+}
+
+fn is_legacy(hashed: &str) -> bool {
+    BCRYPT_PREFIXES.iter().any(|prefix| hashed.starts_with(prefix))
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("bcrypt: ${0:?}")]
+    Bcrypt(#[from] bcrypt::BcryptError),
+    #[error("argon2: ${0:?}")]
+    Argon2(#[from] password_hash::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn argon2_round_trip() {
+        let hashed: Password = hash("correct horse battery staple").unwrap().into();
+        assert!(hashed.as_ref().starts_with("$argon2id$"));
+        assert!(verify("correct horse battery staple", &hashed).unwrap());
+        assert!(!verify("incorrect horse battery staple", &hashed).unwrap());
+        assert!(!needs_rehash(&hashed));
+    }
+
+    #[test]
+    fn bcrypt_still_verifies() {
+        let hashed: Password = bcrypt::hash("correct horse battery staple", 4).unwrap().into();
+        assert!(verify("correct horse battery staple", &hashed).unwrap());
+        assert!(!verify("incorrect horse battery staple", &hashed).unwrap());
+        assert!(needs_rehash(&hashed));
+    }
+}
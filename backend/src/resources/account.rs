@@ -0,0 +1,318 @@
+use std::net::SocketAddr;
+
+use axum::{debug_handler, extract::{self, ConnectInfo, Path, State}, response::IntoResponse, Extension, Json};
+use biscuit_auth::macros::authorizer;
+use chrono::{NaiveDateTime, Utc};
+use hyper::StatusCode;
+use lettre::Address;
+use mattak::{biscuits::AuthContext, condreq, hypermedia::{op, ActionType, ResourceFields}};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{
+        AuditAction, AuditEvent, AuditOutcome, EmailChange, EmailChangeSide, Game, GameId, EventId, Interest, InterestId,
+        LoginFailure, Omit, Revocation, RevocationId, Rsvp, RsvpStatus, User, UserId
+    },
+    mailing,
+    revocation_cache::RevocationCache,
+    routing::{AccountExportLocate, RouteMap},
+    AppState, Error
+};
+
+/// Everything we keep about a user, for them to take away
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct AccountExportResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<AccountExportLocate>,
+
+    pub profile: ExportedProfile,
+    pub suggested_games: Vec<ExportedGame>,
+    pub interests: Vec<ExportedInterest>,
+    pub rsvps: Vec<ExportedRsvp>,
+    pub sessions: Vec<ExportedSession>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ExportedProfile {
+    pub email: String,
+    pub name: Option<String>,
+    pub bgg_username: Option<String>,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ExportedGame {
+    pub id: GameId,
+    pub event_id: EventId,
+    pub name: Option<String>,
+    pub bgg_id: Option<String>,
+    pub bgg_link: Option<String>,
+    pub min_players: Option<i32>,
+    pub max_players: Option<i32>,
+    pub duration_secs: Option<i32>,
+    pub pitch: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ExportedInterest {
+    pub game_id: i64,
+    pub notes: Option<String>,
+    pub can_teach: Option<bool>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ExportedRsvp {
+    pub event_id: EventId,
+    pub status: RsvpStatus,
+    pub arrives_at: Option<NaiveDateTime>,
+    pub departs_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ExportedSession {
+    pub client_hint: Option<String>,
+    pub client_ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub expires: NaiveDateTime,
+}
+
+impl AccountExportResponse {
+    pub fn from_query(
+        nested_at: &str,
+        user: User<UserId>,
+        games: Vec<Game<GameId, EventId, Option<UserId>, Omit>>,
+        interests: Vec<Interest<InterestId>>,
+        rsvps: Vec<Rsvp>,
+        sessions: Vec<Revocation<RevocationId>>,
+    ) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::AccountExport.prefixed(nested_at),
+                AccountExportLocate{ user_id: user.email.clone() },
+                "api:accountExport",
+                vec![ op(ActionType::View) ]
+            )?,
+            profile: ExportedProfile{
+                email: user.email,
+                name: user.name,
+                bgg_username: user.bgg_username,
+                email_verified_at: user.email_verified_at,
+                created_at: user.created_at,
+                updated_at: user.updated_at,
+            },
+            suggested_games: games.into_iter().map(|game| ExportedGame{
+                id: game.id,
+                event_id: game.event_id,
+                name: game.data.name,
+                bgg_id: game.data.bgg_id,
+                bgg_link: game.data.bgg_link,
+                min_players: game.data.min_players,
+                max_players: game.data.max_players,
+                duration_secs: game.data.duration_secs,
+                pitch: game.data.pitch,
+                created_at: game.data.created_at,
+            }).collect(),
+            interests: interests.into_iter().map(|interest| ExportedInterest{
+                game_id: interest.game_id,
+                notes: interest.notes,
+                can_teach: interest.can_teach,
+                created_at: interest.created_at,
+            }).collect(),
+            rsvps: rsvps.into_iter().map(|rsvp| ExportedRsvp{
+                event_id: rsvp.event_id,
+                status: rsvp.status,
+                arrives_at: rsvp.arrives_at,
+                departs_at: rsvp.departs_at,
+                created_at: rsvp.created_at,
+            }).collect(),
+            sessions: sessions.into_iter().map(|session| ExportedSession{
+                client_hint: session.clienthint,
+                client_ip: session.client_ip,
+                created_at: session.created_at,
+                expires: session.expires,
+            }).collect(),
+        })
+    }
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_export(
+    State(db): State<Pool<Postgres>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id.clone()).await?;
+    let games = Game::get_suggested_by(&db, user.id).await?;
+    let interests = Interest::get_for_user(&db, user.id).await?;
+    let rsvps = Rsvp::get_for_user(&db, user.id).await?;
+    let sessions = Revocation::get_live_for_username(&db, user_id.clone(), Utc::now().naive_utc()).await?;
+    AuditEvent::new(user_id.clone(), AuditAction::AccountExported, AuditOutcome::Success).actor(user_id).client_ip(addr)
+        .record(&db).await?;
+    let resp = AccountExportResponse::from_query(nested_at.as_str(), user, games, interests, rsvps, sessions)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+/// Emails a link to confirm the deletion, so that a stolen session isn't enough to delete an account
+#[debug_handler(state = AppState)]
+pub(crate) async fn request_deletion(
+    State(db): State<Pool<Postgres>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    AuditEvent::new(user_id.clone(), AuditAction::AccountDeletionRequested, AuditOutcome::Success).actor(user_id.clone()).client_ip(addr)
+        .record(&db).await?;
+    mailing::request_account_deletion.builder()
+        .set_json(&mailing::AccountDeletionDetails{
+            email: user_id,
+        })?
+        .spawn(&db).await
+        .map_err(crate::db::Error::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes the account, given the token from the confirmation email (which can only be used once).
+/// Suggested games stay with their events, unattributed; interests and RSVPs go, and tokens are revoked
+/// and stripped of anything that identifies the user. The audit log keeps its entries until they age out.
+#[debug_handler(state = AppState)]
+pub(crate) async fn delete(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(authctx): Extension<AuthContext>,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id.clone()).await?;
+    let now = Utc::now().naive_utc();
+
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    let consumed = Revocation::consume(&mut *tx, authctx.revocation_ids().unwrap_or_default(), now).await?;
+    if consumed.is_empty() {
+        return Err((StatusCode::UNAUTHORIZED, "deletion link already used").into())
+    }
+    Interest::remove_for_user(&mut *tx, user.id).await?;
+    Game::anonymize_for_user(&mut *tx, user.id).await?;
+    let revoked = Revocation::scrub_for_username(&mut *tx, user.email.clone(), now).await?;
+    LoginFailure::clear(&mut *tx, user.email.clone()).await?;
+    User::delete(&mut *tx, user.id).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+    cache.add(&revoked);
+
+    AuditEvent::new(user_id.clone(), AuditAction::AccountDeleted, AuditOutcome::Success).actor(user_id).client_ip(addr)
+        .record_or_warn(&db).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct EmailChangeRequest {
+    pub new_email: String,
+}
+
+/// Starts moving the account to a new address, replacing any change already underway.
+/// Nothing changes until links emailed to both addresses have been followed.
+#[debug_handler(state = AppState)]
+pub(crate) async fn request_email_change(
+    State(db): State<Pool<Postgres>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(user_id): Path<String>,
+    Json(req): Json<EmailChangeRequest>
+) -> Result<impl IntoResponse, Error> {
+    let new_email = req.new_email.trim().to_string();
+    if new_email.parse::<Address>().is_err() {
+        return Err((StatusCode::BAD_REQUEST, "not an email address").into())
+    }
+    if new_email == user_id {
+        return Err((StatusCode::BAD_REQUEST, "that's already the account's email").into())
+    }
+
+    let user = User::by_email(&db, user_id.clone()).await?;
+    EmailChange::request(&db, user.id, new_email.clone()).await?;
+    AuditEvent::new(user_id.clone(), AuditAction::EmailChangeRequested, AuditOutcome::Success).actor(user_id.clone()).client_ip(addr)
+        .record_or_warn(&db).await;
+    mailing::request_email_change.builder()
+        .set_json(&mailing::EmailChangeDetails{
+            email: user_id,
+            new_email,
+        })?
+        .spawn(&db).await
+        .map_err(crate::db::Error::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Records a confirmation from one of the addresses, with the token from its email (which can only be used once).
+/// Once both have confirmed, the account and its tokens move to the new address, and every session is revoked.
+#[debug_handler(state = AppState)]
+pub(crate) async fn confirm_email_change(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(authctx): Extension<AuthContext>,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id.clone()).await?;
+    let pending = EmailChange::for_user(&db, user.id).await?
+        .ok_or_else(|| -> Error {(StatusCode::NOT_FOUND, "no email change underway").into()})?;
+    // A link for a change that's since been replaced won't match
+    let side = [EmailChangeSide::Old, EmailChangeSide::New].into_iter()
+        .find(|side| authctx.check(authorizer!(
+            r#"allow if email_change({user_id}, {new_email}, {side});"#,
+            user_id = user_id.clone(), new_email = pending.new_email.clone(), side = side.as_str()
+        )).is_ok())
+        .ok_or_else(|| -> Error {(StatusCode::UNAUTHORIZED, "confirmation link doesn't match the change underway").into()})?;
+    let now = Utc::now().naive_utc();
+
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    let consumed = Revocation::consume(&mut *tx, authctx.revocation_ids().unwrap_or_default(), now).await?;
+    if consumed.is_empty() {
+        return Err((StatusCode::UNAUTHORIZED, "confirmation link already used").into())
+    }
+    let change = EmailChange::confirm(&mut *tx, user.id, pending.new_email.clone(), side, now).await?
+        .ok_or_else(|| -> Error {(StatusCode::NOT_FOUND, "no email change underway").into()})?;
+    if !change.is_confirmed() {
+        tx.commit().await.map_err(crate::db::Error::from)?;
+        AuditEvent::new(user_id.clone(), AuditAction::EmailChangeConfirmed, AuditOutcome::Success).client_ip(addr).detail(side.as_str())
+            .record_or_warn(&db).await;
+        return Ok(StatusCode::NO_CONTENT)
+    }
+
+    if User::email_taken(&mut *tx, change.new_email.clone()).await? {
+        return Err((StatusCode::CONFLICT, "that address already has an account").into())
+    }
+    User::change_email(&mut *tx, user.id, change.new_email.clone()).await?;
+    Revocation::rename_username(&mut *tx, user_id.clone(), change.new_email.clone()).await?;
+    AuditEvent::rename_email(&mut *tx, user_id.clone(), change.new_email.clone()).await?;
+    let revoked = Revocation::revoke_for_username(&mut *tx, change.new_email.clone(), now).await?;
+    LoginFailure::clear(&mut *tx, user_id.clone()).await?;
+    EmailChange::remove(&mut *tx, user.id).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+    cache.add(&revoked);
+
+    AuditEvent::new(change.new_email.clone(), AuditAction::EmailChanged, AuditOutcome::Success).actor(user_id.clone()).client_ip(addr)
+        .detail(side.as_str())
+        .record_or_warn(&db).await;
+    mailing::notify_email_changed.builder()
+        .set_json(&mailing::EmailChangeDetails{
+            email: user_id,
+            new_email: change.new_email,
+        })?
+        .spawn(&db).await
+        .map_err(crate::db::Error::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,184 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use axum::{debug_handler, extract::{self, ConnectInfo, Path, State}, response::IntoResponse, Extension};
+use chrono::{NaiveDateTime, Utc};
+use hyper::StatusCode;
+use mattak::{condreq, hypermedia::{op, ActionType, Link, ResourceFields}};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{AuditAction, AuditEvent, AuditOutcome, LoginFailure, Revocation, User, UserId},
+    mailing,
+    resources::{authentication::CurrentUser, delete_op},
+    revocation_cache::RevocationCache,
+    routing::{AdminUserLocate, EmptyLocate, RouteMap},
+    AppState, Error
+};
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct UserListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<EmptyLocate>,
+
+    pub users: Vec<UserResponse>,
+}
+
+impl UserListResponse {
+    pub fn from_query(nested_at: &str, list: Vec<User<UserId>>, login_locks: HashMap<String, NaiveDateTime>) -> Result<Self, mattak::Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::AdminUsers.prefixed(nested_at),
+                EmptyLocate{},
+                "api:adminUsersList",
+                vec![ op(ActionType::View) ]
+            )?,
+            users: list.into_iter().map(|user| {
+                let login_locked_until = login_locks.get(&user.email).copied();
+                UserResponse::from_query(nested_at, user, login_locked_until)
+            })
+                .collect::<Result<_,_>>()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct UserResponse {
+    pub lock: Link,
+    pub password_reset: Link,
+    pub sessions: Link,
+
+    pub name: Option<String>,
+    pub email: String,
+    pub bgg_username: Option<String>,
+    pub is_admin: bool,
+    pub locked_at: Option<NaiveDateTime>,
+    /// Set while too many failed logins have locked the account; unlocking clears it
+    pub login_locked_until: Option<NaiveDateTime>,
+    pub email_verified_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl UserResponse {
+    pub(crate) fn from_query(nested_at: &str, value: User<UserId>, login_locked_until: Option<NaiveDateTime>) -> Result<Self, mattak::Error> {
+        let locate = || AdminUserLocate{ user_id: value.email.clone() };
+        Ok(Self{
+            lock: Link {
+                id: RouteMap::AdminUserLock.prefixed(nested_at).fill(locate())?,
+                operation: vec![ op(ActionType::Create), delete_op() ]
+            },
+            password_reset: Link {
+                id: RouteMap::AdminPasswordReset.prefixed(nested_at).fill(locate())?,
+                operation: vec![ op(ActionType::Add) ]
+            },
+            sessions: Link {
+                id: RouteMap::AdminSessions.prefixed(nested_at).fill(locate())?,
+                operation: vec![ op(ActionType::Logout) ]
+            },
+
+            name: value.name,
+            email: value.email.clone(),
+            bgg_username: value.bgg_username,
+            is_admin: value.is_admin,
+            locked_at: value.locked_at,
+            login_locked_until,
+            email_verified_at: value.email_verified_at,
+            created_at: value.created_at,
+        })
+    }
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_users(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+) -> Result<impl IntoResponse, Error> {
+    let users = User::get_all(&db).await?;
+    let login_locks = LoginFailure::get_locked(&db, Utc::now().naive_utc()).await?
+        .into_iter()
+        .filter_map(|failure| Some((failure.email, failure.locked_until?)))
+        .collect();
+    let resp = UserListResponse::from_query(nested_at.as_str(), users, login_locks)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+/// Locks an account, so that it can't log in, and ends its sessions
+#[debug_handler(state = AppState)]
+pub(crate) async fn lock(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(CurrentUser(admin)): Extension<CurrentUser>,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let now = Utc::now().naive_utc();
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    User::set_locked(&mut *tx, user_id.clone(), Some(now)).await?;
+    let revoked = Revocation::revoke_for_username(&mut *tx, user_id.clone(), now).await?;
+    AuditEvent::new(user_id, AuditAction::AccountLocked, AuditOutcome::Success).actor(admin).client_ip(addr)
+        .record(&mut *tx).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+    cache.add(&revoked);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Lifts both an administrator's lock and one from too many failed logins
+#[debug_handler(state = AppState)]
+pub(crate) async fn unlock(
+    State(db): State<Pool<Postgres>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(CurrentUser(admin)): Extension<CurrentUser>,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    User::set_locked(&mut *tx, user_id.clone(), None).await?;
+    LoginFailure::clear(&mut *tx, user_id.clone()).await?;
+    AuditEvent::new(user_id, AuditAction::AccountUnlocked, AuditOutcome::Success).actor(admin).client_ip(addr)
+        .record(&mut *tx).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Clears the account's password, ends its sessions and sends it a reset email
+#[debug_handler(state = AppState)]
+pub(crate) async fn force_password_reset(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(CurrentUser(admin)): Extension<CurrentUser>,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    User::clear_password(&mut *tx, user_id.clone()).await?;
+    let revoked = Revocation::revoke_for_username(&mut *tx, user_id.clone(), Utc::now().naive_utc()).await?;
+    AuditEvent::new(user_id.clone(), AuditAction::ForcedPasswordReset, AuditOutcome::Success).actor(admin).client_ip(addr)
+        .record(&mut *tx).await?;
+    mailing::request_reset.builder()
+        .set_json(&mailing::ResetDetails{
+            email: user_id,
+        })?
+        .spawn(&mut *tx).await
+        .map_err(crate::db::Error::from)?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+    cache.add(&revoked);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn revoke_sessions(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(CurrentUser(admin)): Extension<CurrentUser>,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id).await?;
+    let revoked = Revocation::revoke_for_username(&db, user.email.clone(), Utc::now().naive_utc()).await?;
+    cache.add(&revoked);
+    AuditEvent::new(user.email, AuditAction::SessionsRevoked, AuditOutcome::Success).actor(admin).client_ip(addr).detail("all")
+        .record_or_warn(&db).await;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,166 @@
+use std::{net::SocketAddr, time::{Duration, SystemTime}};
+
+use axum::{debug_handler, extract::{self, ConnectInfo, Path, State}, response::IntoResponse, Json};
+use chrono::{NaiveDateTime, Utc};
+use hyper::{header, StatusCode};
+use mattak::{condreq, hypermedia::{op, ActionType, ResourceFields}};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{AuditAction, AuditEvent, AuditOutcome, EventId, Revocation, RevocationId},
+    keyring::LONGEST_TOKEN_LIFETIME,
+    resources::delete_op,
+    revocation_cache::RevocationCache,
+    routing::{ApiTokenLocate, ApiTokensLocate, RouteMap},
+    tokens::TokenIssuer,
+    AppState, Error
+};
+
+const ONE_DAY: u64 = 60 * 60 * 24;
+const DEFAULT_DAYS: u64 = 365;
+const MAX_DAYS: u64 = LONGEST_TOKEN_LIFETIME.num_days() as u64;
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ApiTokenListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<ApiTokensLocate>,
+
+    pub api_tokens: Vec<ApiTokenResponse>,
+}
+
+impl ApiTokenListResponse {
+    pub fn from_query(nested_at: &str, user_id: String, list: Vec<Revocation<RevocationId>>) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::ApiTokens.prefixed(nested_at),
+                ApiTokensLocate{ user_id },
+                "api:apiTokensList",
+                vec![ op(ActionType::View), op(ActionType::Add) ]
+            )?,
+            api_tokens: list.into_iter().map(|token|
+                ApiTokenResponse::from_query(nested_at, token))
+                .collect::<Result<_,_>>()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ApiTokenResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<ApiTokenLocate>,
+
+    pub label: Option<String>,
+    pub expires: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiTokenResponse {
+    pub(crate) fn from_query(nested_at: &str, value: Revocation<RevocationId>) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::ApiToken.prefixed(nested_at),
+                ApiTokenLocate{ user_id: value.username, token_id: value.id },
+                "api:apiTokenById",
+                vec![ delete_op() ]
+            )?,
+            label: value.label,
+            expires: value.expires,
+            created_at: value.created_at,
+        })
+    }
+}
+
+/// Omitting `methods` and `eventId` gives a token that can do anything
+/// its owner can, except manage their account.
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ApiTokenRequest {
+    pub label: String,
+    pub expires_in_days: Option<u64>,
+    pub methods: Option<Vec<String>>,
+    pub event_id: Option<EventId>,
+}
+
+impl ApiTokenRequest {
+    pub(crate) fn valid(&self) -> Result<(), mattak::Error> {
+        if self.label.trim().is_empty() {
+            return Err(mattak::Error::InvalidInput("label is required".to_string()))
+        }
+        if self.expires_in_days.is_some_and(|days| days == 0 || days > MAX_DAYS) {
+            return Err(mattak::Error::InvalidInput(format!("tokens last between 1 and {MAX_DAYS} days")))
+        }
+        if self.methods.as_ref().is_some_and(|methods| methods.is_empty()) {
+            return Err(mattak::Error::InvalidInput("methods can't be empty".to_string()))
+        }
+        Ok(())
+    }
+}
+
+/// The token itself is only ever shown in this response
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct CreatedTokenResponse {
+    #[serde(flatten)]
+    pub api_token: ApiTokenResponse,
+    pub token: String,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_list(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let tokens = Revocation::get_labeled_for_username(&db, user_id.clone(), Utc::now().naive_utc()).await?;
+    let resp = ApiTokenListResponse::from_query(nested_at.as_str(), user_id, tokens)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn create(
+    State(db): State<Pool<Postgres>>,
+    State(issuer): State<TokenIssuer>,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>,
+    Json(req): Json<ApiTokenRequest>
+) -> Result<impl IntoResponse, Error> {
+    req.valid()?;
+    let expires = SystemTime::now() + Duration::from_secs(ONE_DAY * req.expires_in_days.unwrap_or(DEFAULT_DAYS));
+    let methods = req.methods.map(|methods| methods.into_iter().map(|m| m.to_uppercase()).collect());
+    let bundle = issuer.api_token(&user_id, &req.label, expires, methods, req.event_id.map(|id| id.to_string()))
+        .map_err(mattak::Error::from)?;
+
+    let rid = bundle.revocation_ids.first().cloned()
+        .ok_or_else(|| -> Error {(StatusCode::INTERNAL_SERVER_ERROR, "token without revocation id").into()})?;
+    let token = Revocation::add_labeled(&db, rid, user_id.clone(), expires, req.label.clone()).await?;
+
+    let api_token = ApiTokenResponse::from_query(nested_at.as_str(), token)?;
+    let location = api_token.resource_fields.id.to_string();
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)], Json(CreatedTokenResponse{
+        api_token,
+        token: bundle.token,
+    })))
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn revoke(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((user_id, token_id)): Path<(String, RevocationId)>
+) -> Result<impl IntoResponse, Error> {
+    match Revocation::revoke_by_id(&db, token_id, user_id.clone(), Utc::now().naive_utc()).await? {
+        Some(revoked) => {
+            cache.add(&[revoked]);
+            AuditEvent::new(user_id.clone(), AuditAction::ApiTokenRevoked, AuditOutcome::Success).actor(user_id).client_ip(addr)
+                .detail(&token_id.to_string())
+                .record_or_warn(&db).await;
+            Ok(StatusCode::NO_CONTENT)
+        },
+        None => Err((StatusCode::NOT_FOUND, "no such token").into())
+    }
+}
@@ -0,0 +1,121 @@
+use axum::{debug_handler, extract::{self, Path, Query, State}, response::IntoResponse};
+use chrono::NaiveDateTime;
+use mattak::{condreq, hypermedia::{op, ActionType, ResourceFields}};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{AuditEvent, AuditEventId},
+    routing::{AuditEventsLocate, EmptyLocate, RouteMap},
+    AppState, Error
+};
+
+// Enough to cover a good while of ordinary use; anything older is for an administrator to dig up
+const LIST_LIMIT: i64 = 200;
+const ADMIN_LIST_LIMIT: i64 = 1000;
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct AuditEventListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<AuditEventsLocate>,
+
+    pub audit_events: Vec<AuditEventResponse>,
+}
+
+impl AuditEventListResponse {
+    pub fn from_query(nested_at: &str, user_id: String, list: Vec<AuditEvent<AuditEventId>>) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::AuditEvents.prefixed(nested_at),
+                AuditEventsLocate{ user_id },
+                "api:auditEventsList",
+                vec![ op(ActionType::View) ]
+            )?,
+            audit_events: list.into_iter().map(AuditEventResponse::from).collect(),
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct AdminAuditEventListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<EmptyLocate>,
+
+    pub audit_events: Vec<AuditEventResponse>,
+}
+
+impl AdminAuditEventListResponse {
+    pub fn from_query(nested_at: &str, list: Vec<AuditEvent<AuditEventId>>) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::AdminAuditEvents.prefixed(nested_at),
+                EmptyLocate{},
+                "api:adminAuditEventsList",
+                vec![ op(ActionType::View) ]
+            )?,
+            audit_events: list.into_iter().map(AuditEventResponse::from).collect(),
+        })
+    }
+}
+
+/// Entries aren't addressed one by one, so they carry no links
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct AuditEventResponse {
+    pub email: String,
+    pub actor: Option<String>,
+    pub action: String,
+    pub outcome: String,
+    pub client_ip: Option<String>,
+    pub detail: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<AuditEvent<AuditEventId>> for AuditEventResponse {
+    fn from(value: AuditEvent<AuditEventId>) -> Self {
+        Self{
+            email: value.email,
+            actor: value.actor,
+            action: value.action,
+            outcome: value.outcome,
+            client_ip: value.client_ip,
+            detail: value.detail,
+            created_at: value.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AuditQuery {
+    pub email: Option<String>,
+    pub action: Option<String>,
+    pub since: Option<NaiveDateTime>,
+}
+
+/// The newest entries about the user's own account
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_list(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let events = AuditEvent::get_for_email(&db, user_id.clone(), LIST_LIMIT).await?;
+    let resp = AuditEventListResponse::from_query(nested_at.as_str(), user_id, events)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+/// The newest entries for anyone, filtered by `email`, `action` and `since`
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_admin_list(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Query(query): Query<AuditQuery>
+) -> Result<impl IntoResponse, Error> {
+    let events = AuditEvent::search(&db, query.email, query.action, query.since, ADMIN_LIST_LIMIT).await?;
+    let resp = AdminAuditEventListResponse::from_query(nested_at.as_str(), events)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
@@ -7,55 +7,147 @@ use axum::{
     debug_handler,
     extract::{self, ConnectInfo, Request, State},
     middleware::Next,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use biscuit_auth::macros::authorizer;
+use base64ct::{Base64, Encoding as _};
+use biscuit_auth::{macros::{authorizer, fact, rule}, AuthorizerBuilder, Biscuit};
 use chrono::Utc;
-use hyper::StatusCode;
-use mattak::biscuits::{AuthContext, Authentication};
+use hyper::{header, HeaderMap, StatusCode};
+use mattak::biscuits::AuthContext;
 use serde::Deserialize;
 use sqlx::{Pool, Postgres};
 use tracing::debug;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
-use crate::{db::{Revocation, User}, mailing, AppState, Error};
+use crate::{db::{AuditAction, AuditEvent, AuditOutcome, EventId, Game, GameId, LoginFailure, Password, Revocation, User, UserId}, keyring::KeyRing, mailing, password_policy::PasswordPolicy, passwords, revocation_cache::RevocationCache, resources::two_factor::{verify_second_factor, SecondFactor}, throttle::LoginThrottle, tokens::TokenIssuer, totp::TotpKey, AppState, Error};
 
-const ONE_WEEK: u64 = 60 * 60 * 24 * 7; // A week
-const PASSWORD_COST: u32 = bcrypt::DEFAULT_COST;
+// Clients refresh well before the access token runs out; anyone idle for a month logs in again
+const ACCESS_LIFETIME: Duration = Duration::from_secs(60 * 60); // An hour
+const REFRESH_LIFETIME: Duration = Duration::from_secs(60 * 60 * 24 * 30); // 30 days
+// Two tabs opening together both refresh with the same token; the loser picks up the winner's from storage
+const REFRESH_REUSE_GRACE: chrono::Duration = chrono::Duration::seconds(30);
+const ACCOUNT_EMAIL_INTERVAL: chrono::Duration = chrono::Duration::minutes(5);
 
 // #[debug_middleware(state = AppState)]
 pub(crate) async fn add_rejections(
     State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
     Extension(authctx): Extension<AuthContext>,
     mut request: Request,
     next: Next
 ) -> Result<impl IntoResponse, Error> {
-    let revocations = Revocation::get_revoked(&db, Utc::now().naive_utc()).await?;
-    let rids = revocations.into_iter().map(|rev| rev.data).collect();
+    let token_rids = authctx.revocation_ids().unwrap_or_default();
+    let rids = match cache.revoked_among(&token_rids) {
+        Some(rids) => rids,
+        None => {
+            debug!("Revocation cache stale; checking database");
+            let revocations = Revocation::get_revoked(&db, Utc::now().naive_utc()).await?;
+            revocations.into_iter().map(|rev| rev.data).collect()
+        }
+    };
     let authctx = authctx.with_revoked_ids(rids);
     request.extensions_mut().insert(authctx);
     Ok(next.run(request).await)
 }
 
+/// Checks the request against the secured policy, along with what only the database knows:
+/// for a game route, `game_event($game, $event)`, so that tokens scoped to an event can reach its games,
+/// and for an event route, `organizer($user, $event)` for each of its organizers
+pub(crate) async fn authorize(
+    State(db): State<Pool<Postgres>>,
+    Extension(authctx): Extension<AuthContext>,
+    params: extract::RawPathParams,
+    request: Request,
+    next: Next
+) -> Result<Response, Error> {
+    let mut policy = crate::secured_policy();
+    let game_id = params.iter()
+        .find_map(|(key, value)| (key == "game_id").then(|| value.parse::<i64>().ok()).flatten());
+    if let Some(game_id) = game_id {
+        if let Some(event_id) = Game::event_of(&db, GameId::from(game_id)).await? {
+            let (game_id, event_id) = (game_id.to_string(), event_id.to_string());
+            policy = policy.fact(fact!("game_event({game_id}, {event_id})"))?;
+        }
+    }
+    let event_id = params.iter()
+        .find_map(|(key, value)| (key == "event_id").then(|| value.parse::<i64>().ok()).flatten());
+    if let Some(event_id) = event_id {
+        for organizer in User::get_organizers_by_event_id(&db, EventId::from(event_id)).await? {
+            let (email, event) = (organizer.email, event_id.to_string());
+            policy = policy.fact(fact!("organizer({email}, {event})"))?;
+        }
+    }
+    if let Err(error) = authctx.check(policy) {
+        return Ok(error.into_response())
+    }
+    Ok(next.run(request).await)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ShareQuery {
+    share: Option<String>,
+    token: Option<String>,
+}
+
+/// Links to events carry their token in the `share` query parameter, and calendar feeds in `token`,
+/// which is moved into the Authorization header if there isn't one already
+pub(crate) async fn share_from_query(
+    query: Option<extract::Query<ShareQuery>>,
+    mut request: Request,
+    next: Next
+) -> Result<impl IntoResponse, Error> {
+    if !request.headers().contains_key(header::AUTHORIZATION) {
+        if let Some(token) = query.and_then(|extract::Query(ShareQuery{ share, token })| share.or(token)) {
+            let value = header::HeaderValue::from_str(&token)
+                .map_err(|_| -> Error {(StatusCode::BAD_REQUEST, "malformed share token").into()})?;
+            request.headers_mut().insert(header::AUTHORIZATION, value);
+        }
+    }
+    Ok(next.run(request).await)
+}
+
+/// The user named by the request's (already authorized) token
+#[derive(Clone, Debug)]
+pub(crate) struct CurrentUser(pub String);
+
+pub(crate) async fn add_current_user(
+    State(keys): State<KeyRing>,
+    mut request: Request,
+    next: Next
+) -> Result<impl IntoResponse, Error> {
+    if let Some(header) = request.headers().get(header::AUTHORIZATION) {
+        let token = Biscuit::from_base64(header.as_bytes(), keys)?;
+        let users: Vec<(String,)> = AuthorizerBuilder::new().build(&token)?
+            .query(rule!("data($user) <- user($user)"))?;
+        if let Some((email,)) = users.into_iter().next() {
+            request.extensions_mut().insert(CurrentUser(email));
+        }
+    }
+    Ok(next.run(request).await)
+}
+
 #[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
 pub(crate) struct AuthnRequest {
-    pub password: String
+    pub password: String,
+    #[serde(flatten)]
+    pub second_factor: SecondFactor,
+}
+
+/// Proof that the user at the keyboard is the one logged in, asked for before changes that would outlast the session
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct Reauthentication {
+    pub password: String,
+    #[serde(flatten)]
+    pub second_factor: SecondFactor,
 }
 
 #[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
 pub(crate) struct AuthnUpdateRequest {
     pub old_password: Option<String>,
+    #[serde(flatten)]
+    pub second_factor: SecondFactor,
     pub new_password: String
 }
-impl AuthnUpdateRequest {
-    pub(crate) fn valid(&self) -> Result<(), mattak::Error> {
-        debug!("Checking length of password");
-        if self.new_password.len() < 12 {
-            return Err(mattak::Error::InvalidInput("password less than 12 characters".to_string()))
-        }
-        Ok(())
-    }
-}
 
 #[derive(Deserialize)]
 #[serde(rename_all="camelCase")]
@@ -68,45 +160,280 @@ pub(crate) struct RegisterRequest {
 // has to fetch a user
 // has to fetch revocations
 #[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)] // extractors
 pub(crate) async fn authenticate(
     State(db): State<Pool<Postgres>>,
-    State(auth): State<Authentication>,
+    State(issuer): State<TokenIssuer>,
+    State(totp_key): State<TotpKey>,
+    State(throttle): State<LoginThrottle>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     extract::Path(email): extract::Path<String>,
     Json(authreq): Json<AuthnRequest>
-) -> Result<impl IntoResponse, Error> {
+) -> Result<Response, Error> {
     mailing::cleanup_revocations.builder()
         .spawn(&db).await
         .map_err(crate::db::Error::from)?;
+
+    // Failures are tracked by the email tried, known or not, so throttling doesn't reveal accounts either
+    let tried = email.clone();
+    if let Some(wait) = throttled(&db, &throttle, tried.clone(), addr, "password").await? {
+        return Ok(wait)
+    }
     debug!("Attempting to verify user password");
 
-    let cant_match = bcrypt::hash(format!("busy {} work", authreq.password), PASSWORD_COST)?.into();
+    let cant_match: Password = passwords::hash(&format!("busy {} work", authreq.password))?.into();
 
     // Always proceed, to reduce the ability of an attacker to use this as an email oracle
-    let (email, encrypted_password) = match User::by_email(&db, email.clone()).await {
-        Ok(u) => (u.email, u.encrypted_password),
-        Err(_) => ("nobody@nowhere.com".to_string(), cant_match)
+    let user = User::by_email(&db, email.clone()).await.ok();
+    let (email, encrypted_password, is_admin, locked) = match &user {
+        Some(u) => (u.email.clone(), &u.encrypted_password, u.is_admin, u.locked_at.is_some()),
+        None => ("nobody@nowhere.com".to_string(), &cant_match, false, false)
     };
 
-    // XXX bcrypt ONLY for back-compatibility with Rails
-    // change to a real KDF in future
-    if bcrypt::verify(authreq.password.clone(), encrypted_password.as_ref())? {
+    // Checked after verifying, so a locked account takes as long to reject as a bad password
+    if passwords::verify(&authreq.password, encrypted_password)? && !locked {
         debug!("Successfully verified password");
-        let expires = SystemTime::now() + Duration::from_secs(ONE_WEEK);
-        let bundle = auth.authority(&email, expires, Some(addr)).map_err(mattak::Error::from)?;
-
-        let _ = Revocation::add_batch(&db, bundle.revocation_ids, email.clone(), expires).await?;
-        Ok(([("set-authorization", bundle.token)], StatusCode::NO_CONTENT))
+        if let Some(user) = user.as_ref().filter(|u| passwords::needs_rehash(&u.encrypted_password)) {
+            debug!("Rehashing legacy password");
+            user.update_password(&db, passwords::hash(&authreq.password)?).await?;
+        }
+        if let Some(user) = &user {
+            check_second_factor(&db, &totp_key, &throttle, user, &authreq.second_factor, addr).await?;
+        }
+        LoginFailure::clear(&db, tried).await?;
+        Ok(start_session(&db, &issuer, email, is_admin, addr, &headers, "password").await?.into_response())
     } else {
-        Err((StatusCode::FORBIDDEN, "Authorization rejected").into())
+        Err(record_failure(&db, &throttle, tried, addr, "password").await?)
+    }
+}
+
+/// A response asking the client to wait, if the email has failed to log in too often lately.
+/// `method` is how the user tried to log in, for the audit log.
+pub(crate) async fn throttled(db: &Pool<Postgres>, throttle: &LoginThrottle, tried: String, addr: SocketAddr, method: &str) -> Result<Option<Response>, Error> {
+    let now = Utc::now().naive_utc();
+    let Some(wait) = LoginFailure::get(db, tried.clone()).await?.and_then(|failure| throttle.wait(&failure, now)) else {
+        return Ok(None)
+    };
+    AuditEvent::new(tried, AuditAction::Login, AuditOutcome::Throttled).client_ip(addr).detail(method)
+        .record_or_warn(db).await;
+    Ok(Some((StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, wait.as_secs().max(1).to_string())]).into_response()))
+}
+
+/// Checks the password and any second factor of a user who already has a session.
+/// Mistakes count as failed logins, so a stolen session can't be used to guess the password.
+pub(crate) async fn reauthenticate(
+    db: &Pool<Postgres>,
+    totp_key: &TotpKey,
+    throttle: &LoginThrottle,
+    user: &User<UserId>,
+    reauth: &Reauthentication,
+    addr: SocketAddr
+) -> Result<(), Error> {
+    if throttled(db, throttle, user.email.clone(), addr, "reauthentication").await?.is_some() {
+        return Err((StatusCode::TOO_MANY_REQUESTS, "too many failed attempts; try again later").into())
+    }
+    if !passwords::verify(&reauth.password, &user.encrypted_password)? {
+        return Err(record_failure(db, throttle, user.email.clone(), addr, "password").await?)
+    }
+    check_second_factor(db, totp_key, throttle, user, &reauth.second_factor, addr).await
+}
+
+/// Checks any second factor, counting a wrong one as a failed login, like a wrong password
+pub(crate) async fn check_second_factor(
+    db: &Pool<Postgres>,
+    totp_key: &TotpKey,
+    throttle: &LoginThrottle,
+    user: &User<UserId>,
+    factor: &SecondFactor,
+    addr: SocketAddr
+) -> Result<(), Error> {
+    match verify_second_factor(db, totp_key, user, factor, SystemTime::now()).await {
+        Err(Error::StatusCode(StatusCode::FORBIDDEN, _)) => Err(record_failure(db, throttle, user.email.clone(), addr, "second_factor").await?),
+        result => result
+    }
+}
+
+/// Counts a failed login, locking the email out and telling its owner once there have been too many.
+/// `failed` names what didn't match, for the audit log.
+/// Produces the rejection to respond with.
+pub(crate) async fn record_failure(db: &Pool<Postgres>, throttle: &LoginThrottle, tried: String, addr: SocketAddr, failed: &str) -> Result<Error, Error> {
+    AuditEvent::new(tried.clone(), AuditAction::Login, AuditOutcome::Failure).client_ip(addr).detail(failed)
+        .record_or_warn(db).await;
+    let failure = LoginFailure::record(db, tried.clone(), Utc::now().naive_utc()).await?;
+    if throttle.should_lock(&failure) {
+        let until = failure.last_failed_at + throttle.lock_for;
+        LoginFailure::lock(db, tried.clone(), until).await?;
+        AuditEvent::new(tried.clone(), AuditAction::LoginLock, AuditOutcome::Success).client_ip(addr)
+            .record_or_warn(db).await;
+        mailing::notify_login_lock.builder()
+            .set_json(&mailing::LoginLockDetails{
+                email: tried,
+                until,
+            })?
+            .spawn(db).await
+            .map_err(crate::db::Error::from)?;
+    }
+    Ok((StatusCode::FORBIDDEN, "Authorization rejected").into())
+}
+
+/// Starts a session: an hour-long access token and a 30-day refresh token, both recorded so that they can be revoked.
+/// `method` is how the user logged in, for the audit log.
+pub(crate) async fn start_session(
+    db: &Pool<Postgres>,
+    issuer: &TokenIssuer,
+    email: String,
+    is_admin: bool,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    method: &str
+) -> Result<impl IntoResponse, Error> {
+    AuditEvent::new(email.clone(), AuditAction::Login, AuditOutcome::Success).actor(email.clone()).client_ip(addr).detail(method)
+        .record_or_warn(db).await;
+    issue_session_tokens(db, issuer, email, is_admin, None, addr, headers).await
+}
+
+/// Issues an access token and a refresh token for a session.
+/// A new session's chain is named for its first refresh token.
+async fn issue_session_tokens(
+    db: &Pool<Postgres>,
+    issuer: &TokenIssuer,
+    email: String,
+    is_admin: bool,
+    chain: Option<String>,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+) -> Result<impl IntoResponse, Error> {
+    let refresh_expires = SystemTime::now() + REFRESH_LIFETIME;
+    let refresh = issuer.refresh_token(&email, refresh_expires).map_err(mattak::Error::from)?;
+    let access_expires = SystemTime::now() + ACCESS_LIFETIME;
+    let access = issuer.authority(&email, is_admin, access_expires, Some(addr)).map_err(mattak::Error::from)?;
+    let chain = chain.unwrap_or_else(|| refresh.revocation_ids[0].clone());
+
+    let clienthint = headers.get(header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .map(String::from);
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    let _ = Revocation::add_session_refresh(&mut *tx, refresh.revocation_ids, email.clone(), refresh_expires, chain.clone(),
+        clienthint, Some(addr.ip().to_string())).await?;
+    let _ = Revocation::add_session_access(&mut *tx, access.revocation_ids, email, access_expires, chain).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+    Ok(([("set-authorization", access.token), ("set-refresh-token", refresh.token)], StatusCode::NO_CONTENT))
+}
+
+/// Exchanges a refresh token for a new access token and refresh token in the same session.
+/// Each refresh token works once; one presented again, other than right after its use, revokes its whole session.
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)] // extractors
+pub(crate) async fn refresh_session(
+    State(db): State<Pool<Postgres>>,
+    State(issuer): State<TokenIssuer>,
+    State(keys): State<KeyRing>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    extract::Path(email): extract::Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let rejected = || Error::from((StatusCode::UNAUTHORIZED, "Authorization rejected"));
+    let token = headers.get(header::AUTHORIZATION)
+        .and_then(|header| Biscuit::from_base64(header.as_bytes(), keys).ok())
+        .ok_or_else(rejected)?;
+    let now = SystemTime::now();
+    authorizer!(r#"time({now}); allow if refresh_token({user_id});"#, user_id = email.clone())
+        .build(&token).and_then(|mut authorizer| authorizer.authorize())
+        .map_err(|_| rejected())?;
+
+    let user = User::by_email(&db, email).await?;
+    let rids: Vec<String> = token.revocation_identifiers().into_iter()
+        .map(|rid| Base64::encode_string(&rid))
+        .collect();
+    if user.locked_at.is_some() {
+        AuditEvent::new(user.email.clone(), AuditAction::SessionRefresh, AuditOutcome::Failure).client_ip(addr).detail("locked")
+            .record_or_warn(&db).await;
+        return Err((StatusCode::FORBIDDEN, "Authorization rejected").into())
     }
+
+    let consumed = Revocation::consume(&db, rids.clone(), Utc::now().naive_utc()).await?;
+    let Some(chain) = consumed.into_iter().find_map(|rev| rev.session_chain) else {
+        let now = Utc::now().naive_utc();
+        let revoked = Revocation::revoke_chain_of(&db, rids, now - REFRESH_REUSE_GRACE, now).await?;
+        if !revoked.is_empty() {
+            cache.add(&revoked);
+            AuditEvent::new(user.email.clone(), AuditAction::SessionRefresh, AuditOutcome::Failure).client_ip(addr).detail("reused_refresh_token")
+                .record_or_warn(&db).await;
+        }
+        return Err(rejected())
+    };
+
+    issue_session_tokens(&db, &issuer, user.email.clone(), user.is_admin, Some(chain), addr, &headers).await
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn request_login_link(
+    State(db): State<Pool<Postgres>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    extract::Path(email): extract::Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    AuditEvent::new(email.clone(), AuditAction::LoginLinkRequested, AuditOutcome::Success).client_ip(addr)
+        .record_or_warn(&db).await;
+    mailing::request_login_link.builder()
+      .set_json(&mailing::LoginLinkDetails{
+            email: email.clone(),
+        })?
+        .spawn(&db).await
+        .map_err(crate::db::Error::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Exchanges a login link token (which can only be used once) for a session
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)] // extractors
+pub(crate) async fn login_with_link(
+    State(db): State<Pool<Postgres>>,
+    State(issuer): State<TokenIssuer>,
+    State(totp_key): State<TotpKey>,
+    State(throttle): State<LoginThrottle>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(authctx): Extension<AuthContext>,
+    extract::Path(email): extract::Path<String>,
+    second_factor: Option<Json<SecondFactor>>,
+) -> Result<Response, Error> {
+    let user = User::by_email(&db, email).await?;
+    if let Some(wait) = throttled(&db, &throttle, user.email.clone(), addr, "login_link").await? {
+        return Ok(wait)
+    }
+    if user.locked_at.is_some() {
+        AuditEvent::new(user.email.clone(), AuditAction::Login, AuditOutcome::Failure).client_ip(addr).detail("locked")
+            .record_or_warn(&db).await;
+        return Err((StatusCode::FORBIDDEN, "Authorization rejected").into())
+    }
+    // Checked first, so that a missing code doesn't use up the link
+    let second_factor = second_factor.map(|Json(sf)| sf).unwrap_or_default();
+    check_second_factor(&db, &totp_key, &throttle, &user, &second_factor, addr).await?;
+
+    let rids = authctx.revocation_ids().unwrap_or_default();
+    let consumed = Revocation::consume(&db, rids, Utc::now().naive_utc()).await?;
+    if consumed.is_empty() {
+        AuditEvent::new(user.email.clone(), AuditAction::Login, AuditOutcome::Failure).client_ip(addr).detail("used_login_link")
+            .record_or_warn(&db).await;
+        return Err((StatusCode::UNAUTHORIZED, "login link already used").into())
+    }
+    cache.add(&consumed);
+
+    Ok(start_session(&db, &issuer, user.email.clone(), user.is_admin, addr, &headers, "login_link").await?.into_response())
 }
 
 #[debug_handler(state = AppState)]
 pub(crate) async fn reset_password(
     State(db): State<Pool<Postgres>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     extract::Path(email): extract::Path<String>,
 ) -> Result<impl IntoResponse, Error> {
+    AuditEvent::new(email.clone(), AuditAction::PasswordResetRequested, AuditOutcome::Success).client_ip(addr)
+        .record_or_warn(&db).await;
     mailing::request_reset.builder()
       .set_json(&mailing::ResetDetails{
             email: email.clone(),
@@ -118,55 +445,171 @@ pub(crate) async fn reset_password(
 }
 
 // XXX Need to limit regs/IP
+/// Always succeeds the same way, so that it doesn't reveal who has an account.
+/// Someone registering an existing email gets told so by email instead.
 #[debug_handler(state = AppState)]
 pub(crate) async fn register(
     State(db): State<Pool<Postgres>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     extract::Path(email): extract::Path<String>,
     Json(regreq): Json<RegisterRequest>
 ) -> Result<impl IntoResponse, Error> {
-    User::create(&db, &email, &regreq.name, &regreq.bgg_username).await?;
-    mailing::request_registration.builder()
-      .set_json(&mailing::RegistrationDetails{
-            email: email.clone(),
-        })?
-        .spawn(&db).await
-        .map_err(crate::db::Error::from)?;
+    let created = User::create(&db, &email, &regreq.name, &regreq.bgg_username).await?.is_some();
+    AuditEvent::new(email.clone(), AuditAction::Registration, AuditOutcome::Success).client_ip(addr)
+        .detail(if created { "created" } else { "existing_account" })
+        .record_or_warn(&db).await;
+    let now = Utc::now().naive_utc();
+    if !User::claim_account_email(&db, email.clone(), now, now - ACCOUNT_EMAIL_INTERVAL).await? {
+        return Ok(StatusCode::NO_CONTENT)
+    }
+
+    let details = mailing::RegistrationDetails{ email: email.clone() };
+    if created {
+        mailing::request_registration.builder().set_json(&details)?.spawn(&db).await
+    } else {
+        mailing::notify_existing_account.builder().set_json(&details)?.spawn(&db).await
+    }.map_err(crate::db::Error::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sends the registration email again, at most once every few minutes.
+/// Like `register`, it succeeds whether or not there's an account to send to.
+#[debug_handler(state = AppState)]
+pub(crate) async fn resend_verification(
+    State(db): State<Pool<Postgres>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    extract::Path(email): extract::Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    AuditEvent::new(email.clone(), AuditAction::VerificationRequested, AuditOutcome::Success).client_ip(addr)
+        .record_or_warn(&db).await;
+    let unverified = User::by_email(&db, email.clone()).await
+        .is_ok_and(|user| user.email_verified_at.is_none());
+    let now = Utc::now().naive_utc();
+    if unverified && User::claim_account_email(&db, email.clone(), now, now - ACCOUNT_EMAIL_INTERVAL).await? {
+        mailing::request_registration.builder()
+            .set_json(&mailing::RegistrationDetails{
+                email: email.clone(),
+            })?
+            .spawn(&db).await
+            .map_err(crate::db::Error::from)?;
+    }
 
     Ok(StatusCode::NO_CONTENT)
 }
 
 #[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)] // extractors
 pub(crate) async fn update_credentials(
     State(db): State<Pool<Postgres>>,
+    State(policy): State<PasswordPolicy>,
+    State(cache): State<RevocationCache>,
+    State(totp_key): State<TotpKey>,
+    State(throttle): State<LoginThrottle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     extract::Path(email): extract::Path<String>,
     Extension(auth): Extension<AuthContext>,
     Json(authreq): Json<AuthnUpdateRequest>
 ) -> Result<impl IntoResponse, Error> {
-    authreq.valid()?;
     let user = User::by_email(&db, email.clone()).await?;
+    let user_inputs = [Some(user.email.as_str()), user.name.as_deref(), user.bgg_username.as_deref()];
+    policy.check(&authreq.new_password, &user_inputs.into_iter().flatten().collect::<Vec<_>>())?;
 
     let rejected = || -> Error {(StatusCode::FORBIDDEN, "Authorization rejected").into()};
-    if !(auth.check(authorizer!(r#"allow if reset_password({user_id});"#, user_id = email.clone())).is_ok() ||
-        bcrypt::verify(authreq.old_password.clone().ok_or_else(rejected)?, user.encrypted_password.as_ref())?) {
-        return Err(rejected())
+    let by_email_link = auth.check(authorizer!(r#"allow if reset_password({user_id});"#, user_id = email.clone())).is_ok();
+    let audit = |outcome| AuditEvent::new(email.clone(), AuditAction::PasswordChange, outcome)
+        .actor(email.clone()).client_ip(addr).detail(if by_email_link { "email_link" } else { "old_password" });
+    if !by_email_link {
+        let Some(old_password) = authreq.old_password.clone() else {
+            audit(AuditOutcome::Failure).record_or_warn(&db).await;
+            return Err(rejected())
+        };
+        let reauth = Reauthentication{
+            password: old_password,
+            second_factor: SecondFactor{ code: authreq.second_factor.code.clone(), recovery_code: authreq.second_factor.recovery_code.clone() },
+        };
+        if let Err(error) = reauthenticate(&db, &totp_key, &throttle, &user, &reauth, addr).await {
+            audit(AuditOutcome::Failure).record_or_warn(&db).await;
+            return Err(error)
+        }
+    }
+    // Registration and reset links are emailed, so following one shows the address works
+    if by_email_link {
+        User::verify_email(&db, email.clone(), Utc::now().naive_utc()).await?;
     }
 
-    let hashed = bcrypt::hash(authreq.new_password.clone(), PASSWORD_COST)?;
-    Revocation::revoke_for_username(&db, email.clone(), Utc::now().naive_utc()).await?;
+    let hashed = passwords::hash(&authreq.new_password)?;
+    let revoked = Revocation::revoke_for_username(&db, email.clone(), Utc::now().naive_utc()).await?;
+    cache.add(&revoked);
     user.update_password(&db, hashed).await?;
+    audit(AuditOutcome::Success).record_or_warn(&db).await;
     Ok(StatusCode::NO_CONTENT)
 }
 
 #[debug_handler(state = AppState)]
 pub(crate) async fn revoke(
     State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Extension(authctx): Extension<AuthContext>,
+    extract::Path(email): extract::Path<String>,
 ) -> Result<impl IntoResponse, Error> {
     match authctx.revocation_ids() {
         None => Err((StatusCode::NOT_FOUND, "No authorization to revoke").into()),
         Some(revocation_ids) => {
-            Revocation::revoke(&db, revocation_ids, Utc::now().naive_utc()).await?;
+            let revoked = Revocation::revoke(&db, revocation_ids, Utc::now().naive_utc()).await?;
+            cache.add(&revoked);
+            AuditEvent::new(email.clone(), AuditAction::Logout, AuditOutcome::Success).actor(email).client_ip(addr)
+                .record_or_warn(&db).await;
             Ok(StatusCode::NO_CONTENT)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_reauthenticate(pool: Pool<Postgres>) {
+        let key = TotpKey::generate();
+        let throttle = LoginThrottle{ threshold: 10, lock_for: Duration::from_secs(15 * 60) };
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000));
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap().expect("a new user");
+        testy.update_password(&pool, passwords::hash("correct horse").unwrap()).await.unwrap();
+        let testy = User::by_email(&pool, testy.email.clone()).await.unwrap();
+
+        let wrong = Reauthentication{ password: "battery staple".to_string(), second_factor: SecondFactor::default() };
+        assert!(matches!(reauthenticate(&pool, &key, &throttle, &testy, &wrong, addr).await,
+            Err(Error::StatusCode(StatusCode::FORBIDDEN, _))));
+        let failure = LoginFailure::get(&pool, testy.email.clone()).await.unwrap().expect("a failure");
+        assert_eq!(failure.failures, 1, "a wrong password counts as a failed login");
+
+        let right = Reauthentication{ password: "correct horse".to_string(), second_factor: SecondFactor::default() };
+        reauthenticate(&pool, &key, &throttle, &testy, &right, addr).await.expect("the password to pass");
+    }
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_check_second_factor(pool: Pool<Postgres>) {
+        let key = TotpKey::generate();
+        let throttle = LoginThrottle{ threshold: 10, lock_for: Duration::from_secs(15 * 60) };
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000));
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap().expect("a new user");
+        User::set_totp_secret(&pool, testy.email.clone(), key.encrypt(&crate::totp::generate_secret()).unwrap()).await.unwrap();
+        User::confirm_totp(&pool, testy.email.clone(), 0, Utc::now().naive_utc()).await.unwrap();
+        let testy = User::by_email(&pool, testy.email.clone()).await.unwrap();
+
+        let missing = SecondFactor::default();
+        assert!(matches!(check_second_factor(&pool, &key, &throttle, &testy, &missing, addr).await,
+            Err(Error::StatusCode(StatusCode::UNAUTHORIZED, _))));
+        assert!(LoginFailure::get(&pool, testy.email.clone()).await.unwrap().is_none(), "asking for the code isn't a failure");
+
+        let wrong = SecondFactor{ code: None, recovery_code: Some("not-a-code".to_string()) };
+        assert!(matches!(check_second_factor(&pool, &key, &throttle, &testy, &wrong, addr).await,
+            Err(Error::StatusCode(StatusCode::FORBIDDEN, _))));
+        let failure = LoginFailure::get(&pool, testy.email.clone()).await.unwrap().expect("a failure");
+        assert_eq!(failure.failures, 1, "a wrong second factor counts as a failed login");
+    }
+}
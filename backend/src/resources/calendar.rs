@@ -0,0 +1,68 @@
+use std::time::{Duration, SystemTime};
+
+use axum::{debug_handler, extract::{self, Path, State}, response::IntoResponse, Json};
+use hyper::{header, StatusCode};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{Event, Revocation},
+    ical,
+    keyring::LONGEST_TOKEN_LIFETIME,
+    resources::api_token::ApiTokenResponse,
+    routing::{CalendarFeedLocate, RouteMap},
+    tokens::TokenIssuer,
+    AppState, Error
+};
+
+const ONE_DAY: u64 = 60 * 60 * 24;
+// Feeds last as long as a token can, since calendar apps hold on to them
+const FEED_DAYS: u64 = LONGEST_TOKEN_LIFETIME.num_days() as u64;
+const FEED_LABEL: &str = "calendar feed";
+
+/// The token is only ever shown in this response, already in the feed's URL for pasting into a calendar app
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct CreatedFeedResponse {
+    #[serde(flatten)]
+    pub api_token: ApiTokenResponse,
+    pub token: String,
+    pub feed: String,
+}
+
+/// The events the user is interested in, as iCalendar
+#[debug_handler(state = AppState)]
+pub(crate) async fn get(
+    State(db): State<Pool<Postgres>>,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let events = Event::get_for_attendee(&db, user_id).await?;
+    Ok(ical::respond("Wag the Pig", &events))
+}
+
+/// Issues a read-only token for the feed. It's listed, and can be revoked, with the user's API tokens.
+#[debug_handler(state = AppState)]
+pub(crate) async fn create(
+    State(db): State<Pool<Postgres>>,
+    State(issuer): State<TokenIssuer>,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let expires = SystemTime::now() + Duration::from_secs(ONE_DAY * FEED_DAYS);
+    let bundle = issuer.calendar_feed(&user_id, FEED_LABEL, expires)
+        .map_err(mattak::Error::from)?;
+
+    let rid = bundle.revocation_ids.first().cloned()
+        .ok_or_else(|| -> Error {(StatusCode::INTERNAL_SERVER_ERROR, "token without revocation id").into()})?;
+    let token = Revocation::add_labeled(&db, rid, user_id.clone(), expires, FEED_LABEL.to_string()).await?;
+
+    let feed_path = RouteMap::CalendarFeed.prefixed(nested_at.as_str())
+        .fill(CalendarFeedLocate{ user_id })?;
+    let api_token = ApiTokenResponse::from_query(nested_at.as_str(), token)?;
+    let location = api_token.resource_fields.id.to_string();
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)], Json(CreatedFeedResponse{
+        api_token,
+        feed: format!("{feed_path}?token={}", bundle.token),
+        token: bundle.token,
+    })))
+}
@@ -1,19 +1,22 @@
 use std::collections::HashMap;
 
-use axum::{debug_handler, extract::{self, Path, State}, response::IntoResponse, Json};
-use chrono::{DateTime, NaiveDateTime, Utc};
-use hyper::{header, StatusCode};
-use mattak::{condreq, hypermedia::{op, ActionType, IriTemplate, Link, ResourceFields}};
+use axum::{debug_handler, extract::{self, Path, Query, State}, response::{IntoResponse, Response}, Extension, Json};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use hyper::{header, HeaderMap, StatusCode};
+use mattak::{condreq, hypermedia::{op, ActionType, IriTemplate, Link, ResourceFields}, routing::FillPolicy};
 use serde::{Deserialize, Serialize};
 use sqlx::{Pool, Postgres};
 use tracing::debug;
 
 use crate::{
-    db::{Event, EventId, NoId},
-    routing::{EmptyLocate, EventLocate, EventUsersLocate},
-    AppState, Error, RouteMap
+    db::{Event, EventFilter, EventId, Game, Interest, NoId},
+    resources::{authentication::CurrentUser, delete_op, PartialCollectionView},
+    routing::{EmptyLocate, EventArchiveLocate, EventLocate, EventOrganizersLocate, EventRsvpsLocate, EventSearchLocate, EventSharesLocate, EventUsersLocate, EventWhen, SortOrder},
+    ical, AppState, Error, RouteMap
 };
 
+const MAX_PAGE_SIZE: u32 = 200;
 
 #[derive(Serialize,Clone)]
 #[serde(rename_all="camelCase")]
@@ -21,13 +24,20 @@ pub(crate) struct EventListResponse {
     #[serde(flatten)]
     pub resource_fields: ResourceFields<EmptyLocate>,
 
+    pub search: IriTemplate,
+    pub view: PartialCollectionView,
     pub event_by_id: IriTemplate,
     pub event_games_by_id: IriTemplate,
     pub events: Vec<EventResponse>,
 }
 
 impl EventListResponse {
-    pub fn from_query(nested_at: &str, list: Vec<Event<EventId>>) -> Result<Self, mattak::Error> {
+    /// `query` has its page and page size settled, or neither for the whole list; `more` says whether there's a page after this one
+    pub fn from_query(nested_at: &str, query: EventSearchLocate, list: Vec<Event<EventId>>, more: bool) -> Result<Self, mattak::Error> {
+        let search_route = RouteMap::EventSearch.prefixed(nested_at);
+        let page = query.page.unwrap_or(1);
+        let page_link = |page| search_route.serialize(FillPolicy::Strict, EventSearchLocate{ page: query.page_size.map(|_| page), ..query.clone() });
+
         let event_route = RouteMap::Event.prefixed(nested_at);
         let event_tmpl = event_route.template()?;
 
@@ -40,6 +50,17 @@ impl EventListResponse {
                 "api:eventsList",
                 vec![ op(ActionType::View), op(ActionType::Add) ]
             )?,
+            search: IriTemplate {
+                id: "api:eventSearch".try_into()?,
+                template: search_route.template()?,
+                operation: vec![ op(ActionType::Find) ]
+            },
+            view: PartialCollectionView::new(
+                page_link(page)?,
+                page_link(1)?,
+                more.then(|| page_link(page + 1)).transpose()?,
+                (page > 1).then(|| page_link(page - 1)).transpose()?,
+            ),
             event_by_id: IriTemplate {
                 id: "api:eventByIdTemplate".try_into()?,
                 template: event_tmpl,
@@ -64,15 +85,30 @@ pub(crate) struct EventResponse {
     pub resource_fields: ResourceFields<EventLocate>,
     pub games: IriTemplate,
     pub users: Link,
+    pub rsvps: Link,
+    pub organizers: Link,
+    pub shares: Link,
+    pub archive: Link,
 
     pub name: Option<String>,
-    pub time: Option<NaiveDateTime>,
+    /// Start and end are given at their offset in the event's time zone
+    pub time: Option<DateTime<FixedOffset>>,
+    pub end_time: Option<DateTime<FixedOffset>>,
+    pub time_zone: String,
     pub location: Option<String>,
-    pub description: Option<String>
+    pub description: Option<String>,
+    pub archived_at: Option<NaiveDateTime>
+}
+
+/// An event's UTC time, at its offset in `zone`
+pub(crate) fn zoned(time: NaiveDateTime, zone: Tz) -> DateTime<FixedOffset> {
+    time.and_utc().with_timezone(&zone).fixed_offset()
 }
 
 impl EventResponse {
     pub(crate) fn from_query(nested_at: &str, value: Event<EventId>) -> Result<Self, mattak::Error> {
+        // Zones are checked on the way in, but one could have been dropped from the database since
+        let zone: Tz = value.time_zone.parse().unwrap_or(Tz::UTC);
         let mut event_var = HashMap::new();
         event_var.insert("event_id".to_string(), value.id.to_string());
         let usergames_tmpl = RouteMap::EventGames.prefixed(nested_at).partial_fill(event_var)?;
@@ -81,7 +117,7 @@ impl EventResponse {
                 &RouteMap::Event.prefixed(nested_at),
                 EventLocate{ event_id: value.id },
                 "api:eventByIdTemplate",
-                vec![ op(ActionType::View), op(ActionType::Update)]
+                vec![ op(ActionType::View), op(ActionType::Update), delete_op() ]
             )?,
 
             games: IriTemplate {
@@ -93,11 +129,30 @@ impl EventResponse {
                 id: RouteMap::EventUsers.prefixed(nested_at).fill(EventUsersLocate{ event_id: value.id })?,
                 operation: vec![ op(ActionType::View) ]
             },
+            rsvps: Link {
+                id: RouteMap::EventRsvps.prefixed(nested_at).fill(EventRsvpsLocate{ event_id: value.id })?,
+                operation: vec![ op(ActionType::View) ]
+            },
+            organizers: Link {
+                id: RouteMap::EventOrganizers.prefixed(nested_at).fill(EventOrganizersLocate{ event_id: value.id })?,
+                operation: vec![ op(ActionType::View) ]
+            },
+            shares: Link {
+                id: RouteMap::EventShares.prefixed(nested_at).fill(EventSharesLocate{ event_id: value.id })?,
+                operation: vec![ op(ActionType::View), op(ActionType::Add) ]
+            },
+            archive: Link {
+                id: RouteMap::EventArchive.prefixed(nested_at).fill(EventArchiveLocate{ event_id: value.id })?,
+                operation: vec![ op(ActionType::Create), delete_op() ]
+            },
 
             name: value.name,
             location: value.r#where,
-            time: value.date,
-            description: value.description.clone()
+            time: value.date.map(|date| zoned(date, zone)),
+            end_time: value.ends_at.map(|ends_at| zoned(ends_at, zone)),
+            time_zone: zone.name().to_string(),
+            description: value.description.clone(),
+            archived_at: value.archived_at
         })
     }
 }
@@ -107,35 +162,134 @@ impl EventResponse {
 pub(crate) struct EventUpdateRequest {
     pub name: Option<String>,
     pub time: Option<DateTime<Utc>>,
+    /// Conventions can run over several days. Left out, an update keeps the stored end; `null` clears it.
+    #[serde(default, deserialize_with = "present")]
+    pub end_time: Option<Option<DateTime<Utc>>>,
+    /// An IANA name, like "America/Chicago". Left out, an update keeps the stored zone, and a new event is in UTC.
+    pub time_zone: Option<Tz>,
     pub location: Option<String>,
     pub description: Option<String>
 }
 
+/// Tells a field given as `null` (`Some(None)`) from one left out (`None`, by `serde(default)`)
+fn present<'de, D: serde::Deserializer<'de>, T: Deserialize<'de>>(deserializer: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
 #[test]
 fn deserialize_event_update_request() {
     let _eur: EventUpdateRequest = serde_json::from_str(r#"{"name": "Testy", "time": "1970-01-01T00:00:00.000Z", "location": "Somewhere"}"#).expect("to deserialize");
 }
 
+#[test]
+fn event_times_keep_their_zone() {
+    let eur: EventUpdateRequest = serde_json::from_str(r#"{
+        "name": "Big Con",
+        "time": "2026-10-16T09:00:00-05:00",
+        "endTime": "2026-10-18T17:00:00-05:00",
+        "timeZone": "America/Chicago"
+    }"#).expect("to deserialize");
+    let event = eur.db_param(None).expect("a valid event").with_id(EventId::from(7));
+    assert_eq!(event.date.map(|d| d.to_string()), Some("2026-10-16 14:00:00".to_string()));
+
+    let resp = EventResponse::from_query("/api", event).expect("to build");
+    let json = serde_json::to_value(&resp).expect("to serialize");
+    assert_eq!(json["time"], "2026-10-16T09:00:00-05:00");
+    assert_eq!(json["endTime"], "2026-10-18T17:00:00-05:00");
+    assert_eq!(json["timeZone"], "America/Chicago");
+
+    let backwards: EventUpdateRequest = serde_json::from_str(r#"{"time": "2026-10-16T09:00:00Z", "endTime": "2026-10-15T09:00:00Z"}"#)
+        .expect("to deserialize");
+    assert!(backwards.db_param(None).is_err());
+    assert!(serde_json::from_str::<EventUpdateRequest>(r#"{"timeZone": "Mars/Olympus_Mons"}"#).is_err());
+}
+
+#[test]
+fn updates_keep_the_end_and_zone_unless_given() {
+    let stored = EventUpdateRequest {
+        name: Some("Big Con".into()),
+        time: Some("2026-10-16T14:00:00Z".parse().unwrap()),
+        end_time: Some(Some("2026-10-18T22:00:00Z".parse().unwrap())),
+        time_zone: Some(Tz::America__Chicago),
+        location: None,
+        description: None,
+    }.db_param(None).expect("a valid event").with_id(EventId::from(7));
+
+    let renamed: EventUpdateRequest = serde_json::from_str(r#"{"name": "Bigger Con", "time": "2026-10-16T14:00:00Z", "location": "Hall"}"#)
+        .expect("to deserialize");
+    let event = renamed.db_param(Some(&stored)).expect("a valid event");
+    assert_eq!(event.name.as_deref(), Some("Bigger Con"));
+    assert_eq!(event.ends_at, stored.ends_at, "an update without an end time keeps the stored one");
+    assert_eq!(event.time_zone, "America/Chicago", "an update without a zone keeps the stored one");
+
+    let cleared: EventUpdateRequest = serde_json::from_str(r#"{"time": "2026-10-16T14:00:00Z", "endTime": null, "timeZone": "Europe/Berlin"}"#)
+        .expect("to deserialize");
+    let event = cleared.db_param(Some(&stored)).expect("a valid event");
+    assert_eq!(event.ends_at, None);
+    assert_eq!(event.time_zone, "Europe/Berlin");
+}
+
+#[test]
+fn event_list_pages_link_to_each_other() {
+    let query = EventSearchLocate{ when: Some(EventWhen::Upcoming), q: Some("euro game".into()), sort: Some(crate::db::EventSort::Name), page_size: Some(10), page: Some(2), ..EventSearchLocate::default() };
+    let resp = EventListResponse::from_query("/api", query, vec![], true).expect("to build");
+    let view = serde_json::to_value(&resp.view).expect("to serialize");
+    assert_eq!(view["type"], "PartialCollectionView");
+    assert_eq!(view["id"], "/api/events?when=upcoming&q=euro%20game&sort=name&page_size=10&page=2");
+    assert_eq!(view["first"], "/api/events?when=upcoming&q=euro%20game&sort=name&page_size=10&page=1");
+    assert_eq!(view["next"], "/api/events?when=upcoming&q=euro%20game&sort=name&page_size=10&page=3");
+    assert_eq!(view["previous"], "/api/events?when=upcoming&q=euro%20game&sort=name&page_size=10&page=1");
+
+    let last = EventListResponse::from_query("/api", EventSearchLocate{ page_size: Some(10), page: Some(1), ..EventSearchLocate::default() }, vec![], false)
+        .expect("to build");
+    let view = serde_json::to_value(&last.view).expect("to serialize");
+    assert!(view.get("next").is_none() && view.get("previous").is_none());
+
+    let unpaged = EventListResponse::from_query("/api", EventSearchLocate::default(), vec![], false).expect("to build");
+    let view = serde_json::to_value(&unpaged.view).expect("to serialize");
+    assert_eq!(view["id"], "/api/events");
+    assert!(view.get("next").is_none(), "without a page size, the whole list is one page");
+}
+
 impl EventUpdateRequest {
-    pub(crate) fn db_param(&self) -> Event<NoId> {
-        Event {
+    /// The end time and zone are newer than some clients, so when they're left out, the `stored` event's are kept
+    pub(crate) fn db_param(&self, stored: Option<&Event<EventId>>) -> Result<Event<NoId>, Error> {
+        let date = self.time.map(|t| t.naive_utc());
+        let ends_at = match self.end_time {
+            Some(end_time) => end_time.map(|t| t.naive_utc()),
+            None => stored.and_then(|event| event.ends_at),
+        };
+        let time_zone = match (self.time_zone, stored) {
+            (Some(zone), _) => zone.name().to_string(),
+            (None, Some(event)) => event.time_zone.clone(),
+            (None, None) => Tz::UTC.name().to_string(),
+        };
+        if let (Some(start), Some(end)) = (date, ends_at) {
+            if end < start {
+                return Err((StatusCode::BAD_REQUEST, "an event can't end before it starts").into())
+            }
+        }
+        Ok(Event {
             name: self.name.clone(),
-            date: self.time.map(|t| t.naive_utc()),
+            date,
+            ends_at,
+            time_zone,
             r#where: self.location.clone(),
             description: self.description.clone(),
             ..Event::default()
-        }
+        })
     }
 }
 
 #[debug_handler(state = AppState)]
 pub(crate) async fn create_new(
     State(db): State<Pool<Postgres>>,
+    Extension(CurrentUser(creator)): Extension<CurrentUser>,
     nested_at: extract::NestedPath,
     Json(body): extract::Json<EventUpdateRequest>
 ) -> Result<impl IntoResponse, Error> {
-    let new_id = body.db_param()
-        .add_new(&db).await?;
+    let new_id = body.db_param(None)?
+        .add_new(&db, creator).await?;
 
     let location_uri = RouteMap::Event.prefixed(nested_at.as_str())
         .fill( EventLocate{ event_id: new_id })?;
@@ -143,15 +297,39 @@ pub(crate) async fn create_new(
     Ok((StatusCode::CREATED, [(header::LOCATION, location_uri.to_string())]))
 }
 
+/// Events filtered by `when` (upcoming, past or all), a `from`/`to` range and `q` text,
+/// sorted by `sort` (date, name or loc) in `order`. Archived events are left out unless `include_archived` is set.
+/// Given a `page_size`, they come a `page` at a time.
+/// Asking for `text/calendar` gets the same page as iCalendar.
 #[debug_handler(state = AppState)]
 pub(crate) async fn get_list(
     State(db): State<Pool<Postgres>>,
     if_none_match: condreq::CondRetreiveHeader,
-    nested_at: extract::NestedPath
-) -> Result<impl IntoResponse, Error> {
-    let events = Event::get_all(&db).await?;
-    let resp = EventListResponse::from_query(nested_at.as_str(), events)?;
-    if_none_match.respond(resp).map_err(Error::from)
+    nested_at: extract::NestedPath,
+    headers: HeaderMap,
+    Query(query): Query<EventSearchLocate>
+) -> Result<Response, Error> {
+    // Without a page size, everything that matches comes back at once
+    let (query, limit, offset) = match query.page_size {
+        Some(page_size) => {
+            let page_size = page_size.clamp(1, MAX_PAGE_SIZE);
+            let page = query.page.unwrap_or(1).max(1);
+            (EventSearchLocate{ page_size: Some(page_size), page: Some(page), ..query },
+                Some(i64::from(page_size) + 1), i64::from(page - 1) * i64::from(page_size))
+        },
+        None => (EventSearchLocate{ page: None, ..query }, None, 0)
+    };
+
+    let mut events = Event::search(&db, filter(&query, Utc::now().naive_utc()), limit, offset).await?;
+    let more = query.page_size.is_some_and(|page_size| events.len() > page_size as usize);
+    if let Some(page_size) = query.page_size {
+        events.truncate(page_size as usize);
+    }
+    if ical::wants_calendar(&headers) {
+        return Ok(ical::respond("Wag the Pig", &events))
+    }
+    let resp = EventListResponse::from_query(nested_at.as_str(), query, events, more)?;
+    Ok(if_none_match.respond(resp)?.into_response())
 }
 
 #[debug_handler(state = AppState)]
@@ -159,10 +337,16 @@ pub(crate) async fn get(
     State(db): State<Pool<Postgres>>,
     if_none_match: condreq::CondRetreiveHeader,
     nested_at: extract::NestedPath,
+    headers: HeaderMap,
     Path(event_id): extract::Path<EventId>,
-) -> Result<impl IntoResponse, Error> {
+) -> Result<Response, Error> {
+    if ical::wants_calendar(&headers) {
+        let event = retrieve_event(&db, event_id).await?;
+        let name = event.name.clone().unwrap_or_else(|| "Wag the Pig".to_string());
+        return Ok(ical::respond(&name, &[event]))
+    }
     let event_response = retrieve(&db, &nested_at, event_id).await?;
-    if_none_match.respond(event_response).map_err(Error::from)
+    Ok(if_none_match.respond(event_response)?.into_response())
 }
 
 #[debug_handler(state = AppState)]
@@ -173,18 +357,90 @@ pub(crate) async fn update(
     Path(event_id): extract::Path<EventId>,
     Json(body): extract::Json<EventUpdateRequest>
 ) -> Result<impl IntoResponse, Error> {
-    let event = retrieve(&db, &nested_at, event_id).await?;
+    let stored = retrieve_event(&db, event_id).await?;
 
     debug!("if_match: {:?}", if_match);
-    if_match.guard_update(event)?;
+    if_match.guard_update(EventResponse::from_query(nested_at.as_str(), stored.with_id(event_id))?)?;
 
-    let event = body.db_param()
+    let event = body.db_param(Some(&stored))?
         .with_id(event_id)
         .update(&db).await?;
 
     Ok(Json(EventResponse::from_query(nested_at.as_str(), event)?))
 }
 
+fn filter(query: &EventSearchLocate, now: NaiveDateTime) -> EventFilter {
+    let (after, before) = match query.when.unwrap_or_default() {
+        EventWhen::Upcoming => (Some(query.from.map_or(now, |from| from.max(now))), query.to),
+        EventWhen::Past => (query.from, Some(query.to.map_or(now, |to| to.min(now)))),
+        EventWhen::All => (query.from, query.to),
+    };
+    EventFilter {
+        include_archived: query.include_archived.unwrap_or(false),
+        after,
+        before,
+        text: query.q.clone().filter(|q| !q.trim().is_empty()),
+        sort: query.sort.unwrap_or_default(),
+        descending: query.order.unwrap_or_default() == SortOrder::Desc,
+    }
+}
+
+/// Removes the event, with its games and everyone's interest in them
+#[debug_handler(state = AppState)]
+pub(crate) async fn delete(
+    State(db): State<Pool<Postgres>>,
+    Path(event_id): extract::Path<EventId>,
+) -> Result<impl IntoResponse, Error> {
+    retrieve_event(&db, event_id).await?;
+
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    Interest::remove_for_event(&mut *tx, event_id).await?;
+    Game::remove_for_event(&mut *tx, event_id).await?;
+    Event::delete(&mut *tx, event_id).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Hides the event from the default list; it can still be read and brought back
+#[debug_handler(state = AppState)]
+pub(crate) async fn archive(
+    State(db): State<Pool<Postgres>>,
+    nested_at: extract::NestedPath,
+    Path(event_id): extract::Path<EventId>,
+) -> Result<impl IntoResponse, Error> {
+    set_archived(db, nested_at, event_id, Some(Utc::now().naive_utc())).await
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn unarchive(
+    State(db): State<Pool<Postgres>>,
+    nested_at: extract::NestedPath,
+    Path(event_id): extract::Path<EventId>,
+) -> Result<impl IntoResponse, Error> {
+    set_archived(db, nested_at, event_id, None).await
+}
+
+async fn set_archived(
+    db: Pool<Postgres>,
+    nested_at: extract::NestedPath,
+    event_id: EventId,
+    archived_at: Option<NaiveDateTime>
+) -> Result<Json<EventResponse>, Error> {
+    retrieve_event(&db, event_id).await?;
+
+    let event = Event::set_archived(&db, event_id, archived_at).await?;
+    Ok(Json(EventResponse::from_query(nested_at.as_str(), event)?))
+}
+
+async fn retrieve_event(
+    db: &Pool<Postgres>,
+    event_id: EventId
+) -> Result<Event<EventId>, Error> {
+    Event::get_by_id(db, event_id).await?
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "not found").into())
+}
+
 async fn retrieve(
     db: &Pool<Postgres>,
     nested_at: &extract::NestedPath,
@@ -70,7 +70,7 @@ pub(crate) struct EventGameListResponse {
 }
 
 impl EventGameListResponse {
-    pub fn from_query(nested_at: &str, event_id: EventId, user_id: String, list: Vec<db::Game<GameId, EventId, UserId, db::PlayerData>>) -> Result<Self, mattak::Error> {
+    pub fn from_query(nested_at: &str, event_id: EventId, user_id: String, list: Vec<db::Game<GameId, EventId, Option<UserId>, db::PlayerData>>) -> Result<Self, mattak::Error> {
         let game_tmpl = RouteMap::Game.prefixed(nested_at).template()?;
         Ok(Self{
             resource_fields: ResourceFields::new(
@@ -0,0 +1,18 @@
+use axum::{debug_handler, extract::State, response::IntoResponse};
+use biscuit_auth::BiscuitWebKey;
+use serde::Serialize;
+use mattak::condreq;
+
+use crate::{keyring::KeyRing, AppState, Error};
+
+/// As mattak's `KeySetResponse`, but with every key in the ring
+#[derive(Serialize, Clone)]
+pub(crate) struct KeySetResponse(Vec<BiscuitWebKey>);
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get(
+    if_none_match: condreq::CondRetreiveHeader,
+    State(keys): State<KeyRing>,
+) -> Result<impl IntoResponse, Error> {
+    if_none_match.respond(KeySetResponse(keys.web_keys())).map_err(Error::from)
+}
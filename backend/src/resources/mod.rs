@@ -1,5 +1,52 @@
 pub(crate) mod authentication;
+pub(crate) mod oidc;
+pub(crate) mod passkey;
 pub(crate) mod profile;
+pub(crate) mod session;
+pub(crate) mod api_token;
+pub(crate) mod two_factor;
 pub(crate) mod event;
+pub(crate) mod calendar;
+pub(crate) mod organizer;
+pub(crate) mod rsvp;
+pub(crate) mod share;
 pub(crate) mod game;
 pub(crate) mod recommendation;
+pub(crate) mod admin;
+pub(crate) mod audit;
+pub(crate) mod account;
+pub(crate) mod key_set;
+
+use mattak::hypermedia::{IriReferenceString, Operation};
+use serde::Serialize;
+
+/// mattak's ActionTypes don't cover removal
+pub(crate) fn delete_op() -> Operation {
+    Operation{
+        r#type: "DeleteAction".to_string(),
+        method: axum::http::Method::DELETE.into()
+    }
+}
+
+/// Hydra's links between the pages of a collection; `next` and `previous` are left out at either end
+#[derive(Serialize, Clone)]
+pub(crate) struct PartialCollectionView {
+    pub id: IriReferenceString,
+    pub r#type: &'static str,
+    pub first: IriReferenceString,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<IriReferenceString>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous: Option<IriReferenceString>,
+}
+
+impl PartialCollectionView {
+    pub(crate) fn new(
+        id: IriReferenceString,
+        first: IriReferenceString,
+        next: Option<IriReferenceString>,
+        previous: Option<IriReferenceString>
+    ) -> Self {
+        Self{ id, r#type: "PartialCollectionView", first, next, previous }
+    }
+}
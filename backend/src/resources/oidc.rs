@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+
+use axum::{debug_handler, extract::{ConnectInfo, State}, response::{IntoResponse, Response}, Json};
+use chrono::Utc;
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tracing::{debug, warn};
+
+use crate::{
+    db::{AuditAction, AuditEvent, AuditOutcome, OidcIdentity, OidcLogin, User},
+    oidc::{OidcClient, PendingLogin},
+    resources::{authentication::{check_second_factor, start_session, throttled}, two_factor::SecondFactor},
+    throttle::LoginThrottle,
+    tokens::TokenIssuer,
+    totp::TotpKey,
+    AppState, Error
+};
+
+// Long enough to sign in at the provider, short enough that a leaked state is soon useless
+const LOGIN_WINDOW: chrono::Duration = chrono::Duration::minutes(10);
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct OidcLoginResponse {
+    pub authorization_url: String,
+}
+
+/// What the provider sent back to the frontend, plus a second factor if the account has one.
+/// The provider's `code` is renamed, so that it isn't mistaken for the second factor's.
+#[derive(Deserialize)]
+pub(crate) struct OidcCallbackRequest {
+    pub authorization_code: String,
+    pub state: String,
+    #[serde(flatten)]
+    pub second_factor: SecondFactor,
+}
+
+fn configured(oidc: Option<OidcClient>) -> Result<OidcClient, Error> {
+    oidc.ok_or_else(|| (StatusCode::NOT_FOUND, "no identity provider configured").into())
+}
+
+/// Starts a login with the identity provider; the frontend sends the user to the URL returned
+#[debug_handler(state = AppState)]
+pub(crate) async fn begin(
+    State(db): State<Pool<Postgres>>,
+    State(oidc): State<Option<OidcClient>>,
+) -> Result<impl IntoResponse, Error> {
+    let oidc = configured(oidc)?;
+    let (url, pending) = oidc.begin().await?;
+    OidcLogin::add(&db, pending.state, pending.nonce, pending.pkce_verifier).await?;
+    Ok(Json(OidcLoginResponse{ authorization_url: url.to_string() }))
+}
+
+/// Finishes a login once the provider has sent the user back to the frontend's /oidc_login page.
+/// Provider accounts are linked to ours by verified email the first time they're used.
+/// A login can only be finished once, so any second factor has to come along with the code.
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)] // extractors
+pub(crate) async fn finish(
+    State(db): State<Pool<Postgres>>,
+    State(oidc): State<Option<OidcClient>>,
+    State(issuer): State<TokenIssuer>,
+    State(totp_key): State<TotpKey>,
+    State(throttle): State<LoginThrottle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<OidcCallbackRequest>
+) -> Result<Response, Error> {
+    let oidc = configured(oidc)?;
+    let now = Utc::now().naive_utc();
+    let login = OidcLogin::take(&db, req.state.clone(), now - LOGIN_WINDOW).await?
+        .ok_or_else(|| -> Error {(StatusCode::BAD_REQUEST, "login expired; please try again").into()})?;
+    // Until the provider vouches for someone, there's no account to audit the failure against
+    let claims = oidc.finish(&req.authorization_code, &PendingLogin{
+        state: login.state,
+        nonce: login.nonce,
+        pkce_verifier: login.pkce_verifier,
+    }).await.inspect_err(|error| warn!("OIDC login from {addr} failed: {error:?}"))?;
+    let audit_failure = |email: String, failed: &str| AuditEvent::new(email, AuditAction::Login, AuditOutcome::Failure)
+        .client_ip(addr).detail(&format!("oidc_{failed}"));
+
+    let identity = match OidcIdentity::touch(&db, oidc.issuer.clone(), claims.sub.clone(), now).await? {
+        Some(identity) => Some(identity),
+        None => match claims.verified_email() {
+            Some(email) => {
+                debug!("Linking provider account to {email}");
+                let linked = OidcIdentity::link_by_email(&db, oidc.issuer.clone(), claims.sub.clone(), email.to_string(), now).await?;
+                if linked.is_some() {
+                    User::verify_email(&db, email.to_string(), now).await?;
+                }
+                linked
+            }
+            None => None
+        }
+    };
+    let Some(identity) = identity else {
+        if let Some(email) = claims.verified_email() {
+            audit_failure(email.to_string(), "no_account").record_or_warn(&db).await;
+        }
+        return Err((StatusCode::FORBIDDEN, "no account with that verified email; register first").into())
+    };
+
+    let user = User::by_id(&db, identity.user_id.into()).await?;
+    if user.locked_at.is_some() {
+        audit_failure(user.email.clone(), "locked").record_or_warn(&db).await;
+        return Err((StatusCode::FORBIDDEN, "Authorization rejected").into())
+    }
+    if let Some(wait) = throttled(&db, &throttle, user.email.clone(), addr, "oidc").await? {
+        return Ok(wait)
+    }
+    check_second_factor(&db, &totp_key, &throttle, &user, &req.second_factor, addr).await?;
+
+    // Unlike the other logins, the client doesn't know whose account this is until we say
+    let session = start_session(&db, &issuer, user.email.clone(), user.is_admin, addr, &headers, "oidc").await?;
+    Ok(([("set-account-id", user.email)], session).into_response())
+}
+
+#[test]
+fn deserialize_oidc_callback_request() {
+    let req: OidcCallbackRequest = serde_json::from_str(r#"{"authorization_code": "from-provider", "state": "abc", "code": "123456"}"#)
+        .expect("to deserialize");
+    assert_eq!(req.authorization_code, "from-provider");
+    assert_eq!(req.second_factor.code.as_deref(), Some("123456"));
+}
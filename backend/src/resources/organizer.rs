@@ -0,0 +1,112 @@
+use axum::{debug_handler, extract::{self, Path, State}, response::IntoResponse};
+use hyper::StatusCode;
+use mattak::{condreq, hypermedia::{op, ActionType, IriTemplate, ResourceFields}};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{Event, EventId, User, UserId},
+    resources::delete_op,
+    routing::{EventOrganizerLocate, EventOrganizersLocate, RouteMap},
+    AppState, Error
+};
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct OrganizerListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<EventOrganizersLocate>,
+
+    pub organizer: IriTemplate,
+    pub organizers: Vec<OrganizerResponse>,
+}
+
+impl OrganizerListResponse {
+    pub fn from_query(nested_at: &str, event_id: EventId, list: Vec<User<UserId>>) -> Result<Self, mattak::Error> {
+        let organizer_tmpl = RouteMap::EventOrganizer.prefixed(nested_at)
+            .partial_fill([("event_id".to_string(), event_id.to_string())])?;
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::EventOrganizers.prefixed(nested_at),
+                EventOrganizersLocate{ event_id },
+                "api:eventOrganizersList",
+                vec![ op(ActionType::View) ]
+            )?,
+            organizer: IriTemplate {
+                id: "api:eventOrganizerByEmail".try_into()?,
+                template: organizer_tmpl,
+                operation: vec![ op(ActionType::Create), delete_op() ]
+            },
+            organizers: list.into_iter().map(|user|
+                OrganizerResponse::from_query(nested_at, event_id, user))
+                .collect::<Result<_,_>>()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct OrganizerResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<EventOrganizerLocate>,
+
+    pub name: Option<String>,
+    pub email: String,
+}
+
+impl OrganizerResponse {
+    pub(crate) fn from_query(nested_at: &str, event_id: EventId, value: User<UserId>) -> Result<Self, mattak::Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::EventOrganizer.prefixed(nested_at),
+                EventOrganizerLocate{ event_id, user_id: value.email.clone() },
+                "api:eventOrganizerByEmail",
+                vec![ delete_op() ]
+            )?,
+            name: value.name,
+            email: value.email
+        })
+    }
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_list(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(event_id): Path<EventId>,
+) -> Result<impl IntoResponse, Error> {
+    let organizers = User::get_organizers_by_event_id(&db, event_id).await?;
+    let resp = OrganizerListResponse::from_query(nested_at.as_str(), event_id, organizers)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn add(
+    State(db): State<Pool<Postgres>>,
+    Path((event_id, user_id)): Path<(EventId, String)>,
+) -> Result<impl IntoResponse, Error> {
+    User::by_email(&db, user_id.clone()).await?;
+    Event::add_organizer(&db, event_id, user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn remove(
+    State(db): State<Pool<Postgres>>,
+    Path((event_id, user_id)): Path<(EventId, String)>,
+) -> Result<impl IntoResponse, Error> {
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    Event::lock_organizers(&mut *tx, event_id).await?;
+    let organizers = User::get_organizers_by_event_id(&mut *tx, event_id).await?;
+    if !organizers.iter().any(|user| user.email == user_id) {
+        return Err((StatusCode::NOT_FOUND, "not an organizer").into())
+    }
+    if organizers.len() == 1 {
+        return Err((StatusCode::CONFLICT, "an event needs at least one organizer").into())
+    }
+
+    Event::remove_organizer(&mut *tx, event_id, user_id).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,260 @@
+use std::net::SocketAddr;
+
+use axum::{debug_handler, extract::{self, ConnectInfo, Path, State}, response::{IntoResponse, Response}, Json};
+use chrono::{NaiveDateTime, Utc};
+use hyper::{header, HeaderMap, StatusCode};
+use mattak::{condreq, hypermedia::{op, ActionType, ResourceFields}};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, Pool, Postgres};
+use webauthn_rs::prelude::{CreationChallengeResponse, PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse};
+
+use crate::{
+    db::{AuditAction, AuditEvent, AuditOutcome, LoginFailure, Passkey, PasskeyChallenge, PasskeyId, User},
+    passkeys::Passkeys,
+    resources::{authentication::{reauthenticate, record_failure, start_session, throttled, Reauthentication}, delete_op},
+    routing::{PasskeyLocate, PasskeysLocate, RouteMap},
+    throttle::LoginThrottle,
+    tokens::TokenIssuer,
+    totp::TotpKey,
+    AppState, Error
+};
+
+// Authenticators time out after a minute or two; this leaves room for a slow user
+const CHALLENGE_WINDOW: chrono::Duration = chrono::Duration::minutes(5);
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct PasskeyListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<PasskeysLocate>,
+
+    pub passkeys: Vec<PasskeyResponse>,
+}
+
+impl PasskeyListResponse {
+    pub fn from_query(nested_at: &str, user_id: String, list: Vec<Passkey>) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::Passkeys.prefixed(nested_at),
+                PasskeysLocate{ user_id: user_id.clone() },
+                "api:passkeysList",
+                vec![ op(ActionType::View), op(ActionType::Add) ]
+            )?,
+            passkeys: list.into_iter().map(|passkey|
+                PasskeyResponse::from_query(nested_at, user_id.clone(), passkey))
+                .collect::<Result<_,_>>()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct PasskeyResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<PasskeyLocate>,
+
+    pub label: String,
+    pub created_at: NaiveDateTime,
+    pub last_used_at: Option<NaiveDateTime>,
+}
+
+impl PasskeyResponse {
+    pub(crate) fn from_query(nested_at: &str, user_id: String, value: Passkey) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::Passkey.prefixed(nested_at),
+                PasskeyLocate{ user_id, passkey_id: value.id },
+                "api:passkeyById",
+                vec![ delete_op() ]
+            )?,
+            label: value.label,
+            created_at: value.created_at,
+            last_used_at: value.last_used_at,
+        })
+    }
+}
+
+/// The options to hand to `navigator.credentials`, and the id to answer them with
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ChallengeResponse<T> {
+    pub challenge_id: String,
+    #[serde(flatten)]
+    pub options: T,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct RegistrationRequest {
+    pub challenge_id: String,
+    pub label: String,
+    pub credential: RegisterPublicKeyCredential,
+    #[serde(flatten)]
+    pub reauth: Reauthentication,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct LoginRequest {
+    pub challenge_id: String,
+    pub credential: PublicKeyCredential,
+}
+
+fn stored(passkeys: &[Passkey]) -> Result<Vec<webauthn_rs::prelude::Passkey>, Error> {
+    passkeys.iter()
+        .map(|pk| serde_json::from_value(pk.passkey.clone()))
+        .collect::<Result<_,_>>()
+        .map_err(Error::from)
+}
+
+fn rejected() -> Error {
+    (StatusCode::FORBIDDEN, "Authorization rejected").into()
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_list(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id.clone()).await?;
+    let passkeys = Passkey::get_for_user(&db, user.id).await?;
+    let resp = PasskeyListResponse::from_query(nested_at.as_str(), user_id, passkeys)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn start_registration(
+    State(db): State<Pool<Postgres>>,
+    State(passkeys): State<Passkeys>,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id).await?;
+    let user = User::claim_passkey_handle(&db, user.id, Uuid::new_v4()).await?;
+    let handle = user.passkey_handle.ok_or_else(|| -> Error {(StatusCode::INTERNAL_SERVER_ERROR, "no passkey handle").into()})?;
+    let existing = stored(&Passkey::get_for_user(&db, user.id).await?)?;
+    let display_name = user.name.clone().unwrap_or_else(|| user.email.clone());
+
+    let (options, state) = passkeys.start_registration(handle, &user.email, &display_name, &existing)?;
+    let challenge_id = Uuid::new_v4().to_string();
+    PasskeyChallenge::add(&db, challenge_id.clone(), user.id, true, state).await?;
+    Ok(Json(ChallengeResponse::<CreationChallengeResponse>{ challenge_id, options }))
+}
+
+/// A passkey outlives password changes and revoked sessions, so adding one takes the password
+/// (and second factor, if there is one) as well as a session
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)] // extractors
+pub(crate) async fn finish_registration(
+    State(db): State<Pool<Postgres>>,
+    State(passkeys): State<Passkeys>,
+    State(totp_key): State<TotpKey>,
+    State(throttle): State<LoginThrottle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>,
+    Json(req): Json<RegistrationRequest>
+) -> Result<impl IntoResponse, Error> {
+    if req.label.trim().is_empty() {
+        return Err(mattak::Error::InvalidInput("label is required".to_string()).into())
+    }
+    let user = User::by_email(&db, user_id.clone()).await?;
+    // Before the challenge is taken, so a mistyped password can be tried again
+    reauthenticate(&db, &totp_key, &throttle, &user, &req.reauth, addr).await?;
+    let challenge = PasskeyChallenge::take(&db, req.challenge_id.clone(), user.id, true, Utc::now().naive_utc() - CHALLENGE_WINDOW).await?
+        .ok_or_else(|| -> Error {(StatusCode::BAD_REQUEST, "registration expired; please try again").into()})?;
+
+    let passkey = passkeys.finish_registration(&req.credential, challenge.state)?;
+    let credential_id = passkey.cred_id().to_vec();
+    let added = Passkey::add(&db, user.id, credential_id, req.label.clone(), serde_json::to_value(&passkey)?).await?;
+
+    let resp = PasskeyResponse::from_query(nested_at.as_str(), user_id, added)?;
+    let location = resp.resource_fields.id.to_string();
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)], Json(resp)))
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn remove(
+    State(db): State<Pool<Postgres>>,
+    Path((user_id, passkey_id)): Path<(String, PasskeyId)>
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id).await?;
+    match Passkey::remove(&db, passkey_id, user.id).await? {
+        Some(_) => Ok(StatusCode::NO_CONTENT),
+        None => Err((StatusCode::NOT_FOUND, "no such passkey").into())
+    }
+}
+
+/// Challenges the user's passkeys.
+/// Unknown users, locked accounts and accounts without passkeys get a decoy challenge instead,
+/// so this doesn't tell anyone which emails have accounts.
+#[debug_handler(state = AppState)]
+pub(crate) async fn start_login(
+    State(db): State<Pool<Postgres>>,
+    State(passkeys): State<Passkeys>,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id.clone()).await.ok().filter(|user| user.locked_at.is_none());
+    let registered = match &user {
+        Some(user) => Passkey::get_for_user(&db, user.id).await?,
+        None => vec![]
+    };
+
+    let (options, state) = if registered.is_empty() {
+        passkeys.start_decoy_login(&user_id)?
+    } else {
+        passkeys.start_login(&stored(&registered)?)?
+    };
+    let challenge_id = Uuid::new_v4().to_string();
+    // A decoy is stored too, where there's a user to store it for, so answering it fails like a wrong passkey
+    if let Some(user) = user {
+        PasskeyChallenge::add(&db, challenge_id.clone(), user.id, false, state).await?;
+    }
+    Ok(Json(ChallengeResponse::<RequestChallengeResponse>{ challenge_id, options }))
+}
+
+/// Passkeys verify the user themselves, so they stand in for both the password and any second factor.
+/// Failures count towards the same throttle as passwords.
+#[debug_handler(state = AppState)]
+#[allow(clippy::too_many_arguments)] // extractors
+pub(crate) async fn finish_login(
+    State(db): State<Pool<Postgres>>,
+    State(passkeys): State<Passkeys>,
+    State(issuer): State<TokenIssuer>,
+    State(throttle): State<LoginThrottle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(req): Json<LoginRequest>
+) -> Result<Response, Error> {
+    if let Some(wait) = throttled(&db, &throttle, user_id.clone(), addr, "passkey").await? {
+        return Ok(wait)
+    }
+    let Ok(user) = User::by_email(&db, user_id.clone()).await else {
+        return Err(record_failure(&db, &throttle, user_id, addr, "passkey").await?)
+    };
+    if user.locked_at.is_some() {
+        AuditEvent::new(user.email.clone(), AuditAction::Login, AuditOutcome::Failure).client_ip(addr).detail("locked")
+            .record_or_warn(&db).await;
+        return Err(rejected())
+    }
+    let now = Utc::now().naive_utc();
+    let challenge = PasskeyChallenge::take(&db, req.challenge_id.clone(), user.id, false, now - CHALLENGE_WINDOW).await?
+        .ok_or_else(|| -> Error {(StatusCode::BAD_REQUEST, "login expired; please try again").into()})?;
+
+    let Ok(result) = passkeys.finish_login(&req.credential, challenge.state) else {
+        return Err(record_failure(&db, &throttle, user_id, addr, "passkey").await?)
+    };
+    let Some(used) = Passkey::get_for_user(&db, user.id).await?
+        .into_iter()
+        .find(|pk| pk.credential_id.as_slice() == result.cred_id().as_slice()) else {
+        return Err(record_failure(&db, &throttle, user_id, addr, "passkey").await?)
+    };
+    let mut passkey: webauthn_rs::prelude::Passkey = serde_json::from_value(used.passkey.clone())?;
+    passkey.update_credential(&result);
+    Passkey::record_use(&db, used.id, serde_json::to_value(&passkey)?, now).await?;
+    LoginFailure::clear(&db, user_id).await?;
+
+    Ok(start_session(&db, &issuer, user.email.clone(), user.is_admin, addr, &headers, "passkey").await?.into_response())
+}
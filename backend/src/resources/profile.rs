@@ -1,12 +1,13 @@
 use axum::{debug_handler, extract::{self, Path, State}, response::IntoResponse};
-use mattak::{condreq, hypermedia::{op, ActionType, ResourceFields}};
+use mattak::{condreq, hypermedia::{op, ActionType, Link, ResourceFields}};
 use serde::Serialize;
 use sqlx::{Pool, Postgres};
 use tracing::debug;
 
 use crate::{
     db::{EventId, GameId, User, UserId},
-    routing::{EventUsersLocate, GameUsersLocate, ProfileLocate, RouteMap, UserLocate},
+    resources::delete_op,
+    routing::{AccountDeletionLocate, AccountExportLocate, AuditEventsLocate, CalendarFeedLocate, EmailChangeLocate, EventUsersLocate, GameUsersLocate, PasskeysLocate, ProfileLocate, RouteMap, UserLocate},
     AppState, Error
 };
 
@@ -65,6 +66,13 @@ pub(crate) struct ProfileResponse {
     #[serde(flatten)]
     pub resource_fields: ResourceFields<ProfileLocate>,
 
+    pub passkeys: Link,
+    pub audit_events: Link,
+    pub account_export: Link,
+    pub account_deletion: Link,
+    pub email_change: Link,
+    pub calendar_feed: Link,
+
     pub name: Option<String>,
     pub bgg_username: Option<String>,
     pub email: String,
@@ -80,6 +88,30 @@ impl ProfileResponse {
                 "api:profileByEmailTemplate",
                 vec![ op(ActionType::View) ]
             )?,
+            passkeys: Link {
+                id: RouteMap::Passkeys.prefixed(nested_at).fill(PasskeysLocate{ user_id: value.email.clone() })?,
+                operation: vec![ op(ActionType::View), op(ActionType::Add) ]
+            },
+            audit_events: Link {
+                id: RouteMap::AuditEvents.prefixed(nested_at).fill(AuditEventsLocate{ user_id: value.email.clone() })?,
+                operation: vec![ op(ActionType::View) ]
+            },
+            account_export: Link {
+                id: RouteMap::AccountExport.prefixed(nested_at).fill(AccountExportLocate{ user_id: value.email.clone() })?,
+                operation: vec![ op(ActionType::View) ]
+            },
+            account_deletion: Link {
+                id: RouteMap::AccountDeletion.prefixed(nested_at).fill(AccountDeletionLocate{ user_id: value.email.clone() })?,
+                operation: vec![ op(ActionType::Create), delete_op() ]
+            },
+            email_change: Link {
+                id: RouteMap::EmailChange.prefixed(nested_at).fill(EmailChangeLocate{ user_id: value.email.clone() })?,
+                operation: vec![ op(ActionType::Create), op(ActionType::Add) ]
+            },
+            calendar_feed: Link {
+                id: RouteMap::CalendarFeed.prefixed(nested_at).fill(CalendarFeedLocate{ user_id: value.email.clone() })?,
+                operation: vec![ op(ActionType::Find), op(ActionType::Add) ]
+            },
             name: value.name,
             bgg_username: value.bgg_username,
             email: value.email
@@ -32,7 +32,7 @@ pub(crate) struct RecommendListResponse {
 }
 
 impl RecommendListResponse {
-    pub fn from_query(nested_at: &str, event_id: EventId, list: Vec<db::Game<GameId, EventId, UserId, RecommendData>>) -> Result<Self, Error> {
+    pub fn from_query(nested_at: &str, event_id: EventId, list: Vec<db::Game<GameId, EventId, Option<UserId>, RecommendData>>) -> Result<Self, Error> {
         Ok(Self{
             resource_fields: ResourceFields::new(
                 &RouteMap::Recommend.prefixed(nested_at),
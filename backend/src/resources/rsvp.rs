@@ -0,0 +1,147 @@
+use axum::{debug_handler, extract::{self, Path, State}, response::IntoResponse, Json};
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use mattak::{condreq, hypermedia::{op, ActionType, IriTemplate, ResourceFields}};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{Event, EventId, Rsvp, RsvpStatus},
+    resources::delete_op,
+    routing::{EventRsvpLocate, EventRsvpsLocate, RouteMap},
+    AppState, Error
+};
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct RsvpListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<EventRsvpsLocate>,
+
+    pub rsvp: IriTemplate,
+    pub rsvps: Vec<RsvpResponse>,
+}
+
+impl RsvpListResponse {
+    pub fn from_query(nested_at: &str, event_id: EventId, list: Vec<Rsvp>) -> Result<Self, mattak::Error> {
+        let rsvp_tmpl = RouteMap::EventRsvp.prefixed(nested_at)
+            .partial_fill([("event_id".to_string(), event_id.to_string())])?;
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::EventRsvps.prefixed(nested_at),
+                EventRsvpsLocate{ event_id },
+                "api:eventRsvpsList",
+                vec![ op(ActionType::View) ]
+            )?,
+            rsvp: IriTemplate {
+                id: "api:eventRsvpByEmail".try_into()?,
+                template: rsvp_tmpl,
+                operation: vec![ op(ActionType::Find), op(ActionType::Update), delete_op() ]
+            },
+            rsvps: list.into_iter().map(|rsvp|
+                RsvpResponse::from_query(nested_at, event_id, rsvp))
+                .collect::<Result<_,_>>()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct RsvpResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<EventRsvpLocate>,
+
+    pub name: Option<String>,
+    pub email: String,
+    pub status: RsvpStatus,
+    pub arrives_at: Option<DateTime<Utc>>,
+    pub departs_at: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl RsvpResponse {
+    pub(crate) fn from_query(nested_at: &str, event_id: EventId, value: Rsvp) -> Result<Self, mattak::Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::EventRsvp.prefixed(nested_at),
+                EventRsvpLocate{ event_id, user_id: value.email.clone() },
+                "api:eventRsvpByEmail",
+                vec![ op(ActionType::View), op(ActionType::Update), delete_op() ]
+            )?,
+            name: value.name,
+            email: value.email,
+            status: value.status,
+            arrives_at: value.arrives_at.map(|time| time.and_utc()),
+            departs_at: value.departs_at.map(|time| time.and_utc()),
+            updated_at: value.updated_at.and_utc(),
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct RsvpRequest {
+    pub status: RsvpStatus,
+    pub arrives_at: Option<DateTime<Utc>>,
+    pub departs_at: Option<DateTime<Utc>>,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_list(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(event_id): Path<EventId>,
+) -> Result<impl IntoResponse, Error> {
+    let rsvps = Rsvp::get_for_event(&db, event_id).await?;
+    let resp = RsvpListResponse::from_query(nested_at.as_str(), event_id, rsvps)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path((event_id, user_id)): Path<(EventId, String)>,
+) -> Result<impl IntoResponse, Error> {
+    let rsvp = Rsvp::get(&db, event_id, user_id).await?
+        .ok_or_else(|| -> Error {(StatusCode::NOT_FOUND, "no RSVP").into()})?;
+    if_none_match.respond(RsvpResponse::from_query(nested_at.as_str(), event_id, rsvp)?).map_err(Error::from)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn update(
+    State(db): State<Pool<Postgres>>,
+    nested_at: extract::NestedPath,
+    Path((event_id, user_id)): Path<(EventId, String)>,
+    Json(req): Json<RsvpRequest>
+) -> Result<impl IntoResponse, Error> {
+    if let (Some(arrives), Some(departs)) = (req.arrives_at, req.departs_at) {
+        if departs < arrives {
+            return Err((StatusCode::BAD_REQUEST, "can't leave before arriving").into())
+        }
+    }
+    Event::get_by_id(&db, event_id).await?
+        .ok_or_else(|| -> Error {(StatusCode::NOT_FOUND, "not found").into()})?;
+
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    Rsvp::set(&mut *tx, event_id, user_id.clone(), req.status,
+        req.arrives_at.map(|time| time.naive_utc()), req.departs_at.map(|time| time.naive_utc())).await?;
+    let rsvp = Rsvp::get(&mut *tx, event_id, user_id).await?
+        .ok_or_else(|| -> Error {(StatusCode::NOT_FOUND, "no such user").into()})?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+    Ok(Json(RsvpResponse::from_query(nested_at.as_str(), event_id, rsvp)?))
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn remove(
+    State(db): State<Pool<Postgres>>,
+    Path((event_id, user_id)): Path<(EventId, String)>,
+) -> Result<impl IntoResponse, Error> {
+    if Rsvp::remove(&db, event_id, user_id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "no RSVP").into())
+    }
+}
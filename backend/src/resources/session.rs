@@ -0,0 +1,165 @@
+use std::net::SocketAddr;
+
+use axum::{debug_handler, extract::{self, ConnectInfo, Path, State}, response::IntoResponse, Extension};
+use chrono::{NaiveDateTime, Utc};
+use hyper::StatusCode;
+use mattak::{biscuits::AuthContext, condreq, hypermedia::{op, ActionType, ResourceFields}};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{AuditAction, AuditEvent, AuditOutcome, Revocation, RevocationId},
+    revocation_cache::RevocationCache,
+    routing::{RouteMap, SessionLocate, SessionsLocate},
+    AppState, Error
+};
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct SessionListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<SessionsLocate>,
+
+    pub sessions: Vec<SessionResponse>,
+}
+
+impl SessionListResponse {
+    pub fn from_query(nested_at: &str, user_id: String, current: &CurrentSession, list: Vec<Revocation<RevocationId>>) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::Sessions.prefixed(nested_at),
+                SessionsLocate{ user_id },
+                "api:sessionsList",
+                vec![ op(ActionType::View), op(ActionType::Logout) ]
+            )?,
+            sessions: list.into_iter().map(|session|
+                SessionResponse::from_query(nested_at, current, session))
+                .collect::<Result<_,_>>()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct SessionResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<SessionLocate>,
+
+    pub client_hint: Option<String>,
+    pub client_ip: Option<String>,
+    pub expires: NaiveDateTime,
+    pub current: bool,
+}
+
+impl SessionResponse {
+    pub(crate) fn from_query(nested_at: &str, current: &CurrentSession, value: Revocation<RevocationId>) -> Result<Self, Error> {
+        let is_current = current.includes(&value);
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::Session.prefixed(nested_at),
+                SessionLocate{ user_id: value.username, session_id: value.id },
+                "api:sessionById",
+                vec![ op(ActionType::Logout) ]
+            )?,
+            client_hint: value.clienthint,
+            client_ip: value.client_ip,
+            expires: value.expires,
+            current: is_current,
+        })
+    }
+}
+
+/// The tokens making the request, and the sessions they belong to
+pub(crate) struct CurrentSession {
+    rids: Vec<String>,
+    chains: Vec<String>,
+}
+
+impl CurrentSession {
+    pub(crate) async fn load(db: &Pool<Postgres>, authctx: &AuthContext) -> Result<Self, Error> {
+        let rids = authctx.revocation_ids().unwrap_or_default();
+        let chains = Revocation::chains_for(db, rids.clone()).await?;
+        Ok(Self{ rids, chains })
+    }
+
+    fn includes(&self, value: &Revocation<RevocationId>) -> bool {
+        self.rids.contains(&value.data) || value.session_chain.as_ref().is_some_and(|chain| self.chains.contains(chain))
+    }
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_list(
+    State(db): State<Pool<Postgres>>,
+    Extension(authctx): Extension<AuthContext>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let sessions = Revocation::get_live_for_username(&db, user_id.clone(), Utc::now().naive_utc()).await?;
+    let current = CurrentSession::load(&db, &authctx).await?;
+    let resp = SessionListResponse::from_query(nested_at.as_str(), user_id, &current, sessions)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+/// Revokes every session for the user except the one making the request
+#[debug_handler(state = AppState)]
+pub(crate) async fn revoke_others(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(authctx): Extension<AuthContext>,
+    Path(user_id): Path<String>
+) -> Result<impl IntoResponse, Error> {
+    let current = authctx.revocation_ids().unwrap_or_default();
+    let revoked = Revocation::revoke_others_for_username(&db, user_id.clone(), current, Utc::now().naive_utc()).await?;
+    cache.add(&revoked);
+    AuditEvent::new(user_id.clone(), AuditAction::SessionsRevoked, AuditOutcome::Success).actor(user_id).client_ip(addr).detail("others")
+        .record_or_warn(&db).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn revoke(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((user_id, session_id)): Path<(String, RevocationId)>
+) -> Result<impl IntoResponse, Error> {
+    let revoked = Revocation::revoke_session_by_id(&db, session_id, user_id.clone(), Utc::now().naive_utc()).await?;
+    if revoked.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "no such session").into())
+    }
+    cache.add(&revoked);
+    AuditEvent::new(user_id.clone(), AuditAction::SessionsRevoked, AuditOutcome::Success).actor(user_id).client_ip(addr).detail("one")
+        .record_or_warn(&db).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::{Ipv4Addr, SocketAddrV4}, time::{Duration, SystemTime}};
+
+    use super::*;
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_revoke_only_sessions(pool: Pool<Postgres>) {
+        let cache = RevocationCache::start(pool.clone());
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 3000));
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let now = Utc::now().naive_utc();
+        let email = "test@mctesterson.net".to_string();
+        let token = Revocation::add_labeled(&pool, "bot".to_string(), email.clone(), expires, "game poster".to_string()).await.unwrap();
+        let session = Revocation::add_session_refresh(&pool, vec!["refresh".to_string()], email.clone(), expires, "refresh".to_string(), None, None)
+            .await.unwrap();
+        Revocation::add_session_access(&pool, vec!["access".to_string()], email.clone(), expires, "refresh".to_string()).await.unwrap();
+
+        let result = revoke(State(pool.clone()), State(cache.clone()), ConnectInfo(addr), Path((email.clone(), token.id))).await;
+        assert!(matches!(result, Err(Error::StatusCode(StatusCode::NOT_FOUND, _))), "API tokens aren't sessions");
+        assert_eq!(Revocation::get_labeled_for_username(&pool, email.clone(), now).await.unwrap().len(), 1);
+
+        let result = revoke(State(pool.clone()), State(cache.clone()), ConnectInfo(addr), Path((email.clone(), session[0]))).await;
+        assert!(result.is_ok());
+        assert!(Revocation::get_live_for_username(&pool, email.clone(), now).await.unwrap().is_empty());
+        assert!(Revocation::get_labeled_for_username(&pool, email.clone(), now).await.unwrap().len() == 1, "the API token is left alone");
+    }
+}
@@ -0,0 +1,156 @@
+use std::time::{Duration, SystemTime};
+
+use axum::{debug_handler, extract::{self, Path, State}, response::IntoResponse, Extension, Json};
+use chrono::{NaiveDateTime, Utc};
+use hyper::{header, StatusCode};
+use mattak::{condreq, hypermedia::{op, ActionType, ResourceFields}};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+
+use crate::{
+    db::{EventId, Revocation, RevocationId},
+    keyring::LONGEST_TOKEN_LIFETIME,
+    resources::{authentication::CurrentUser, delete_op},
+    revocation_cache::RevocationCache,
+    routing::{EventShareLocate, EventSharesLocate, RouteMap},
+    tokens::TokenIssuer,
+    AppState, Error
+};
+
+const ONE_DAY: u64 = 60 * 60 * 24;
+const DEFAULT_DAYS: u64 = 30;
+const MAX_DAYS: u64 = LONGEST_TOKEN_LIFETIME.num_days() as u64;
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ShareListResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<EventSharesLocate>,
+
+    pub shares: Vec<ShareResponse>,
+}
+
+impl ShareListResponse {
+    pub fn from_query(nested_at: &str, event_id: EventId, list: Vec<Revocation<RevocationId>>) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::EventShares.prefixed(nested_at),
+                EventSharesLocate{ event_id },
+                "api:eventSharesList",
+                vec![ op(ActionType::View), op(ActionType::Add) ]
+            )?,
+            shares: list.into_iter().map(|share|
+                ShareResponse::from_query(nested_at, event_id, share))
+                .collect::<Result<_,_>>()?,
+        })
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ShareResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<EventShareLocate>,
+
+    pub label: Option<String>,
+    pub created_by: String,
+    pub expires: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+impl ShareResponse {
+    pub(crate) fn from_query(nested_at: &str, event_id: EventId, value: Revocation<RevocationId>) -> Result<Self, Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::EventShare.prefixed(nested_at),
+                EventShareLocate{ event_id, share_id: value.id },
+                "api:eventShareById",
+                vec![ delete_op() ]
+            )?,
+            label: value.label,
+            created_by: value.username,
+            expires: value.expires,
+            created_at: value.created_at,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct ShareRequest {
+    pub label: Option<String>,
+    pub expires_in_days: Option<u64>,
+}
+
+impl ShareRequest {
+    pub(crate) fn valid(&self) -> Result<(), mattak::Error> {
+        if self.expires_in_days.is_some_and(|days| days == 0 || days > MAX_DAYS) {
+            return Err(mattak::Error::InvalidInput(format!("links last between 1 and {MAX_DAYS} days")))
+        }
+        Ok(())
+    }
+}
+
+/// The token is only ever shown in this response.
+/// It can be sent in the Authorization header, or as the `share` query parameter.
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct CreatedShareResponse {
+    #[serde(flatten)]
+    pub share: ShareResponse,
+    pub token: String,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get_list(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(event_id): Path<EventId>,
+) -> Result<impl IntoResponse, Error> {
+    let shares = Revocation::get_shares_for_event(&db, event_id, Utc::now().naive_utc()).await?;
+    let resp = ShareListResponse::from_query(nested_at.as_str(), event_id, shares)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn create(
+    State(db): State<Pool<Postgres>>,
+    State(issuer): State<TokenIssuer>,
+    Extension(CurrentUser(creator)): Extension<CurrentUser>,
+    nested_at: extract::NestedPath,
+    Path(event_id): Path<EventId>,
+    Json(req): Json<ShareRequest>
+) -> Result<impl IntoResponse, Error> {
+    req.valid()?;
+
+    let expires = SystemTime::now() + Duration::from_secs(ONE_DAY * req.expires_in_days.unwrap_or(DEFAULT_DAYS));
+    let bundle = issuer.share_link(&event_id.to_string(), expires)
+        .map_err(mattak::Error::from)?;
+    let rid = bundle.revocation_ids.first().cloned()
+        .ok_or_else(|| -> Error {(StatusCode::INTERNAL_SERVER_ERROR, "token without revocation id").into()})?;
+    let label = req.label.clone().unwrap_or_else(|| "shared link".to_string());
+    let share = Revocation::add_share(&db, rid, creator, expires, label, event_id).await?;
+
+    let share = ShareResponse::from_query(nested_at.as_str(), event_id, share)?;
+    let location = share.resource_fields.id.to_string();
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)], Json(CreatedShareResponse{
+        share,
+        token: bundle.token,
+    })))
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn revoke(
+    State(db): State<Pool<Postgres>>,
+    State(cache): State<RevocationCache>,
+    Path((event_id, share_id)): Path<(EventId, RevocationId)>
+) -> Result<impl IntoResponse, Error> {
+    match Revocation::revoke_share(&db, share_id, event_id, Utc::now().naive_utc()).await? {
+        Some(revoked) => {
+            cache.add(&[revoked]);
+            Ok(StatusCode::NO_CONTENT)
+        },
+        None => Err((StatusCode::NOT_FOUND, "no such link").into())
+    }
+}
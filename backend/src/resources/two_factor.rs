@@ -0,0 +1,251 @@
+use std::{net::SocketAddr, time::SystemTime};
+
+use axum::{debug_handler, extract::{self, ConnectInfo, Path, State}, response::IntoResponse, Json};
+use chrono::{DateTime, Utc};
+use hyper::StatusCode;
+use mattak::{condreq, hypermedia::{op, ActionType, ResourceFields}};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+use crate::{
+    db::{RecoveryCode, User, UserId},
+    resources::{authentication::{reauthenticate, record_failure, throttled, Reauthentication}, delete_op},
+    routing::{RouteMap, TwoFactorLocate},
+    throttle::LoginThrottle,
+    totp::{self, TotpKey},
+    AppState, Error
+};
+
+/// The second factor offered when logging in: either a current code or an unused recovery code
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop, Default)]
+pub(crate) struct SecondFactor {
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+/// Passes if the user hasn't enabled two-factor authentication,
+/// or if the second factor checks out. Codes are used up by checking them.
+pub(crate) async fn verify_second_factor(
+    db: &Pool<Postgres>,
+    key: &TotpKey,
+    user: &User<UserId>,
+    factor: &SecondFactor,
+    now: SystemTime
+) -> Result<(), Error> {
+    let sealed = match (&user.totp_confirmed_at, &user.totp_secret) {
+        (Some(_), Some(sealed)) => sealed,
+        _ => return Ok(())
+    };
+    let rejected = || -> Error {(StatusCode::FORBIDDEN, "Authorization rejected").into()};
+
+    if let Some(code) = &factor.code {
+        let secret = key.decrypt(sealed)?;
+        match totp::verify(&secret, code, now, user.totp_last_step) {
+            Some(step) if User::use_totp_step(db, user.email.clone(), step).await? => Ok(()),
+            _ => Err(rejected())
+        }
+    } else if let Some(recovery_code) = &factor.recovery_code {
+        let hash = totp::hash_recovery_code(recovery_code);
+        match RecoveryCode::consume(db, user.id, hash, DateTime::<Utc>::from(now).naive_utc()).await? {
+            Some(_) => Ok(()),
+            None => Err(rejected())
+        }
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "two-factor code required").into())
+    }
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct TwoFactorResponse {
+    #[serde(flatten)]
+    pub resource_fields: ResourceFields<TwoFactorLocate>,
+
+    pub enabled: bool,
+    pub pending: bool,
+}
+
+impl TwoFactorResponse {
+    pub(crate) fn from_query(nested_at: &str, value: User<UserId>) -> Result<Self, mattak::Error> {
+        Ok(Self{
+            resource_fields: ResourceFields::new(
+                &RouteMap::TwoFactor.prefixed(nested_at),
+                TwoFactorLocate{ user_id: value.email.clone() },
+                "api:twoFactor",
+                vec![ op(ActionType::View), op(ActionType::Add), op(ActionType::Update), delete_op() ]
+            )?,
+            enabled: value.totp_confirmed_at.is_some(),
+            pending: value.totp_confirmed_at.is_none() && value.totp_secret.is_some(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct EnrollmentResponse {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all="camelCase")]
+pub(crate) struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct CodeRequest {
+    pub code: String
+}
+
+/// The code from the new secret, with the password, since a stolen session shouldn't be able to lock the owner out
+#[derive(Deserialize, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct ConfirmationRequest {
+    pub code: String,
+    #[serde(flatten)]
+    pub reauth: Reauthentication,
+}
+
+#[debug_handler(state = AppState)]
+pub(crate) async fn get(
+    State(db): State<Pool<Postgres>>,
+    if_none_match: condreq::CondRetreiveHeader,
+    nested_at: extract::NestedPath,
+    Path(user_id): Path<String>,
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id).await?;
+    let resp = TwoFactorResponse::from_query(nested_at.as_str(), user)?;
+    if_none_match.respond(resp).map_err(Error::from)
+}
+
+/// Starts enrollment with a new secret, which has to be confirmed before it's required.
+/// Takes the password, like confirming does.
+#[debug_handler(state = AppState)]
+pub(crate) async fn enroll(
+    State(db): State<Pool<Postgres>>,
+    State(key): State<TotpKey>,
+    State(throttle): State<LoginThrottle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(user_id): Path<String>,
+    Json(reauth): Json<Reauthentication>,
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id).await?;
+    if user.totp_confirmed_at.is_some() {
+        return Err((StatusCode::CONFLICT, "two-factor authentication already enabled").into())
+    }
+    reauthenticate(&db, &key, &throttle, &user, &reauth, addr).await?;
+
+    let secret = totp::generate_secret();
+    User::set_totp_secret(&db, user.email.clone(), key.encrypt(&secret)?).await?;
+    Ok(Json(EnrollmentResponse{
+        secret: totp::encode_secret(&secret),
+        provisioning_uri: totp::provisioning_uri(&secret, &user.email),
+    }))
+}
+
+/// Confirms enrollment with a code, and hands out recovery codes
+#[debug_handler(state = AppState)]
+pub(crate) async fn confirm(
+    State(db): State<Pool<Postgres>>,
+    State(key): State<TotpKey>,
+    State(throttle): State<LoginThrottle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(user_id): Path<String>,
+    Json(req): Json<ConfirmationRequest>,
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id).await?;
+    let sealed = match (&user.totp_confirmed_at, &user.totp_secret) {
+        (None, Some(sealed)) => sealed,
+        (Some(_), _) => return Err((StatusCode::CONFLICT, "two-factor authentication already enabled").into()),
+        (None, None) => return Err((StatusCode::NOT_FOUND, "no two-factor enrollment to confirm").into()),
+    };
+    reauthenticate(&db, &key, &throttle, &user, &req.reauth, addr).await?;
+
+    let secret = key.decrypt(sealed)?;
+    let step = totp::verify(&secret, &req.code, SystemTime::now(), None)
+        .ok_or_else(|| -> Error {(StatusCode::FORBIDDEN, "incorrect code").into()})?;
+
+    let recovery_codes = totp::generate_recovery_codes();
+    let hashes = recovery_codes.iter().map(|code| totp::hash_recovery_code(code)).collect();
+
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    User::confirm_totp(&mut *tx, user.email.clone(), step, Utc::now().naive_utc()).await?;
+    RecoveryCode::replace_for_user(&mut *tx, user.id, hashes).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+
+    Ok(Json(RecoveryCodesResponse{ recovery_codes }))
+}
+
+/// Turns two-factor authentication off, which takes a current code.
+/// Wrong codes count as failed logins.
+#[debug_handler(state = AppState)]
+pub(crate) async fn disable(
+    State(db): State<Pool<Postgres>>,
+    State(key): State<TotpKey>,
+    State(throttle): State<LoginThrottle>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(user_id): Path<String>,
+    Json(req): Json<CodeRequest>,
+) -> Result<impl IntoResponse, Error> {
+    let user = User::by_email(&db, user_id).await?;
+    let sealed = match (&user.totp_confirmed_at, &user.totp_secret) {
+        (Some(_), Some(sealed)) => sealed,
+        _ => return Err((StatusCode::NOT_FOUND, "two-factor authentication not enabled").into()),
+    };
+    if throttled(&db, &throttle, user.email.clone(), addr, "second_factor").await?.is_some() {
+        return Err((StatusCode::TOO_MANY_REQUESTS, "too many failed attempts; try again later").into())
+    }
+
+    let secret = key.decrypt(sealed)?;
+    match totp::verify(&secret, &req.code, SystemTime::now(), user.totp_last_step) {
+        Some(step) if User::use_totp_step(&db, user.email.clone(), step).await? => (),
+        _ => return Err(record_failure(&db, &throttle, user.email.clone(), addr, "second_factor").await?)
+    }
+
+    let mut tx = db.begin().await.map_err(crate::db::Error::from)?;
+    User::clear_totp(&mut *tx, user.email.clone()).await?;
+    RecoveryCode::clear_for_user(&mut *tx, user.id).await?;
+    tx.commit().await.map_err(crate::db::Error::from)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_second_factor(pool: Pool<Postgres>) {
+        let key = TotpKey::generate();
+        let secret = totp::generate_secret();
+        let now = UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+        let later = now + Duration::from_secs(90);
+
+        let testy = User::create(&pool, "test@mctesterson.net", "Testy McTesterson", "testy").await.unwrap().expect("a new user");
+        let none = SecondFactor::default();
+        verify_second_factor(&pool, &key, &testy, &none, now).await
+            .expect("no second factor needed before enrolling");
+
+        User::set_totp_secret(&pool, testy.email.clone(), key.encrypt(&secret).unwrap()).await.unwrap();
+        User::confirm_totp(&pool, testy.email.clone(), 0, Utc::now().naive_utc()).await.unwrap();
+        RecoveryCode::replace_for_user(&pool, testy.id, vec![totp::hash_recovery_code("abcde-12345")]).await.unwrap();
+        let testy = User::by_email(&pool, testy.email.clone()).await.unwrap();
+
+        assert!(verify_second_factor(&pool, &key, &testy, &none, now).await.is_err());
+
+        let code = SecondFactor{ code: Some(totp::code_at(&secret, now)), recovery_code: None };
+        verify_second_factor(&pool, &key, &testy, &code, now).await.expect("current code to pass");
+        let testy = User::by_email(&pool, testy.email.clone()).await.unwrap();
+        assert!(verify_second_factor(&pool, &key, &testy, &code, now).await.is_err(), "codes can't be replayed");
+
+        let wrong = SecondFactor{ code: Some(totp::code_at(&secret, later)), recovery_code: None };
+        assert!(verify_second_factor(&pool, &key, &testy, &wrong, now).await.is_err(), "future codes don't pass");
+
+        let recovery = SecondFactor{ code: None, recovery_code: Some("ABCDE12345".to_string()) };
+        verify_second_factor(&pool, &key, &testy, &recovery, now).await.expect("recovery code to pass");
+        assert!(verify_second_factor(&pool, &key, &testy, &recovery, now).await.is_err(), "recovery codes are single use");
+    }
+}
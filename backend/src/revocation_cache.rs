@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    sync::{atomic::{AtomicBool, Ordering}, Arc, RwLock},
+    time::Duration
+};
+
+use chrono::{NaiveDateTime, Utc};
+use serde::Deserialize;
+use sqlx::{postgres::PgListener, Pool, Postgres};
+use tracing::{debug, warn};
+
+use crate::db::{self, Revocation};
+
+const CHANNEL: &str = "revocations";
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The set of revoked token ids, kept in step with the revocations table
+/// by a NOTIFY trigger. While the listener is down, the cache reports itself
+/// stale and callers should ask the database instead.
+#[derive(Clone, Default)]
+pub(crate) struct RevocationCache {
+    revoked: Arc<RwLock<HashMap<String, NaiveDateTime>>>,
+    live: Arc<AtomicBool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Notice {
+    data: String,
+    expires: NaiveDateTime,
+}
+
+impl RevocationCache {
+    /// Starts listening for revocations in the background.
+    /// The cache is stale until the first load from the database completes.
+    pub(crate) fn start(pool: Pool<Postgres>) -> Self {
+        let cache = Self::default();
+        tokio::spawn(cache.clone().listen(pool));
+        cache
+    }
+
+    /// Which of `rids` have been revoked?
+    /// None if the cache can't currently be trusted.
+    pub(crate) fn revoked_among(&self, rids: &[String]) -> Option<Vec<String>> {
+        if !self.live.load(Ordering::Acquire) {
+            return None
+        }
+        let revoked = self.revoked.read().expect("revocation cache not to be poisoned");
+        Some(rids.iter().filter(|rid| revoked.contains_key(*rid)).cloned().collect())
+    }
+
+    /// Records revocations made by this process, without waiting for their notification
+    pub(crate) fn add<T>(&self, revocations: &[Revocation<T>]) {
+        let mut revoked = self.revoked.write().expect("revocation cache not to be poisoned");
+        for rev in revocations {
+            revoked.insert(rev.data.clone(), rev.expires);
+        }
+    }
+
+    fn apply(&self, payload: &str) {
+        match serde_json::from_str::<Notice>(payload) {
+            Ok(notice) => {
+                debug!("revocation notice: {:?}", notice);
+                let now = Utc::now().naive_utc();
+                let mut revoked = self.revoked.write().expect("revocation cache not to be poisoned");
+                revoked.retain(|_, expires| *expires > now);
+                revoked.insert(notice.data, notice.expires);
+            }
+            Err(e) => warn!("couldn't parse revocation notice {payload:?}: {e:?}")
+        }
+    }
+
+    async fn reload(&self, pool: &Pool<Postgres>) -> Result<(), db::Error> {
+        let revocations = Revocation::get_revoked(pool, Utc::now().naive_utc()).await?;
+        let mut revoked = self.revoked.write().expect("revocation cache not to be poisoned");
+        *revoked = revocations.into_iter().map(|rev| (rev.data, rev.expires)).collect();
+        Ok(())
+    }
+
+    fn set_live(&self, live: bool) {
+        self.live.store(live, Ordering::Release)
+    }
+
+    async fn listen(self, pool: Pool<Postgres>) {
+        loop {
+            // Subscribe before loading, so that nothing revoked in between is missed
+            match subscribe(&pool).await {
+                Ok(mut listener) => match self.reload(&pool).await {
+                    Ok(()) => {
+                        debug!("revocation cache loaded; listening for changes");
+                        self.set_live(true);
+                        loop {
+                            match listener.try_recv().await {
+                                Ok(Some(notification)) => self.apply(notification.payload()),
+                                Ok(None) => {
+                                    warn!("revocation listener lost its connection");
+                                    break
+                                }
+                                Err(e) => {
+                                    warn!("revocation listener failed: {e:?}");
+                                    break
+                                }
+                            }
+                        }
+                        self.set_live(false);
+                    }
+                    Err(e) => warn!("couldn't load revocations: {e:?}")
+                },
+                Err(e) => warn!("couldn't listen for revocations: {e:?}")
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+}
+
+async fn subscribe(pool: &Pool<Postgres>) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[sqlx_pg_test_template::test(template = "wtp_empty_template")]
+    async fn test_notified_revocation(pool: Pool<Postgres>) {
+        let cache = RevocationCache::start(pool.clone());
+        let rids = vec!["one".to_string(), "two".to_string()];
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        Revocation::add_batch(&pool, rids.clone(), "test@mctesterson.net".into(), expires, None, None).await.unwrap();
+
+        let mut waited = 0;
+        while cache.revoked_among(&rids).is_none() {
+            assert!(waited < 50, "cache never became live");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            waited += 1;
+        }
+        assert_eq!(cache.revoked_among(&rids), Some(vec![]));
+
+        Revocation::revoke(&pool, vec!["two".to_string()], Utc::now().naive_utc()).await.unwrap();
+
+        let mut waited = 0;
+        while cache.revoked_among(&rids) != Some(vec!["two".to_string()]) {
+            assert!(waited < 50, "revocation never arrived");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            waited += 1;
+        }
+    }
+
+    #[test]
+    fn stale_cache_defers_to_database() {
+        let cache = RevocationCache::default();
+        cache.apply(r#"{"data": "one", "expires": "2999-01-01T00:00:00"}"#);
+        assert_eq!(cache.revoked_among(&["one".to_string()]), None);
+
+        cache.set_live(true);
+        assert_eq!(cache.revoked_among(&["one".to_string(), "two".to_string()]), Some(vec!["one".to_string()]));
+    }
+}
@@ -1,10 +1,11 @@
 use axum::{response::IntoResponse, Json};
 use mattak::{hypermedia::{op, ActionType}, routing::{Entry, RouteTemplate}};
 use mattak_derives::{Context, Extract, Listable};
-use serde::Serialize;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::db::{EventId, GameId, UserId};
+use crate::{db::{EventId, EventSort, GameId, PasskeyId, RevocationId, UserId}, resources::delete_op};
 
 /*
 * Serious consideration:
@@ -30,15 +31,45 @@ pub(crate) enum RouteMap {
     Root,
     Authenticate,
     PasswordReset,
+    Verification,
+    LoginLink,
+    OidcLogin,
+    PasskeyLogin,
+    SessionRefresh,
+    TwoFactor,
     Profile,
+    Sessions,
+    Session,
+    ApiTokens,
+    ApiToken,
+    Passkeys,
+    Passkey,
+    AuditEvents,
+    AccountExport,
+    AccountDeletion,
+    EmailChange,
+    CalendarFeed,
     User,
     Events,
+    EventSearch,
     Event,
+    EventArchive,
+    EventOrganizers,
+    EventOrganizer,
+    EventShares,
+    EventShare,
     EventUsers,
+    EventRsvps,
+    EventRsvp,
     EventGames,
     Game,
     GameUsers,
-    Recommend
+    Recommend,
+    AdminUsers,
+    AdminUserLock,
+    AdminPasswordReset,
+    AdminSessions,
+    AdminAuditEvents
 }
 
 impl RouteTemplate for RouteMap {
@@ -48,15 +79,46 @@ impl RouteTemplate for RouteMap {
             Root          => "/",
             Authenticate  => "/authenticate/{user_id}",                // by login
             PasswordReset => "/reset_password/{user_id}",              // by login
+            Verification  => "/verification/{user_id}",                // by login
+            LoginLink     => "/login_link/{user_id}",                  // by login
+            OidcLogin     => "/oidc_login",
+            PasskeyLogin  => "/passkey_login/{user_id}",               // by login
+            SessionRefresh => "/session_refresh/{user_id}",            // by login
+            TwoFactor     => "/two_factor/{user_id}",                  // by login
             Profile       => "/profile/{user_id}",                     // by login
+            Sessions      => "/sessions/{user_id}",                    // by login
+            Session       => "/sessions/{user_id}/{session_id}",       // by login
+            ApiTokens     => "/api_tokens/{user_id}",                  // by login
+            ApiToken      => "/api_tokens/{user_id}/{token_id}",       // by login
+            Passkeys      => "/passkeys/{user_id}",                    // by login
+            Passkey       => "/passkeys/{user_id}/{passkey_id}",       // by login
+            AuditEvents   => "/audit_events/{user_id}",                // by login
+            AccountExport => "/account_export/{user_id}",              // by login
+            AccountDeletion => "/account_deletion/{user_id}",          // by login
+            EmailChange   => "/email_change/{user_id}",                // by login
+            CalendarFeed  => "/calendar/{user_id}",                    // by login
             User          => "/profile/{user_id}",                     // by ID
             Events        => "/events",
+            // Only for links: the same route as Events, with its query
+            EventSearch   => "/events{?when,from,to,q,sort,order,page_size,page,include_archived}",
             Event         => "/event/{event_id}",
+            EventArchive  => "/event_archive/{event_id}",
+            EventOrganizers => "/event_organizers/{event_id}",
+            EventOrganizer  => "/event_organizers/{event_id}/user/{user_id}",
+            EventShares   => "/event_shares/{event_id}",
+            EventShare    => "/event_shares/{event_id}/{share_id}",
             EventUsers    => "/event_users/{event_id}",
+            EventRsvps    => "/event_rsvps/{event_id}",
+            EventRsvp     => "/event_rsvps/{event_id}/user/{user_id}",
             EventGames    => "/event_games/{event_id}/user/{user_id}",
             Game          => "/games/{game_id}/user/{user_id}",
             GameUsers     => "/game_users/{game_id}",
-            Recommend     => "/recommend/{event_id}"
+            Recommend     => "/recommend/{event_id}",
+            AdminUsers    => "/admin/users",
+            AdminUserLock => "/admin/users/{user_id}/lock",            // by login
+            AdminPasswordReset => "/admin/users/{user_id}/password_reset", // by login
+            AdminSessions => "/admin/users/{user_id}/sessions",        // by login
+            AdminAuditEvents => "/admin/audit_events"
         }.to_string()
     }
 }
@@ -85,21 +147,173 @@ pub(crate) struct ProfileLocate {
     pub user_id: String
 }
 
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct TwoFactorLocate {
+    pub user_id: String
+}
+
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct SessionsLocate {
+    pub user_id: String
+}
+
+#[derive(Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct SessionLocate {
+    pub user_id: String,
+    pub session_id: RevocationId
+}
+
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct ApiTokensLocate {
+    pub user_id: String
+}
+
+#[derive(Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct ApiTokenLocate {
+    pub user_id: String,
+    pub token_id: RevocationId
+}
+
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct PasskeysLocate {
+    pub user_id: String
+}
+
+#[derive(Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct PasskeyLocate {
+    pub user_id: String,
+    pub passkey_id: PasskeyId
+}
+
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct AuditEventsLocate {
+    pub user_id: String
+}
+
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct AccountExportLocate {
+    pub user_id: String
+}
+
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct AccountDeletionLocate {
+    pub user_id: String
+}
+
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct EmailChangeLocate {
+    pub user_id: String
+}
+
+#[derive(Default, Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct CalendarFeedLocate {
+    pub user_id: String
+}
+
 #[derive(Serialize, Clone, Listable, Context, Extract)]
 pub(crate) struct UserLocate {
     pub user_id: UserId
 }
 
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EventWhen {
+    Upcoming,
+    Past,
+    #[default]
+    All,
+}
+
+// Serialized by hand throughout: URI templates only fill in strings, not unit variants
+impl Serialize for EventWhen {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            EventWhen::Upcoming => "upcoming",
+            EventWhen::Past => "past",
+            EventWhen::All => "all",
+        })
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl Serialize for SortOrder {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        })
+    }
+}
+
+/// The query for the events list. Also extracted from requests, so every field is optional
+#[derive(Default, Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct EventSearchLocate {
+    pub when: Option<EventWhen>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub q: Option<String>,
+    pub sort: Option<EventSort>,
+    pub order: Option<SortOrder>,
+    pub page_size: Option<u32>,
+    pub page: Option<u32>,
+    pub include_archived: Option<bool>,
+}
+
 #[derive(Serialize, Copy, Clone, Listable, Context, Extract)]
 pub(crate) struct EventLocate {
     pub event_id: EventId
 }
 
+#[derive(Serialize, Copy, Clone, Listable, Context, Extract)]
+pub(crate) struct EventArchiveLocate {
+    pub event_id: EventId
+}
+
+#[derive(Serialize, Copy, Clone, Listable, Context, Extract)]
+pub(crate) struct EventOrganizersLocate {
+    pub event_id: EventId
+}
+
+#[derive(Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct EventOrganizerLocate {
+    pub event_id: EventId,
+    pub user_id: String
+}
+
+#[derive(Serialize, Copy, Clone, Listable, Context, Extract)]
+pub(crate) struct EventSharesLocate {
+    pub event_id: EventId
+}
+
+#[derive(Serialize, Copy, Clone, Listable, Context, Extract)]
+pub(crate) struct EventShareLocate {
+    pub event_id: EventId,
+    pub share_id: RevocationId
+}
+
 #[derive(Serialize, Clone, Listable, Context, Extract)]
 pub(crate) struct EventUsersLocate {
     pub event_id: EventId,
 }
 
+#[derive(Serialize, Copy, Clone, Listable, Context, Extract)]
+pub(crate) struct EventRsvpsLocate {
+    pub event_id: EventId
+}
+
+#[derive(Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct EventRsvpLocate {
+    pub event_id: EventId,
+    pub user_id: String
+}
+
 #[derive(Serialize, Clone, Listable, Context, Extract)]
 pub(crate) struct EventGamesLocate {
     pub event_id: EventId,
@@ -122,6 +336,12 @@ pub(crate) struct RecommendLocate {
     pub event_id: EventId
 }
 
+/// Locates any of the admin routes for a particular user
+#[derive(Serialize, Clone, Listable, Context, Extract)]
+pub(crate) struct AdminUserLocate {
+    pub user_id: String
+}
+
 pub(crate) fn api_doc(nested_at: &str, bgg_api_url: &str) -> impl IntoResponse {
     use RouteMap::*;
     use ActionType::*;
@@ -144,10 +364,28 @@ pub(crate) fn api_doc(nested_at: &str, bgg_api_url: &str) -> impl IntoResponse {
     Json(json!({
         "root": entry(Root, vec![ op(View) ]),
         "resetPassword": entry(PasswordReset, vec![op(Create)]),
+        "verification": entry(Verification, vec![op(Add)]),
         "authenticate": entry(Authenticate, vec![op(Login), op(Update), op(Logout)]),
+        "loginLink": entry(LoginLink, vec![op(Create), op(Login)]),
+        "oidcLogin": entry(OidcLogin, vec![op(Create), op(Login)]),
+        "passkeyLogin": entry(PasskeyLogin, vec![op(Create), op(Login)]),
+        "sessionRefresh": entry(SessionRefresh, vec![op(Login)]),
         "profile": entry(Profile, vec![op(Create), op(Find)]),
+        "sessions": entry(Sessions, vec![op(Find), op(Logout)]),
+        "apiTokens": entry(ApiTokens, vec![op(Find), op(Add)]),
+        "passkeys": entry(Passkeys, vec![op(Find), op(Add)]),
+        "auditEvents": entry(AuditEvents, vec![op(Find)]),
+        "accountExport": entry(AccountExport, vec![op(Find)]),
+        "accountDeletion": entry(AccountDeletion, vec![op(Create), delete_op()]),
+        "emailChange": entry(EmailChange, vec![op(Create), op(Add)]),
+        "calendarFeed": entry(CalendarFeed, vec![op(Find), op(Add)]),
+        "twoFactor": entry(TwoFactor, vec![op(Find), op(Add), op(Update), delete_op()]),
         "events": entry(Events, vec![ op(View), op(Add) ]),
-        "event": entry(Event, vec![ op(Find), op(Update) ]),
+        "eventSearch": entry(EventSearch, vec![ op(Find) ]),
+        "event": entry(Event, vec![ op(Find), op(Update), delete_op() ]),
+        "eventRsvp": entry(EventRsvp, vec![ op(Find), op(Update), delete_op() ]),
+        "adminUsers": entry(AdminUsers, vec![ op(View) ]),
+        "adminAuditEvents": entry(AdminAuditEvents, vec![ op(View) ]),
         "bggAPI": {
             "type": "Link",
             "id": bgg_api_url,
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+use crate::db::LoginFailure;
+
+// A few mistakes are free; after that each failure doubles the wait, up to a minute
+const FREE_FAILURES: i32 = 3;
+const MAX_BACKOFF_SECS: u64 = 60;
+
+/// How failed logins for an email are slowed down, and when they lock it out
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LoginThrottle {
+    pub threshold: i32,
+    pub lock_for: Duration,
+}
+
+impl LoginThrottle {
+    pub(crate) fn backoff(&self, failures: i32) -> Duration {
+        if failures < FREE_FAILURES {
+            return Duration::ZERO
+        }
+        let exponent = (failures - FREE_FAILURES).min(6) as u32;
+        Duration::from_secs((1u64 << exponent).min(MAX_BACKOFF_SECS))
+    }
+
+    /// How long until another attempt will be considered, if it has to wait at all
+    pub(crate) fn wait(&self, failure: &LoginFailure, now: NaiveDateTime) -> Option<Duration> {
+        let until = match failure.locked_until {
+            Some(locked_until) if locked_until > now => locked_until,
+            _ => failure.last_failed_at + self.backoff(failure.failures),
+        };
+        (until - now).to_std().ok().filter(|wait| !wait.is_zero())
+    }
+
+    pub(crate) fn should_lock(&self, failure: &LoginFailure) -> bool {
+        failure.failures >= self.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle{ threshold: 10, lock_for: Duration::from_secs(15 * 60) }
+    }
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        let throttle = throttle();
+        assert_eq!(throttle.backoff(0), Duration::ZERO);
+        assert_eq!(throttle.backoff(2), Duration::ZERO);
+        assert_eq!(throttle.backoff(3), Duration::from_secs(1));
+        assert_eq!(throttle.backoff(5), Duration::from_secs(4));
+        assert_eq!(throttle.backoff(50), Duration::from_secs(MAX_BACKOFF_SECS));
+    }
+
+    #[test]
+    fn waits_for_backoff_or_lock() {
+        let throttle = throttle();
+        let now = Utc::now().naive_utc();
+        let failure = |failures, locked_until| LoginFailure{
+            email: "nobody@nowhere.com".to_string(),
+            failures,
+            last_failed_at: now,
+            locked_until,
+        };
+
+        assert_eq!(throttle.wait(&failure(1, None), now), None);
+        assert_eq!(throttle.wait(&failure(4, None), now), Some(Duration::from_secs(2)));
+        assert_eq!(throttle.wait(&failure(4, None), now + chrono::Duration::seconds(5)), None);
+        assert_eq!(throttle.wait(&failure(0, Some(now + chrono::Duration::minutes(1))), now), Some(Duration::from_secs(60)));
+        assert!(!throttle.should_lock(&failure(9, None)));
+        assert!(throttle.should_lock(&failure(10, None)));
+    }
+}
@@ -0,0 +1,313 @@
+use std::{collections::BTreeSet, net::SocketAddr, time::SystemTime};
+
+use base64ct::{Base64, Encoding as _};
+use biscuit_auth::{builder::Term, macros::{biscuit, block, check, fact}, Biscuit};
+use mattak::{biscuits::{self, TokenBundle}, routing::route_config};
+
+use crate::{keyring::KeyRing, routing::RouteMap};
+
+/// Issues tokens carrying facts beyond what mattak's `Authentication` provides.
+/// Signs with the key ring's active key, so the usual `biscuits::middleware::setup` verifies them.
+#[derive(Clone)]
+pub(crate) struct TokenIssuer {
+    keys: KeyRing,
+}
+
+impl TokenIssuer {
+    pub(crate) fn new(keys: KeyRing) -> Self {
+        Self { keys }
+    }
+
+    /// As `Authentication::authority`, with an `admin` fact for site administrators
+    pub(crate) fn authority(
+        &self,
+        userid: &str,
+        is_admin: bool,
+        expires: SystemTime,
+        maybe_addr: Option<SocketAddr>,
+    ) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let mut builder = biscuit!(r#"
+            user({userid});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            "#);
+        if is_admin {
+            builder = builder.fact(fact!("admin({userid})"))?;
+        }
+        if let Some(addr) = maybe_addr {
+            let addr_str = addr.ip().to_string();
+            builder = builder.fact(fact!("client_ip({addr_str})"))?;
+        }
+        bundle(self.keys.sign(builder)?)
+    }
+
+    /// As `Authentication::reset_password`
+    pub(crate) fn reset_password(&self, userid: &str, expires: SystemTime) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let builder = biscuit!(r#"
+            reset_password({userid});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            "#);
+        bundle(self.keys.sign(builder)?)
+    }
+
+    /// A short-lived token that can only be exchanged for a session.
+    /// The caller must record its revocation ids, so that it can be used once.
+    pub(crate) fn login_link(&self, userid: &str, expires: SystemTime) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let builder = biscuit!(r#"
+            login_link({userid});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            "#);
+        bundle(self.keys.sign(builder)?)
+    }
+
+    /// A short-lived token that confirms the account's deletion, sent to its email.
+    /// The caller must record its revocation ids, so that it can be used once.
+    pub(crate) fn delete_account(&self, userid: &str, expires: SystemTime) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let builder = biscuit!(r#"
+            delete_account({userid});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            "#);
+        bundle(self.keys.sign(builder)?)
+    }
+
+    /// Confirms that one of the addresses (`side` is "old" or "new") agrees to moving the account to `new_email`.
+    /// The caller must record its revocation ids, so that it can be used once.
+    pub(crate) fn email_change(&self, userid: &str, new_email: &str, side: &str, expires: SystemTime) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let builder = biscuit!(r#"
+            email_change({userid}, {new_email}, {side});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            "#);
+        bundle(self.keys.sign(builder)?)
+    }
+
+    /// A token that can only be exchanged for a new session token, and a replacement for itself.
+    /// The caller must record its revocation ids, so that it can be used once.
+    pub(crate) fn refresh_token(&self, userid: &str, expires: SystemTime) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let builder = biscuit!(r#"
+            refresh_token({userid});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            "#);
+        bundle(self.keys.sign(builder)?)
+    }
+
+    /// A long-lived token for scripts, labelled so that it can be listed and revoked.
+    /// Restrictions are added in a block of checks after the authority,
+    /// so the first revocation id is the one to record.
+    pub(crate) fn api_token(
+        &self,
+        userid: &str,
+        label: &str,
+        expires: SystemTime,
+        methods: Option<Vec<String>>,
+        event_id: Option<String>,
+    ) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let token = self.keys.sign(biscuit!(r#"
+            user({userid});
+            api_token({label});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            "#))?;
+
+        if methods.is_none() && event_id.is_none() {
+            return bundle(token)
+        }
+        let mut restrictions = block!("");
+        if let Some(methods) = methods {
+            let methods: BTreeSet<Term> = methods.into_iter().map(Term::from).collect();
+            restrictions = restrictions.check(check!("check if method($method), {methods}.contains($method)"))?;
+        }
+        if let Some(event_id) = event_id {
+            // Game routes don't name their event, so the authorizer says which it is
+            restrictions = restrictions.check(check!(r#"
+                check if path_param("event_id", {event_id})
+                    or path_param("game_id", $game), game_event($game, {event_id})
+                "#))?;
+        }
+        bundle(token.append(restrictions)?)
+    }
+
+    /// A long-lived token that can only read its user's calendar feed, since it ends up stored in calendar apps.
+    /// Like an API token, it's labelled so that it can be listed and revoked.
+    pub(crate) fn calendar_feed(&self, userid: &str, label: &str, expires: SystemTime) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let feed_route = route_config(RouteMap::CalendarFeed).axum_route();
+        let builder = biscuit!(r#"
+            user({userid});
+            api_token({label});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            check if method("GET");
+            check if route({feed_route});
+            "#);
+        bundle(self.keys.sign(builder)?)
+    }
+
+    /// A read-only token for one event, for people who may not have an account.
+    /// It carries no `user` fact; the policy admits it only to the event's public views.
+    pub(crate) fn share_link(&self, event_id: &str, expires: SystemTime) -> Result<TokenBundle, biscuits::Error> {
+        let now = SystemTime::now();
+        let builder = biscuit!(r#"
+            share_event({event_id});
+            issued_at({now});
+            check if issued_at($issued), time($time), $issued <= $time;
+            check if time($time), $time < {expires};
+            check if method("GET");
+            check if path_param("event_id", {event_id});
+            "#);
+        bundle(self.keys.sign(builder)?)
+    }
+}
+
+fn bundle(token: Biscuit) -> Result<TokenBundle, biscuits::Error> {
+    Ok(TokenBundle {
+        token: token.to_base64()?,
+        revocation_ids: token.revocation_identifiers().into_iter()
+            .map(|rid| Base64::encode_string(&rid))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use biscuit_auth::{macros::{authorizer, rule}, AuthorizerBuilder};
+
+    use super::*;
+
+    #[test]
+    fn admin_fact_verifies_with_the_key_ring() {
+        let keys = KeyRing::generate();
+        let issuer = TokenIssuer::new(keys.clone());
+        let expires = SystemTime::now() + Duration::from_secs(60);
+
+        let bundle = issuer.authority("admin@example.com", true, expires, None).expect("token to issue");
+        let token = Biscuit::from_base64(&bundle.token, keys.clone()).expect("token to verify");
+        let admins: Vec<(String,)> = AuthorizerBuilder::new().build(&token).unwrap()
+            .query(rule!("data($user) <- admin($user)")).unwrap();
+        assert_eq!(admins, vec![("admin@example.com".to_string(),)]);
+
+        let bundle = issuer.authority("user@example.com", false, expires, None).expect("token to issue");
+        let token = Biscuit::from_base64(&bundle.token, keys.clone()).expect("token to verify");
+        let admins: Vec<(String,)> = AuthorizerBuilder::new().build(&token).unwrap()
+            .query(rule!("data($user) <- admin($user)")).unwrap();
+        assert!(admins.is_empty());
+    }
+
+    #[test]
+    fn api_tokens_are_attenuated() {
+        use mattak::routing::route_config;
+        use crate::routing::RouteMap::{self, *};
+
+        let keys = KeyRing::generate();
+        let issuer = TokenIssuer::new(keys.clone());
+        let expires = SystemTime::now() + Duration::from_secs(60);
+
+        let authorized = |bundle: &TokenBundle, method: &str, rm: RouteMap, params: &[(&str, &str)]| {
+            let token = Biscuit::from_base64(&bundle.token, keys.clone()).expect("token to verify");
+            let now = SystemTime::now();
+            let route = route_config(rm).axum_route();
+            let mut builder = authorizer!(r#"time({now}); route({route}); method({method}); game_event("12", "7");"#);
+            for &(key, value) in params {
+                builder = builder.fact(fact!("path_param({key}, {value})")).expect("fact to add");
+            }
+            builder.merge(crate::secured_policy()).set_limits(crate::tests::limits())
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        let own = [("user_id", "one@example.com")];
+        let event = |id| [("event_id", id), ("user_id", "one@example.com")];
+
+        let any = issuer.api_token("one@example.com", "bot", expires, None, None).expect("token to issue");
+        assert_eq!(any.revocation_ids.len(), 1);
+        assert!(authorized(&any, "POST", EventGames, &event("7")));
+        assert!(authorized(&any, "GET", Profile, &own));
+        assert!(!authorized(&any, "PUT", Profile, &own), "API tokens can't change the profile");
+        assert!(!authorized(&any, "GET", Sessions, &own), "API tokens can't manage sessions");
+        assert!(!authorized(&any, "POST", ApiTokens, &own), "API tokens can't mint more tokens");
+        assert!(!authorized(&any, "PUT", Authenticate, &own), "API tokens can't change the password");
+        assert!(authorized(&any, "GET", CalendarFeed, &own));
+        assert!(!authorized(&any, "POST", CalendarFeed, &own), "API tokens can't issue calendar feeds");
+
+        let read_only = issuer.api_token("one@example.com", "reader", expires, Some(vec!["GET".to_string()]), None)
+            .expect("token to issue");
+        assert_eq!(read_only.revocation_ids.len(), 2, "restrictions are in their own block");
+        assert!(authorized(&read_only, "GET", EventGames, &event("7")));
+        assert!(!authorized(&read_only, "POST", EventGames, &event("7")));
+
+        let one_event = issuer.api_token("one@example.com", "poster", expires, None, Some("7".to_string()))
+            .expect("token to issue");
+        assert!(authorized(&one_event, "POST", EventGames, &event("7")));
+        assert!(!authorized(&one_event, "POST", EventGames, &event("8")));
+        assert!(authorized(&one_event, "PUT", Game, &[("game_id", "12"), ("user_id", "one@example.com")]),
+            "games at the event are covered");
+        assert!(!authorized(&one_event, "PUT", Game, &[("game_id", "13"), ("user_id", "one@example.com")]));
+        assert!(!authorized(&one_event, "GET", Events, &[]));
+    }
+
+    #[test]
+    fn calendar_feeds_only_read_the_feed() {
+        use crate::routing::RouteMap::{self, *};
+
+        let keys = KeyRing::generate();
+        let issuer = TokenIssuer::new(keys.clone());
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let bundle = issuer.calendar_feed("one@example.com", "calendar feed", expires).expect("token to issue");
+        let token = Biscuit::from_base64(&bundle.token, keys).expect("token to verify");
+        assert_eq!(bundle.revocation_ids.len(), 1);
+
+        let authorized = |method: &str, rm: RouteMap, user: &str| {
+            let now = SystemTime::now();
+            let route = route_config(rm).axum_route();
+            authorizer!(r#"time({now}); route({route}); method({method}); path_param("user_id", {user});"#)
+                .merge(crate::secured_policy()).set_limits(crate::tests::limits())
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        assert!(authorized("GET", CalendarFeed, "one@example.com"));
+        assert!(!authorized("GET", CalendarFeed, "two@example.com"));
+        assert!(!authorized("POST", CalendarFeed, "one@example.com"), "feed tokens can't mint more feeds");
+        assert!(!authorized("GET", Events, "one@example.com"));
+        assert!(!authorized("GET", Profile, "one@example.com"));
+    }
+
+    #[test]
+    fn share_links_check_themselves() {
+        let keys = KeyRing::generate();
+        let issuer = TokenIssuer::new(keys.clone());
+        let expires = SystemTime::now() + Duration::from_secs(60);
+        let bundle = issuer.share_link("7", expires).expect("token to issue");
+        let token = Biscuit::from_base64(&bundle.token, keys).expect("token to verify");
+
+        // Without any policy of our own, the token's checks still have to pass
+        let authorized = |method: &str, event_id: &str| {
+            let now = SystemTime::now();
+            authorizer!(r#"time({now}); method({method}); path_param("event_id", {event_id}); allow if true;"#)
+                .build(&token).expect("authorizer to build")
+                .authorize().is_ok()
+        };
+        assert!(authorized("GET", "7"));
+        assert!(!authorized("PUT", "7"));
+        assert!(!authorized("GET", "8"));
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read as _, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+use chacha20poly1305::{
+    aead::{Aead as _, AeadCore as _, KeyInit as _, OsRng},
+    ChaCha20Poly1305, Key, Nonce
+};
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Digest as _, Sha256};
+use subtle::ConstantTimeEq as _;
+
+// RFC 6238 defaults, which is what authenticator apps assume
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_LEN: usize = 20;
+// Accept the previous and next code too, to allow for clock drift
+const SKEW_STEPS: u64 = 1;
+
+const ISSUER: &str = "Wag the Pig";
+const RECOVERY_CODE_COUNT: usize = 10;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Encrypts TOTP secrets at rest
+#[derive(Clone)]
+pub(crate) struct TotpKey {
+    key: Key,
+}
+
+impl TotpKey {
+    /// Loads the key from `persist_path`, creating it if it doesn't exist yet
+    pub(crate) fn new<P: AsRef<Path> + Clone>(persist_path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let key = match File::open(persist_path.clone()) {
+            Ok(mut f) => {
+                let mut k = String::new();
+                f.read_to_string(&mut k)?;
+                let bytes = HEXLOWER.decode(k.trim().as_bytes())?;
+                if bytes.len() != KEY_LEN {
+                    return Err("TOTP key should be 32 bytes".into())
+                }
+                let mut key = Key::default();
+                key.copy_from_slice(&bytes);
+                key
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+                let mut f = OpenOptions::new().write(true).create_new(true).mode(0o600).open(persist_path)?;
+                f.write_all(HEXLOWER.encode(&key).as_bytes())?;
+                key
+            }
+            Err(e) => Err(e)?,
+        };
+        Ok(Self { key })
+    }
+
+    /// A key for some other purpose, derived from this one so that there's still only the one secret to keep
+    pub(crate) fn derive(&self, purpose: &str) -> Vec<u8> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC to take any key length");
+        mac.update(purpose.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn generate() -> Self {
+        Self { key: ChaCha20Poly1305::generate_key(&mut OsRng) }
+    }
+
+    /// Produces the nonce followed by the ciphertext
+    pub(crate) fn encrypt(&self, secret: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ChaCha20Poly1305::new(&self.key).encrypt(&nonce, secret).map_err(|_| Error::Cipher)?);
+        Ok(sealed)
+    }
+
+    pub(crate) fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::Cipher)
+        }
+        let (nonce_bytes, ciphertext) = sealed.split_at(NONCE_LEN);
+        let mut nonce = Nonce::default();
+        nonce.copy_from_slice(nonce_bytes);
+        ChaCha20Poly1305::new(&self.key).decrypt(&nonce, ciphertext).map_err(|_| Error::Cipher)
+    }
+}
+
+pub(crate) fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    rand_fill(&mut secret);
+    secret
+}
+
+/// The secret as authenticator apps expect it to be typed in
+pub(crate) fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+/// An otpauth:// URI, suitable for a QR code
+pub(crate) fn provisioning_uri(secret: &[u8], email: &str) -> String {
+    let issuer = ISSUER.replace(' ', "%20");
+    format!("otpauth://totp/{issuer}:{email}?secret={secret}&issuer={issuer}&digits={DIGITS}&period={STEP_SECS}",
+        email = email.replace('@', "%40"),
+        secret = encode_secret(secret))
+}
+
+fn step_at(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).expect("1970 to be in the past").as_secs() / STEP_SECS
+}
+
+fn code_for_step(secret: &[u8], step: u64) -> String {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC to take any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([digest[offset], digest[offset + 1], digest[offset + 2], digest[offset + 3]]) & 0x7fff_ffff;
+    format!("{:0width$}", truncated % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// The code an authenticator app would show at `now`
+#[cfg(test)]
+pub(crate) fn code_at(secret: &[u8], now: SystemTime) -> String {
+    code_for_step(secret, step_at(now))
+}
+
+/// Checks a code against the secret, allowing for a little clock drift.
+/// Returns the time step the code matched, which should be recorded
+/// and passed back as `last_step` so that codes can't be replayed.
+pub(crate) fn verify(secret: &[u8], code: &str, now: SystemTime, last_step: Option<i64>) -> Option<i64> {
+    let current = step_at(now);
+    let code = code.trim();
+    (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS)
+        .filter(|&step| last_step.is_none_or(|last| step as i64 > last))
+        .find(|&step| bool::from(code_for_step(secret, step).as_bytes().ct_eq(code.as_bytes())))
+        .map(|step| step as i64)
+}
+
+/// Fresh recovery codes, to be shown to the user once
+pub(crate) fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT).map(|_| {
+        let mut bytes = [0u8; 5];
+        rand_fill(&mut bytes);
+        let code = HEXLOWER.encode(&bytes);
+        format!("{}-{}", &code[..5], &code[5..])
+    }).collect()
+}
+
+/// Recovery codes are random enough that a fast hash is sufficient
+pub(crate) fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code.trim().to_lowercase().chars().filter(|c| *c != '-').collect();
+    HEXLOWER.encode(&Sha256::digest(normalized.as_bytes()))
+}
+
+fn rand_fill(bytes: &mut [u8]) {
+    use chacha20poly1305::aead::rand_core::RngCore as _;
+    OsRng.fill_bytes(bytes)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("couldn't encrypt or decrypt TOTP secret")]
+    Cipher,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn rfc6238_vectors() {
+        // The RFC's SHA1 vectors are 8 digits; we use the last 6
+        for (secs, code) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924"), (2000000000, "279037")] {
+            assert_eq!(code_for_step(RFC_SECRET, step_at(at(secs))), code);
+        }
+    }
+
+    #[test]
+    fn verify_allows_drift_but_not_replay() {
+        let now = at(1111111109);
+        let step = step_at(now) as i64;
+
+        assert_eq!(verify(RFC_SECRET, "081804", now, None), Some(step));
+        assert_eq!(verify(RFC_SECRET, "081804", at(1111111109 + STEP_SECS), None), Some(step),
+            "the previous code should still work");
+        assert_eq!(verify(RFC_SECRET, "081804", at(1111111109 + 3 * STEP_SECS), None), None,
+            "old codes should expire");
+        assert_eq!(verify(RFC_SECRET, "081804", now, Some(step)), None,
+            "a code shouldn't be accepted twice");
+        assert_eq!(verify(RFC_SECRET, "000000", now, None), None);
+    }
+
+    #[test]
+    fn secrets_round_trip_encrypted() {
+        let key = TotpKey::generate();
+        let secret = generate_secret();
+        let sealed = key.encrypt(&secret).unwrap();
+        assert_ne!(&sealed[NONCE_LEN..], &secret[..]);
+        assert_eq!(key.decrypt(&sealed).unwrap(), secret);
+
+        let other = TotpKey::generate();
+        assert!(other.decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    fn recovery_codes_normalize() {
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[0].to_uppercase().replace('-', "")));
+        assert_ne!(hash_recovery_code(&codes[0]), hash_recovery_code(&codes[1]));
+    }
+}
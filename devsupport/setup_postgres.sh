@@ -9,5 +9,5 @@ set -x
 psql -h $db_socket_path postgres -c "create user postgres with superuser;"
 psql -h $db_socket_path postgres -c "create user wagthepig;"
 psql -h $db_socket_path postgres -c "create database wagthepig with owner wagthepig;"
-sqlx migrate run --source $root/backend/migrations
+PGOPTIONS="-c wagthepig.admin_email=${ADMIN_EMAIL}" sqlx migrate run --source $root/backend/migrations
 psql -h $db_socket_path wagthepig < $root/devsupport/seeds.sql
@@ -1,5 +1,6 @@
 module Auth exposing
     ( Cred
+    , accountCredExtractor
     , accountID
     , credExtractor
     , credHeader
@@ -7,6 +8,7 @@ module Auth exposing
     , loadCred
     , loggedIn
     , logout
+    , refreshHeader
     , storageField
     , storeCred
     , testSuite
@@ -40,6 +42,7 @@ type Cred
 type alias Credentials =
     { accountID : String
     , token : String
+    , refreshToken : Maybe String
     }
 
 
@@ -86,6 +89,18 @@ credHeader (Cred cred) =
             []
 
 
+{-| Sent only to exchange the refresh token for new credentials
+-}
+refreshHeader : Cred -> List Http.Header
+refreshHeader (Cred cred) =
+    case Maybe.andThen .refreshToken cred of
+        Just token ->
+            [ Http.header "authorization" token ]
+
+        Nothing ->
+            []
+
+
 {-| It's important that this is never exposed!
 We expose `login`, `loadCred` and request functions instead, so we can be certain that if anyone
 ever has access to a `Cred` value, it came from either the login API endpoint
@@ -93,9 +108,10 @@ or was passed in via flags.
 -}
 credDecoder : Decoder Credentials
 credDecoder =
-    D.map2 Credentials
+    D.map3 Credentials
         (D.field "accountID" D.string)
         (D.field "token" D.string)
+        (D.maybe (D.field "refreshToken" D.string))
 
 
 fragmentParser : Parser (Cred -> a) a
@@ -108,7 +124,7 @@ anonymousToken maybeToken =
     -- XXX should use the body to get accountID
     case maybeToken of
         Just token ->
-            Cred (Just (Credentials "" token))
+            Cred (Just (Credentials "" token Nothing))
 
         Nothing ->
             Cred Nothing
@@ -117,9 +133,17 @@ anonymousToken maybeToken =
 encodeCred : Credentials -> Value
 encodeCred cred =
     E.object
-        [ ( "accountID", E.string cred.accountID )
-        , ( "token", E.string cred.token )
-        ]
+        ([ ( "accountID", E.string cred.accountID )
+         , ( "token", E.string cred.token )
+         ]
+            ++ (case cred.refreshToken of
+                    Just refreshToken ->
+                        [ ( "refreshToken", E.string refreshToken ) ]
+
+                    Nothing ->
+                        []
+               )
+        )
 
 
 storeCred : Cred -> Cmd msg
@@ -137,12 +161,24 @@ credExtractor email res =
     -- XXX should use the body to get accountID
     case Dict.get "set-authorization" res.headers of
         Just token ->
-            Ok (Cred (Just (Credentials email token)))
+            Ok (Cred (Just (Credentials email token (Dict.get "set-refresh-token" res.headers))))
 
         Nothing ->
             Err "no set-authorization header"
 
 
+{-| For logins where the server, rather than the user, says whose account it is
+-}
+accountCredExtractor : { res | headers : Headers } -> Result String Cred
+accountCredExtractor res =
+    case Dict.get "set-account-id" res.headers of
+        Just email ->
+            credExtractor email res
+
+        Nothing ->
+            Err "no set-account-id header"
+
+
 accountID : Cred -> String
 accountID (Cred cred) =
     case cred of
@@ -168,7 +204,7 @@ testSuite =
     T.describe "API tests"
         [ T.test "loadCred loads a credential" <|
             \_ ->
-                equal (loadCred dummyFlags) (Cred (Just { accountID = "user@example.com", token = "FAKETOKEN" }))
+                equal (loadCred dummyFlags) (Cred (Just { accountID = "user@example.com", token = "FAKETOKEN", refreshToken = Nothing }))
         ]
 
 
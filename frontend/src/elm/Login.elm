@@ -1,12 +1,14 @@
-module Login exposing (AuthResponse, Interface, Model, Msg(..), Toast, init, logout, nextPageUpdater, updaters, view, viewToast)
+module Login exposing (AuthResponse, Interface, Model, Msg(..), Toast, init, logout, nextPageUpdater, refresh, updaters, view, viewToast)
 
 import Auth
+import Browser.Navigation as Nav
 import Dict
 import Html exposing (Html, a, button, div, form, h1, p, text)
 import Html.Attributes exposing (class, type_)
 import Html.Events exposing (onClick, onSubmit)
 import Http exposing (Error(..))
 import Hypermedia as HM exposing (OperationSelector(..), emptyBody, emptyResponse)
+import Json.Decode as D
 import Json.Encode as E
 import LinkFollowing as HM
 import Router
@@ -35,6 +37,8 @@ type Msg
     | AuthenticationAttempted
     | AuthResponse (Result Http.Error Auth.Cred)
     | WantsReg
+    | WantsOidc
+    | OidcStarted (Result Http.Error String)
     | LoggedOut (Result Http.Error ())
 
 
@@ -56,6 +60,9 @@ view model =
         , Eww.inputPair [ type_ "password" ] "Password" model.password ChangePassword
         , button [ type_ "submit" ] [ text "Log in" ]
         ]
+    , div [ class "oidc-login" ]
+        [ a [ class "button", onClick WantsOidc ] [ text "Log in with your identity provider" ]
+        ]
     , div [ class "need-reg" ]
         [ p [ class "need-reg" ]
             [ text "Don't have an account? No problem!" ]
@@ -117,6 +124,17 @@ updaters ({ localUpdate, installNewCred, requestNav, lowerModel, handleError } a
         WantsReg ->
             requestNav Router.Register
 
+        WantsOidc ->
+            localUpdate (\m -> ( m, beginOidc ))
+
+        OidcStarted res ->
+            case res of
+                Ok url ->
+                    localUpdate (\m -> ( m, Nav.load url ))
+
+                Err err ->
+                    handleError err
+
         LoggedOut res ->
             case res of
                 Ok () ->
@@ -180,6 +198,32 @@ login email password =
         AuthResponse
 
 
+{-| The provider sends the user back to the OidcLogin page to finish
+-}
+beginOidc : Cmd Msg
+beginOidc =
+    HM.chain
+        [ HM.browse [ "oidcLogin" ] (ByType "CreateAction")
+        ]
+        []
+        emptyBody
+        (HM.decodeBody (D.field "authorizationUrl" D.string))
+        OidcStarted
+
+
+{-| Trades the refresh token for new credentials, before the current ones run out
+-}
+refresh : (Result Http.Error Auth.Cred -> msg) -> Auth.Cred -> Cmd msg
+refresh toMsg cred =
+    HM.chain
+        [ HM.browse [ "sessionRefresh" ] (ByType "LoginAction") |> HM.fillIn (Dict.fromList [ ( "user_id", Auth.accountID cred ) ])
+        ]
+        (Auth.refreshHeader cred)
+        emptyBody
+        (Auth.credExtractor (Auth.accountID cred))
+        toMsg
+
+
 logout : Auth.Cred -> Cmd Msg
 logout cred =
     HM.chain
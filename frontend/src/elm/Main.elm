@@ -20,6 +20,7 @@ import Platform.Cmd as Cmd
 import ResourceUpdate as Up
 import Router
 import State
+import Time
 import Toast exposing (withAttributes, withTrayAttributes)
 import Updaters
 import Url
@@ -43,6 +44,8 @@ type Msg
     | PathRequested String
     | SignOut
     | StoreChange ( String, Value )
+    | RefreshCred Time.Posix
+    | RefreshedCred (Result Http.Error Auth.Cred)
     | PageMsg Pages.Msg
     | ToastMsg Toast.Msg
     | CloseToast (Toast.Info ())
@@ -117,8 +120,16 @@ init flags url key =
 
         logoFetchCmd =
             BGG.fetchAPIRoot (Up.resultDispatch ErrBGGAPIRoot GotBGGAPIRoot)
+
+        -- the stored access token may well have run out since the last visit
+        refreshCmd =
+            if Auth.loggedIn model.creds then
+                Login.refresh RefreshedCred model.creds
+
+            else
+                Cmd.none
     in
-    ( routedModel, Cmd.batch [ routeCmd, logoFetchCmd ] )
+    ( routedModel, Cmd.batch [ routeCmd, logoFetchCmd, refreshCmd ] )
 
 
 type alias Updater model msg =
@@ -254,6 +265,16 @@ update msg model =
         StoreChange ( key, value ) ->
             ( loadIntoModel key value model, Cmd.none )
 
+        RefreshCred _ ->
+            ( model, Login.refresh RefreshedCred model.creds )
+
+        RefreshedCred (Ok newcred) ->
+            onNewCred newcred model
+
+        -- the next request will be refused, and ask to log in again
+        RefreshedCred (Err _) ->
+            ( model, Cmd.none )
+
         SignOut ->
             ( { model | creds = Auth.unauthenticated }
             , Cmd.batch
@@ -368,8 +389,22 @@ routeToPage url model =
 
 
 subscriptions : Model -> Sub Msg
-subscriptions _ =
-    State.onStoreChange StoreChange
+subscriptions model =
+    Sub.batch
+        [ State.onStoreChange StoreChange
+        , if Auth.loggedIn model.creds then
+            Time.every refreshInterval RefreshCred
+
+          else
+            Sub.none
+        ]
+
+
+{-| Access tokens last an hour, so this leaves plenty of room for a slow request
+-}
+refreshInterval : Float
+refreshInterval =
+    20 * 60 * 1000
 
 
 
@@ -0,0 +1,123 @@
+module OidcLogin exposing (FromServer, Interface, Model, Msg(..), init, updaters, view)
+
+import Auth
+import Html exposing (Html, button, form, h1, p, text)
+import Html.Attributes exposing (type_)
+import Html.Events exposing (onSubmit)
+import Html.Extra exposing (viewIf)
+import Http
+import Hypermedia as HM exposing (OperationSelector(..))
+import Json.Encode as E
+import LinkFollowing as HM
+import Router
+import Updaters exposing (Updater)
+import ViewUtil as Eww
+
+
+{-| Where the identity provider sends users back to, with a code to trade for a session.
+The code only works once, so any second factor has to be asked for before it's sent.
+-}
+type alias Model =
+    { authorizationCode : String
+    , state : String
+    , secondFactor : String
+    , fromServer : FromServer
+    }
+
+
+init : Model
+init =
+    Model "" "" "" None
+
+
+type Msg
+    = Entered (Maybe String) (Maybe String)
+    | ChangeSecondFactor String
+    | LoginAttempted
+    | AuthResponse (Result Http.Error Auth.Cred)
+
+
+type FromServer
+    = None
+    | Failed
+
+
+view : Model -> List (Html Msg)
+view model =
+    [ h1 [] [ text "Finish logging in" ]
+    , viewIf (model.fromServer == Failed) (p [] [ text "That didn't work - please, try logging in again" ])
+    , form [ onSubmit LoginAttempted ]
+        [ Eww.inputPair [] "Two-factor code (if you use one)" model.secondFactor ChangeSecondFactor
+        , button [ type_ "submit" ] [ text "Log in" ]
+        ]
+    ]
+
+
+type alias Interface base model msg =
+    { base
+        | localUpdate : Updater Model Msg -> Updater model msg
+        , requestNav : Router.Target -> Updater model msg
+        , installNewCred : Auth.Cred -> Updater model msg
+    }
+
+
+updaters : Interface base model msg -> Msg -> Updater model msg
+updaters { localUpdate, requestNav, installNewCred } msg =
+    case msg of
+        Entered code state ->
+            localUpdate
+                (\m ->
+                    ( { m
+                        | authorizationCode = Maybe.withDefault "" code
+                        , state = Maybe.withDefault "" state
+                        , secondFactor = ""
+                        , fromServer = None
+                      }
+                    , Cmd.none
+                    )
+                )
+
+        ChangeSecondFactor code ->
+            localUpdate (\m -> ( { m | secondFactor = code }, Cmd.none ))
+
+        LoginAttempted ->
+            localUpdate (\m -> ( { m | fromServer = None }, finish m ))
+
+        AuthResponse res ->
+            case res of
+                Ok cred ->
+                    Updaters.compose (installNewCred cred)
+                        (requestNav (Router.Events Nothing))
+
+                Err _ ->
+                    localUpdate (\m -> ( { m | fromServer = Failed, secondFactor = "" }, Cmd.none ))
+
+
+finish : Model -> Cmd Msg
+finish model =
+    let
+        secondFactor : List ( String, E.Value )
+        secondFactor =
+            if String.isEmpty model.secondFactor then
+                []
+
+            else
+                [ ( "code", E.string model.secondFactor ) ]
+
+        reqBody : Http.Body
+        reqBody =
+            Http.jsonBody
+                (E.object
+                    ([ ( "authorization_code", E.string model.authorizationCode )
+                     , ( "state", E.string model.state )
+                     ]
+                        ++ secondFactor
+                    )
+                )
+    in
+    HM.chain
+        [ HM.browse [ "oidcLogin" ] (ByType "LoginAction") ]
+        []
+        reqBody
+        Auth.accountCredExtractor
+        AuthResponse
@@ -12,6 +12,7 @@ import Html exposing (Html)
 import Hypermedia exposing (Affordance, Error)
 import Landing
 import Login
+import OidcLogin
 import Profile
 import Register
 import Router exposing (Target(..))
@@ -32,6 +33,7 @@ type Msg
     | WhatShouldWePlayMsg WhatShouldWePlay.Msg
     | RegisterMsg Register.Msg
     | CompleteRegistrationMsg CompleteRegistration.Msg
+    | OidcLoginMsg OidcLogin.Msg
     | CredentialedArrivalMsg Auth.Cred Target
 
 
@@ -59,6 +61,7 @@ type alias Models =
     , createGame : Game.Create.Model
     , register : Register.Model
     , complete_registration : CompleteRegistration.Model
+    , oidc_login : OidcLogin.Model
     }
 
 
@@ -86,6 +89,7 @@ init =
         Game.Create.init
         Register.init
         CompleteRegistration.init
+        OidcLogin.init
 
 
 view : Router.Target -> Models -> List (Html Msg)
@@ -147,6 +151,10 @@ view target models =
             CompleteRegistration.view models.complete_registration
                 |> wrapMsg CompleteRegistrationMsg
 
+        Router.OidcLogin _ _ ->
+            OidcLogin.view models.oidc_login
+                |> wrapMsg OidcLoginMsg
+
 
 viewToast : Toast.Info Toast -> List (Html Msg)
 viewToast toastInfo =
@@ -217,6 +225,9 @@ pageNavMsg target creds =
         Router.CompleteRegistration email ->
             CompleteRegistrationMsg (CompleteRegistration.Entered creds email)
 
+        Router.OidcLogin code state ->
+            OidcLoginMsg (OidcLogin.Entered code state)
+
         Login ->
             LoginMsg Login.Entered
 
@@ -348,6 +359,11 @@ updaters ({ installNewCred, requestNav } as iface) msg =
                 (pageInterface identity .complete_registration (\models -> \pm -> { models | complete_registration = pm }) CompleteRegistrationMsg)
                 submsg
 
+        OidcLoginMsg submsg ->
+            OidcLogin.updaters
+                (pageInterface identity .oidc_login (\models -> \pm -> { models | oidc_login = pm }) OidcLoginMsg)
+                submsg
+
         GameEditMsg submsg ->
             Game.Edit.updaters
                 (pageInterface GameEditToast .editGame (\models -> \pm -> { models | editGame = pm }) GameEditMsg)
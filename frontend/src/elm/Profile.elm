@@ -57,6 +57,7 @@ type alias Password =
     { old : String
     , new : String
     , newAgain : String
+    , secondFactor : String
     }
 
 
@@ -66,7 +67,7 @@ init =
         Auth.unauthenticated
         Nothing
         (Profile "" "" "" Nothing Nothing)
-        (Password "" "" "")
+        (Password "" "" "" "")
 
 
 encode : Profile -> E.Value
@@ -99,6 +100,7 @@ type Msg
     | ChangeOldPassword String
     | ChangeNewPassword String
     | ChangeNewPasswordAgain String
+    | ChangePasswordSecondFactor String
     | SubmitPassword
     | AuthResponse (Result Http.Error ())
 
@@ -136,6 +138,7 @@ view model =
         [ Eww.inputPair [ type_ "password" ] "Old Password" model.password.old ChangeOldPassword
         , Eww.inputPair passwordInputAttrs "New Password" model.password.new ChangeNewPassword
         , Eww.inputPair passwordInputAttrs "New Password Again" model.password.newAgain ChangeNewPasswordAgain
+        , Eww.inputPair [] "Two-factor code (if you use one)" model.password.secondFactor ChangePasswordSecondFactor
         , Eww.maybeSubmit (passwordsMatch && passwordLongEnough) "Update Password"
         , viewIf (not passwordsMatch) (span [ class "warning" ] [ text "Passwords have to match" ])
         , viewIf (not (passwordEmpty || passwordLongEnough)) (span [ class "warning" ] [ text "Password has to be at least 12 characters long" ])
@@ -203,6 +206,9 @@ updaters { localUpdate, requestNav, handleError, sendToast } msg =
         ChangeNewPasswordAgain p ->
             updatePassword (\pw -> { pw | newAgain = p })
 
+        ChangePasswordSecondFactor code ->
+            updatePassword (\pw -> { pw | secondFactor = code })
+
         SubmitPassword ->
             localUpdate (\m -> ( m, submitPasswordUpdate m ))
 
@@ -226,13 +232,23 @@ submitPasswordUpdate model =
         password =
             model.password
 
+        secondFactor : List ( String, E.Value )
+        secondFactor =
+            if String.isEmpty password.secondFactor then
+                []
+
+            else
+                [ ( "code", E.string password.secondFactor ) ]
+
         reqBody : Http.Body
         reqBody =
             Http.jsonBody
                 (E.object
-                    [ ( "old_password", E.string password.old )
-                    , ( "new_password", E.string password.new )
-                    ]
+                    ([ ( "old_password", E.string password.old )
+                     , ( "new_password", E.string password.new )
+                     ]
+                        ++ secondFactor
+                    )
                 )
     in
     HM.chain
@@ -17,6 +17,7 @@ import TableSort exposing (Sorting)
 import Url exposing (Url)
 import Url.Builder exposing (absolute)
 import Url.Parser exposing ((</>), (<?>), Parser, int, map, oneOf, parse, s, string, top)
+import Url.Parser.Query as Query
 
 
 type Target
@@ -33,6 +34,7 @@ type Target
     | CredentialedArrival Target Cred
     | Register
     | CompleteRegistration String
+    | OidcLogin (Maybe String) (Maybe String)
 
 
 type EventSortBy
@@ -201,6 +203,9 @@ buildFromTarget target =
         CompleteRegistration email ->
             absolute [ "complete_registration", email ] []
 
+        OidcLogin _ _ ->
+            absolute [ "oidc_login" ] []
+
         WhatShouldWePlay event_id sorting ->
             absolute [ "whatshouldweplay", String.fromInt event_id ] (TableSort.builder reccoSortToString sorting)
 
@@ -248,6 +253,9 @@ pageName target =
         CompleteRegistration _ ->
             "registration"
 
+        OidcLogin _ _ ->
+            "login"
+
         WhatShouldWePlay _ _ ->
             "whatshouldweplay"
 
@@ -268,6 +276,9 @@ router =
         , map EditGame (s "events" </> int </> s "game" </> int </> s "edit")
         , map registrationArrival (s "handle_registration" </> string </> Auth.fragmentParser)
         , map CompleteRegistration (s "complete_registration" </> string)
+
+        -- where the identity provider sends users back to
+        , map OidcLogin (s "oidc_login" <?> Query.string "code" <?> Query.string "state")
         ]
 
 
@@ -58,6 +58,8 @@ lib.mkIf config.services.wag-the-pig.enable (
               SQL
 
               ${dbURL}
+              # Migrations that need an owner for existing rows assign them to the admin
+              export PGOPTIONS="-c wagthepig.admin_email=${cfg.adminEmail}"
               ${pkgs.sqlx-cli}/bin/sqlx migrate run --source ${migrationsPackage}
             ''
           ).overrideAttrs
@@ -88,6 +90,7 @@ lib.mkIf config.services.wag-the-pig.enable (
           BGG_PROXY = cfg.bggProxyURL;
           TRUST_FORWARDED_HEADER = lib.boolToString cfg.trustForwarded;
           AUTH_KEYPAIR = "%S/wag-the-pig/backend.keypair";
+          TOTP_KEY = "%S/wag-the-pig/totp.key";
           ADMIN_EMAIL = cfg.adminEmail;
           SMTP_HOST = cfg.smtp.host;
           SMTP_PORT = toString cfg.smtp.port;